@@ -0,0 +1,511 @@
+use crate::api_key;
+use crate::error::GptError;
+use crate::execute::stream_tail;
+use crate::gpt3::{Gpt, Gpt3Message};
+use crate::usage::today;
+use colored::Colorize;
+use inquire::Confirm;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SYSTEM_PROMPT: &str = r#"You are a linux terminal agent reaching a goal step by step. Propose one command at a time, you will get its exit status and output before the next step. Respond only with a JSON object, without markdown: {"command": "<linux command>", "explanation": "<one sentence>", "done": false} for the next step, or {"done": true, "summary": "<what was achieved>"} when the goal is reached or can't be reached."#;
+
+/// Sent back when an answer is neither a command nor the end of the session.
+const MALFORMED_STEP: &str = r#"Your answer has no command and is not done. Respond only with the JSON object, {"command": "<linux command>", "explanation": "<one sentence>", "done": false} or {"done": true, "summary": "<what was achieved>"}."#;
+
+/// Output of a step fed back to the model is limited to its last characters.
+const MAX_FEEDBACK: usize = 4000;
+
+/// Next step proposed by the model.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct AgentStep {
+    #[serde(default)]
+    pub command: Option<String>,
+    #[serde(default)]
+    pub explanation: String,
+    #[serde(default)]
+    pub done: bool,
+    #[serde(default)]
+    pub summary: Option<String>,
+}
+
+impl AgentStep {
+    /// Parses the JSON object, treating any other answer as the command itself. Braces inside a
+    /// command, eg. `find . -exec rm {} \;`, are not a step, which has `command` or `done`.
+    pub fn parse(answer: &str) -> Self {
+        let json = answer
+            .find('{')
+            .zip(answer.rfind('}'))
+            .and_then(|(start, end)| answer.get(start..=end))
+            .and_then(|json| serde_json::from_str::<serde_json::Value>(json).ok())
+            .filter(|json| {
+                answer.trim_start().starts_with('{')
+                    || json.get("command").is_some()
+                    || json.get("done").is_some()
+            });
+        match json.and_then(|json| serde_json::from_value::<AgentStep>(json).ok()) {
+            Some(step) => step,
+            None => AgentStep {
+                command: Some(answer.trim().trim_matches('`').to_string()),
+                ..AgentStep::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepOutput {
+    pub command: String,
+    /// `None` when the command was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl StepOutput {
+    /// Message telling the model how the step went.
+    fn feedback(&self) -> String {
+        let status = self.exit_code.map_or_else(
+            || String::from("killed by a signal"),
+            |code| code.to_string(),
+        );
+        format!(
+            "Exit status: {status}\nstdout:\n{}\nstderr:\n{}",
+            tail(&self.stdout),
+            tail(&self.stderr)
+        )
+    }
+}
+
+fn tail(text: &str) -> &str {
+    if text.len() <= MAX_FEEDBACK {
+        return text;
+    }
+    let mut start = text.len() - MAX_FEEDBACK;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    &text[start..]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Done,
+    Stopped,
+    StepLimit,
+}
+
+/// Agent session saved to the history.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Transcript {
+    pub date: String,
+    pub time: u64,
+    pub goal: String,
+    pub steps: Vec<StepOutput>,
+    pub outcome: Outcome,
+    pub summary: Option<String>,
+}
+
+impl Transcript {
+    /// `GPT_HISTORY_PATH` or `~/.gpt-history.jsonl`.
+    pub fn default_path() -> String {
+        std::env::var("GPT_HISTORY_PATH").unwrap_or_else(|_| {
+            format!("{}/.gpt-history.jsonl", dirs::home_dir().unwrap().display())
+        })
+    }
+
+    /// Appends the transcript as one JSON line.
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+/// Maximum number of commands in a session, `GPT_AGENT_MAX_STEPS` or 10.
+pub fn max_steps() -> usize {
+    std::env::var("GPT_AGENT_MAX_STEPS")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .map_or(10, |steps| steps.max(1))
+}
+
+/// Reaches `goal` with commands confirmed one by one in the terminal.
+pub async fn run(gpt: &Gpt, goal: &str) -> Result<Transcript, GptError> {
+    let gpt = gpt.clone().for_agent();
+    let transcript = run_with(&gpt, goal, max_steps(), confirm, execute).await?;
+    if let Err(error) = transcript.save(&Transcript::default_path()) {
+        eprintln!("Can't save history: {error}");
+    }
+    Ok(transcript)
+}
+
+/// Agent loop with confirmation and execution of commands supplied by the caller.
+pub async fn run_with(
+    gpt: &Gpt,
+    goal: &str,
+    max_steps: usize,
+    mut confirm: impl FnMut(usize, &AgentStep) -> bool,
+    mut execute: impl FnMut(&str) -> StepOutput,
+) -> Result<Transcript, GptError> {
+    let mut messages = vec![
+        Gpt3Message {
+            role: String::from("system"),
+            content: String::from(SYSTEM_PROMPT),
+        },
+        Gpt3Message {
+            role: String::from("user"),
+            content: goal.to_string(),
+        },
+    ];
+    let mut transcript = Transcript {
+        date: today(),
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        goal: goal.to_string(),
+        steps: vec![],
+        outcome: Outcome::StepLimit,
+        summary: None,
+    };
+
+    // Answers without a command are corrected, each taking one of the steps.
+    for _ in 0..max_steps {
+        let response = gpt.ask(messages.clone()).await?;
+        let answer = response.choices[0].message.content.clone();
+        let step = AgentStep::parse(&answer);
+
+        if step.done {
+            transcript.outcome = Outcome::Done;
+            transcript.summary = step.summary;
+            return Ok(transcript);
+        }
+        let command = match &step.command {
+            Some(command) if !command.trim().is_empty() => command.clone(),
+            _ => {
+                messages.push(Gpt3Message {
+                    role: String::from("assistant"),
+                    content: answer,
+                });
+                messages.push(Gpt3Message {
+                    role: String::from("user"),
+                    content: String::from(MALFORMED_STEP),
+                });
+                continue;
+            }
+        };
+        if !confirm(transcript.steps.len() + 1, &step) {
+            transcript.outcome = Outcome::Stopped;
+            return Ok(transcript);
+        }
+
+        let output = execute(&command);
+        messages.push(Gpt3Message {
+            role: String::from("assistant"),
+            content: answer,
+        });
+        messages.push(Gpt3Message {
+            role: String::from("user"),
+            content: output.feedback(),
+        });
+        transcript.steps.push(output);
+    }
+
+    Ok(transcript)
+}
+
+fn confirm(step_number: usize, step: &AgentStep) -> bool {
+    let command = step.command.as_deref().unwrap_or_default();
+    let mut message = format!("Step {step_number}.:\n\n{}\n", command.green());
+    if !step.explanation.is_empty() {
+        message.push_str(&format!("{}\n", step.explanation.dimmed()));
+    }
+    Confirm::new(&message)
+        .with_default(true)
+        .with_help_message("Pressing enter you run this command, answering no stops the agent")
+        .prompt()
+        .unwrap_or(false)
+}
+
+/// Runs the command in bash with its output streamed to the terminal, keeping the tails the model
+/// is told about.
+fn execute(command: &str) -> StepOutput {
+    let spawned = api_key::scrub(Command::new("bash").arg("-c").arg(command))
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(error) => {
+            let message = format!("Failed to execute command: {error}");
+            eprintln!("{}", message.red());
+            return StepOutput {
+                command: command.to_string(),
+                exit_code: None,
+                stdout: String::new(),
+                stderr: message,
+            };
+        }
+    };
+
+    let stderr = child.stderr.take().map(|stderr| {
+        std::thread::spawn(move || stream_tail(stderr, std::io::stderr(), MAX_FEEDBACK))
+    });
+    let stdout = child.stdout.take().map_or_else(Vec::new, |stdout| {
+        stream_tail(stdout, std::io::stdout(), MAX_FEEDBACK)
+    });
+    let stderr = stderr
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default();
+
+    StepOutput {
+        command: command.to_string(),
+        exit_code: child.wait().ok().and_then(|status| status.code()),
+        stdout: String::from_utf8_lossy(&stdout).to_string(),
+        stderr: String::from_utf8_lossy(&stderr).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_step() {
+        assert_eq!(
+            AgentStep::parse(
+                r#"{"command": "python3 -m venv .venv", "explanation": "Creates a venv.", "done": false}"#
+            ),
+            AgentStep {
+                command: Some(String::from("python3 -m venv .venv")),
+                explanation: String::from("Creates a venv."),
+                ..AgentStep::default()
+            }
+        );
+        assert!(AgentStep::parse(r#"{"done": true, "summary": "Tests pass."}"#).done);
+        assert_eq!(
+            AgentStep::parse("`ls -la`").command.as_deref(),
+            Some("ls -la")
+        );
+    }
+
+    #[test]
+    fn test_parse_command_with_braces() {
+        let command = r"find . -name '*.log' -exec rm {} \;";
+        assert_eq!(AgentStep::parse(command).command.as_deref(), Some(command));
+        assert!(!AgentStep::parse(command).done);
+    }
+
+    #[test]
+    fn test_feedback_keeps_tail() {
+        let output = StepOutput {
+            command: String::from("yes"),
+            exit_code: Some(0),
+            stdout: format!("{}end", "y\n".repeat(MAX_FEEDBACK)),
+            stderr: String::new(),
+        };
+        let feedback = output.feedback();
+        assert!(feedback.starts_with("Exit status: 0\nstdout:\n"));
+        assert!(feedback.contains("end\nstderr:\n"));
+        assert!(feedback.len() < MAX_FEEDBACK + 50);
+    }
+
+    #[test]
+    fn test_save_transcript() {
+        let path = &format!("/tmp/.gpt-history-{}.jsonl", rand::random::<u64>());
+        let transcript = Transcript {
+            date: String::from("2024-05-10"),
+            time: 1715300000,
+            goal: String::from("run tests"),
+            steps: vec![],
+            outcome: Outcome::Done,
+            summary: None,
+        };
+        transcript.save(path).unwrap();
+        transcript.save(path).unwrap();
+
+        let history = std::fs::read_to_string(path).unwrap();
+        assert_eq!(history.lines().count(), 2);
+        assert!(history.contains(r#""outcome":"done""#));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_execute_keeps_both_streams() {
+        let output = execute("echo out; echo err >&2; exit 2");
+        assert_eq!(output.exit_code, Some(2));
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr, "err\n");
+    }
+
+    fn agent_gpt(server: &httpmock::MockServer) -> Gpt {
+        Gpt::mock(&server.url(""))
+    }
+
+    fn answer(content: &str) -> serde_json::Value {
+        json!({
+            "id": "testid",
+            "object": "chat.completion",
+            "created": 1678705627,
+            "model": "gpt-4o",
+            "usage": { "prompt_tokens": 45, "completion_tokens": 3, "total_tokens": 48 },
+            "choices": [
+                {
+                    "message": { "role": "assistant", "content": content },
+                    "finish_reason": "stop",
+                    "index": 0
+                }
+            ]
+        })
+    }
+
+    #[tokio::test]
+    async fn test_run_until_done() {
+        let server = httpmock::MockServer::start();
+        let done = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .body_contains("Exit status: 0");
+            then.status(200)
+                .json_body_obj(&answer(r#"{"done": true, "summary": "Said hi."}"#));
+        });
+        let first = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200)
+                .json_body_obj(&answer(r#"{"command": "echo hi", "done": false}"#));
+        });
+
+        let transcript = run_with(
+            &agent_gpt(&server),
+            "say hi",
+            5,
+            |_, _| true,
+            |command| StepOutput {
+                command: command.to_string(),
+                exit_code: Some(0),
+                stdout: String::from("hi\n"),
+                stderr: String::new(),
+            },
+        )
+        .await
+        .unwrap();
+
+        first.assert_hits(1);
+        done.assert_hits(1);
+        assert_eq!(transcript.outcome, Outcome::Done);
+        assert_eq!(transcript.summary.as_deref(), Some("Said hi."));
+        assert_eq!(transcript.steps.len(), 1);
+        assert_eq!(transcript.steps[0].command, "echo hi");
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_when_declined() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200)
+                .json_body_obj(&answer(r#"{"command": "rm -rf build", "done": false}"#));
+        });
+
+        let transcript = run_with(
+            &agent_gpt(&server),
+            "clean",
+            5,
+            |_, _| false,
+            |_| panic!("Declined command was executed."),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(transcript.outcome, Outcome::Stopped);
+        assert!(transcript.steps.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_step_limit() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200)
+                .json_body_obj(&answer(r#"{"command": "sleep 1", "done": false}"#));
+        });
+
+        let transcript = run_with(
+            &agent_gpt(&server),
+            "wait",
+            2,
+            |_, _| true,
+            |command| StepOutput {
+                command: command.to_string(),
+                exit_code: Some(0),
+                stdout: String::new(),
+                stderr: String::new(),
+            },
+        )
+        .await
+        .unwrap();
+
+        mock.assert_hits(2);
+        assert_eq!(transcript.outcome, Outcome::StepLimit);
+        assert_eq!(transcript.steps.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_corrects_step_without_command() {
+        let server = httpmock::MockServer::start();
+        let corrected = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .body_contains("Your answer has no command and is not done.");
+            then.status(200)
+                .json_body_obj(&answer(r#"{"done": true, "summary": "Nothing to do."}"#));
+        });
+        let malformed = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200)
+                .json_body_obj(&answer(r#"{"done": false}"#));
+        });
+
+        let transcript = run_with(
+            &agent_gpt(&server),
+            "clean",
+            5,
+            |_, _| panic!("Step without command was confirmed."),
+            |_| panic!("Step without command was executed."),
+        )
+        .await
+        .unwrap();
+
+        malformed.assert_hits(1);
+        corrected.assert_hits(1);
+        assert_eq!(transcript.outcome, Outcome::Done);
+        assert!(transcript.steps.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run_never_finishes_without_done() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200).json_body_obj(&answer("{}"));
+        });
+
+        let transcript = run_with(
+            &agent_gpt(&server),
+            "clean",
+            2,
+            |_, _| panic!("Step without command was confirmed."),
+            |_| panic!("Step without command was executed."),
+        )
+        .await
+        .unwrap();
+
+        mock.assert_hits(2);
+        assert_eq!(transcript.outcome, Outcome::StepLimit);
+    }
+}
@@ -0,0 +1,287 @@
+use crate::error::GptError;
+use crate::profile;
+use std::fmt;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, OnceLock};
+
+/// Environment variables holding API keys, removed from the environment of commands we run.
+pub const KEY_VARIABLES: [&str; 4] = [
+    "OPENAI_API_KEY",
+    "AZURE_OPENAI_API_KEY",
+    "GEMINI_API_KEY",
+    "GOOGLE_API_KEY",
+];
+
+/// Shown in place of keys in debug output, traces and recordings.
+pub const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, Clone, PartialEq)]
+enum KeySource {
+    Value(String),
+    /// Variable holding the path, and the path.
+    File(String, String),
+    /// Variable holding the command, and the command.
+    Command(String, String),
+    /// First variable the key was looked up in.
+    Missing(String),
+}
+
+/// API key given directly, in a file or printed by a command, read when first needed. Clones
+/// share the key, so the command runs once.
+#[derive(Clone)]
+pub struct ApiKey {
+    source: KeySource,
+    resolved: Arc<OnceLock<Result<String, String>>>,
+}
+
+impl ApiKey {
+    /// The first of `variables` that is set, either itself or as `{name}_FILE` with the path of
+    /// a file containing the key, or as `{name}_COMMAND` printing the key, eg. `pass show openai`.
+    /// `{name}_{PROFILE}` of the current `GPT_PROFILE` is looked up before `name`.
+    pub fn from_env(variables: &[&str]) -> Self {
+        let var = |name: String| {
+            std::env::var(&name)
+                .ok()
+                .filter(|value| !value.is_empty())
+                .map(|value| (name, value))
+        };
+        let source = variables
+            .iter()
+            .flat_map(|name| profile::names(name))
+            .find_map(|name| {
+                var(name.clone())
+                    .map(|(_, key)| KeySource::Value(key))
+                    .or_else(|| {
+                        var(format!("{name}_FILE")).map(|(name, path)| KeySource::File(name, path))
+                    })
+                    .or_else(|| {
+                        var(format!("{name}_COMMAND"))
+                            .map(|(name, command)| KeySource::Command(name, command))
+                    })
+            })
+            .unwrap_or_else(|| KeySource::Missing(variables.first().unwrap_or(&"").to_string()));
+
+        ApiKey {
+            source,
+            resolved: Arc::default(),
+        }
+    }
+
+    /// The key, reading the file or running the command the first time.
+    pub fn value(&self) -> Result<String, GptError> {
+        if let KeySource::Missing(name) = &self.source {
+            return Err(GptError::MissingCredentials(name.clone()));
+        }
+        self.resolved
+            .get_or_init(|| resolve(&self.source))
+            .clone()
+            .map_err(GptError::KeyUnavailable)
+    }
+}
+
+impl From<&str> for ApiKey {
+    fn from(key: &str) -> Self {
+        ApiKey {
+            source: KeySource::Value(key.to_string()),
+            resolved: Arc::default(),
+        }
+    }
+}
+
+/// Shows where the key comes from, never the key itself.
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            KeySource::Value(_) => write!(f, "ApiKey({REDACTED})"),
+            KeySource::File(name, _) | KeySource::Command(name, _) => write!(f, "ApiKey({name})"),
+            KeySource::Missing(_) => write!(f, "ApiKey(missing)"),
+        }
+    }
+}
+
+fn resolve(source: &KeySource) -> Result<String, String> {
+    let key = match source {
+        KeySource::Value(key) => key.clone(),
+        KeySource::File(name, path) => std::fs::read_to_string(path)
+            .map_err(|e| format!("Error: can't read the key from {name} {path}: {e}"))?,
+        KeySource::Command(name, command) => {
+            let output = Command::new("bash")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .output()
+                .map_err(|e| format!("Error: can't run {name}: {e}"))?;
+            if !output.status.success() {
+                return Err(format!("Error: {name} failed with {}.", output.status));
+            }
+            String::from_utf8_lossy(&output.stdout).to_string()
+        }
+        KeySource::Missing(_) => String::new(),
+    };
+
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(match source {
+            KeySource::File(name, _) | KeySource::Command(name, _) => {
+                format!("Error: {name} gave an empty key.")
+            }
+            _ => String::from("Error: the API key is empty."),
+        });
+    }
+    Ok(key.to_string())
+}
+
+/// `text` with every occurrence of the secrets replaced.
+pub fn redact(text: &str, secrets: &[&str]) -> String {
+    secrets
+        .iter()
+        .filter(|secret| !secret.is_empty())
+        .fold(text.to_string(), |text, secret| {
+            text.replace(secret, REDACTED)
+        })
+}
+
+/// Removes API keys, also those of profiles, from the environment `command` inherits.
+pub fn scrub(command: &mut Command) -> &mut Command {
+    for (name, _) in std::env::vars_os() {
+        if is_key_variable(&name.to_string_lossy()) {
+            command.env_remove(name);
+        }
+    }
+    for name in KEY_VARIABLES {
+        command.env_remove(name);
+    }
+    command
+}
+
+/// One of [`KEY_VARIABLES`] or its variant for a profile, eg. `OPENAI_API_KEY_WORK`, but not the
+/// path or command giving a key.
+fn is_key_variable(name: &str) -> bool {
+    KEY_VARIABLES
+        .iter()
+        .any(|key| match name.strip_prefix(key) {
+            Some("") => true,
+            Some(rest) => {
+                rest.starts_with('_') && !rest.ends_with("_FILE") && !rest.ends_with("_COMMAND")
+            }
+            None => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(source: KeySource) -> ApiKey {
+        ApiKey {
+            source,
+            resolved: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn test_key_from_file() {
+        let path = std::env::temp_dir().join(format!("gpt-key-{}", rand::random::<u64>()));
+        std::fs::write(&path, "sk-file\n").unwrap();
+        let api_key = key(KeySource::File(
+            String::from("OPENAI_API_KEY_FILE"),
+            path.display().to_string(),
+        ));
+        assert_eq!(api_key.value().unwrap(), "sk-file");
+        std::fs::remove_file(path).unwrap();
+        // Read once, so the key is still known.
+        assert_eq!(api_key.value().unwrap(), "sk-file");
+    }
+
+    #[test]
+    fn test_key_from_command() {
+        let api_key = key(KeySource::Command(
+            String::from("OPENAI_API_KEY_COMMAND"),
+            String::from("echo sk-command"),
+        ));
+        assert_eq!(api_key.value().unwrap(), "sk-command");
+
+        let failing = key(KeySource::Command(
+            String::from("OPENAI_API_KEY_COMMAND"),
+            String::from("exit 3"),
+        ));
+        assert!(
+            matches!(failing.value(), Err(GptError::KeyUnavailable(message)) if message.contains("OPENAI_API_KEY_COMMAND failed"))
+        );
+    }
+
+    #[test]
+    fn test_clones_run_command_once() {
+        let path = std::env::temp_dir().join(format!("gpt-key-runs-{}", rand::random::<u64>()));
+        let api_key = key(KeySource::Command(
+            String::from("OPENAI_API_KEY_COMMAND"),
+            format!("echo run >> {}; echo sk-command", path.display()),
+        ));
+        let clone = api_key.clone();
+
+        assert_eq!(api_key.value().unwrap(), "sk-command");
+        assert_eq!(clone.value().unwrap(), "sk-command");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "run\n");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_key() {
+        assert!(matches!(
+            key(KeySource::Missing(String::from("GEMINI_API_KEY"))).value(),
+            Err(GptError::MissingCredentials(name)) if name == "GEMINI_API_KEY"
+        ));
+    }
+
+    #[test]
+    fn test_missing_key_names_first_variable() {
+        let api_key = ApiKey::from_env(&["GPT_TEST_MISSING_KEY", "GPT_TEST_MISSING_FALLBACK"]);
+        assert_eq!(
+            api_key.value().unwrap_err().to_string(),
+            "Error: GPT_TEST_MISSING_KEY environment variable is not defined."
+        );
+    }
+
+    #[test]
+    fn test_debug_is_redacted() {
+        assert_eq!(
+            format!("{:?}", ApiKey::from("sk-secret")),
+            "ApiKey(<redacted>)"
+        );
+        let command = key(KeySource::Command(
+            String::from("OPENAI_API_KEY_COMMAND"),
+            String::from("pass show openai"),
+        ));
+        assert_eq!(format!("{command:?}"), "ApiKey(OPENAI_API_KEY_COMMAND)");
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("Incorrect API key provided: sk-secret.", &["sk-secret", ""]),
+            "Incorrect API key provided: <redacted>."
+        );
+    }
+
+    #[test]
+    fn test_is_key_variable() {
+        assert!(is_key_variable("OPENAI_API_KEY"));
+        assert!(is_key_variable("GEMINI_API_KEY_WORK"));
+        assert!(!is_key_variable("OPENAI_API_KEY_FILE"));
+        assert!(!is_key_variable("OPENAI_API_KEY_WORK_COMMAND"));
+        assert!(!is_key_variable("OPENAI_BASE_URL"));
+    }
+
+    #[test]
+    fn test_scrub() {
+        let mut command = Command::new("env");
+        command
+            .env("OPENAI_API_KEY", "sk-secret")
+            .env("HOME", "/root");
+        let output = scrub(&mut command).output().unwrap();
+        let env = String::from_utf8_lossy(&output.stdout);
+        assert!(!env.contains("sk-secret"));
+        assert!(env.contains("HOME=/root"));
+    }
+}
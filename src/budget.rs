@@ -0,0 +1,296 @@
+use crate::error::GptError;
+use crate::usage::Ledger;
+use colored::Colorize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    /// Prefix of ledger days belonging to the period containing `day`.
+    fn prefix(self, day: &str) -> &str {
+        match self {
+            Period::Day => day,
+            Period::Month => &day[..7],
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Period::Day => "daily",
+            Period::Month => "monthly",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    /// Prompt and completion tokens together.
+    Tokens,
+    Usd,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limit {
+    pub period: Period,
+    pub unit: Unit,
+    pub amount: f64,
+}
+
+impl Limit {
+    fn spent(&self, ledger: &Ledger, day: &str) -> f64 {
+        let total = ledger.total(self.period.prefix(day));
+        match self.unit {
+            Unit::Tokens => (total.prompt_tokens + total.completion_tokens) as f64,
+            Unit::Usd => total.cost,
+        }
+    }
+
+    fn describe(&self, spent: f64) -> String {
+        match self.unit {
+            Unit::Tokens => format!(
+                "{} token budget, {spent} of {} tokens used",
+                self.period.name(),
+                self.amount
+            ),
+            Unit::Usd => format!(
+                "{} cost budget, ${spent:.2} of ${:.2} spent",
+                self.period.name(),
+                self.amount
+            ),
+        }
+    }
+}
+
+/// Limits of tokens and estimated cost recorded in the usage ledger, checked before each request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Budget {
+    pub limits: Vec<Limit>,
+    /// Fraction of a limit after which a warning is shown.
+    pub warn_at: f64,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget {
+            limits: vec![],
+            warn_at: 0.8,
+        }
+    }
+}
+
+/// Result of comparing the ledger with the limits.
+#[derive(Debug, Default, PartialEq)]
+pub struct BudgetCheck {
+    pub exceeded: Vec<String>,
+    /// Pairs of a key identifying the limit in its period and the warning.
+    pub warnings: Vec<(String, String)>,
+}
+
+impl Budget {
+    /// Limits from `GPT_BUDGET_{DAILY,MONTHLY}_{TOKENS,USD}`, warning threshold from `GPT_BUDGET_WARN`.
+    pub fn from_env() -> Self {
+        let number = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.trim().parse::<f64>().ok())
+                .filter(|value| *value >= 0.0)
+        };
+
+        let limits = [
+            ("GPT_BUDGET_DAILY_TOKENS", Period::Day, Unit::Tokens),
+            ("GPT_BUDGET_MONTHLY_TOKENS", Period::Month, Unit::Tokens),
+            ("GPT_BUDGET_DAILY_USD", Period::Day, Unit::Usd),
+            ("GPT_BUDGET_MONTHLY_USD", Period::Month, Unit::Usd),
+        ]
+        .into_iter()
+        .filter_map(|(name, period, unit)| {
+            number(name).map(|amount| Limit {
+                period,
+                unit,
+                amount,
+            })
+        })
+        .collect();
+
+        Budget {
+            limits,
+            warn_at: number("GPT_BUDGET_WARN").unwrap_or(Budget::default().warn_at),
+        }
+    }
+
+    pub fn check(&self, ledger: &Ledger, day: &str) -> BudgetCheck {
+        let mut check = BudgetCheck::default();
+        for limit in self.limits.iter() {
+            let spent = limit.spent(ledger, day);
+            if spent >= limit.amount {
+                check.exceeded.push(limit.describe(spent));
+            } else if spent >= limit.amount * self.warn_at {
+                let key = format!(
+                    "{} {} {:?}",
+                    limit.period.prefix(day),
+                    limit.period.name(),
+                    limit.unit
+                );
+                check.warnings.push((key, limit.describe(spent)));
+            }
+        }
+        check
+    }
+
+    /// Refuses to go on past a limit unless `force` is set, warning once per period near a limit.
+    pub fn enforce(&self, ledger_path: &str, day: &str, force: bool) -> Result<(), GptError> {
+        if self.limits.is_empty() {
+            return Ok(());
+        }
+        Ledger::update(ledger_path, |ledger| {
+            let check = self.check(ledger, day);
+
+            if !check.exceeded.is_empty() {
+                if !force {
+                    return Err(GptError::BudgetExceeded(check.exceeded.join("; ")));
+                }
+                eprintln!(
+                    "{}",
+                    format!(
+                        "Budget exceeded ({}), sending anyway because of --force-budget.",
+                        check.exceeded.join("; ")
+                    )
+                    .yellow()
+                );
+            }
+
+            let fresh: Vec<_> = check
+                .warnings
+                .into_iter()
+                .filter(|(key, _)| !ledger.warned.contains(key))
+                .collect();
+            if fresh.is_empty() {
+                return Ok(());
+            }
+            for (_, warning) in fresh.iter() {
+                eprintln!("{}", format!("Warning: {warning}.").yellow());
+            }
+            ledger
+                .warned
+                .retain(|key| key.split(' ').next().is_some_and(|p| day.starts_with(p)));
+            ledger.warned.extend(fresh.into_iter().map(|(key, _)| key));
+            Ok(())
+        })
+        .map_err(GptError::Usage)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt3::Usage;
+    use crate::usage::{Pricing, entry};
+
+    fn ledger(day: &str, prompt_tokens: i32, completion_tokens: i32) -> Ledger {
+        let mut ledger = Ledger::default();
+        let usage = Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        };
+        ledger.record(
+            day,
+            "gpt-4o",
+            "default",
+            &entry(&Pricing::default(), "gpt-4o", &usage),
+        );
+        ledger
+    }
+
+    fn budget(period: Period, unit: Unit, amount: f64) -> Budget {
+        Budget {
+            limits: vec![Limit {
+                period,
+                unit,
+                amount,
+            }],
+            ..Budget::default()
+        }
+    }
+
+    #[test]
+    fn test_check_under_limit() {
+        let check = budget(Period::Day, Unit::Tokens, 1000.0)
+            .check(&ledger("2024-05-10", 100, 10), "2024-05-10");
+        assert_eq!(check, BudgetCheck::default());
+    }
+
+    #[test]
+    fn test_check_warning() {
+        let check = budget(Period::Day, Unit::Tokens, 1000.0)
+            .check(&ledger("2024-05-10", 800, 50), "2024-05-10");
+        assert!(check.exceeded.is_empty());
+        assert_eq!(
+            check.warnings,
+            vec![(
+                String::from("2024-05-10 daily Tokens"),
+                String::from("daily token budget, 850 of 1000 tokens used")
+            )]
+        );
+    }
+
+    #[test]
+    fn test_check_exceeded_cost() {
+        let check = budget(Period::Month, Unit::Usd, 1.0)
+            .check(&ledger("2024-05-01", 1_000_000, 0), "2024-05-10");
+        assert_eq!(
+            check.exceeded,
+            vec!["monthly cost budget, $2.50 of $1.00 spent"]
+        );
+    }
+
+    #[test]
+    fn test_check_ignores_other_periods() {
+        let check = budget(Period::Day, Unit::Tokens, 100.0)
+            .check(&ledger("2024-05-09", 1000, 0), "2024-05-10");
+        assert_eq!(check, BudgetCheck::default());
+    }
+
+    #[test]
+    fn test_enforce() {
+        let path = &format!("/tmp/.gpt-usage-{}.json", rand::random::<u64>());
+        ledger("2024-05-10", 900, 0).save(path).unwrap();
+
+        let budget = budget(Period::Day, Unit::Tokens, 1000.0);
+        budget.enforce(path, "2024-05-10", false).unwrap();
+        assert!(
+            Ledger::load(path)
+                .unwrap()
+                .warned
+                .contains("2024-05-10 daily Tokens")
+        );
+
+        let exceeded = Budget {
+            warn_at: 0.5,
+            ..self::budget(Period::Day, Unit::Tokens, 500.0)
+        };
+        assert!(matches!(
+            exceeded.enforce(path, "2024-05-10", false),
+            Err(GptError::BudgetExceeded(limits)) if limits == "daily token budget, 900 of 500 tokens used"
+        ));
+        exceeded.enforce(path, "2024-05-10", true).unwrap();
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(format!("{path}.lock")).unwrap();
+    }
+
+    #[test]
+    fn test_enforce_refuses_corrupted_ledger() {
+        let path = &format!("/tmp/.gpt-usage-{}.json", rand::random::<u64>());
+        std::fs::write(path, "not a ledger").unwrap();
+
+        assert!(matches!(
+            budget(Period::Day, Unit::Tokens, 1000.0).enforce(path, "2024-05-10", false),
+            Err(GptError::Usage(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(format!("{path}.lock")).unwrap();
+    }
+}
@@ -1,45 +1,176 @@
-use std::collections::HashMap;
-use std::fs::{read_to_string, write};
+mod encrypted_store;
+mod indexed_store;
+mod json_store;
+
+pub use encrypted_store::{CacheKey, EncryptedStore};
+pub use indexed_store::IndexedStore;
+pub use json_store::JsonStore;
+use std::fs::{remove_file, rename, write};
+use std::io::ErrorKind;
+
+/// Storage backend of the answers cache.
+pub trait CacheStore: Send {
+    fn get(&mut self, key: &str) -> Option<String>;
+    fn set(&mut self, key: &str, value: &str);
+    fn entries(&mut self) -> Result<Vec<(String, String)>, String>;
+}
 
 pub struct Cache {
-    path: String,
-    map: HashMap<String, String>,
+    store: Box<dyn CacheStore>,
 }
 
 impl Cache {
+    /// Paths ending with `.json` use the legacy single-file store, any other path the indexed one.
     pub fn new(path: Option<&str>) -> Self {
-        let default_path = format!("{}/.gpt-cache.json", dirs::home_dir().unwrap().display());
-        let path = path.map_or(default_path, |p| p.to_string());
-        Cache {
-            path,
-            map: HashMap::new(),
+        let path = Cache::path(path);
+        let store = Cache::store(&path, path == Cache::default_path(".gpt-cache.log"));
+
+        Cache { store }
+    }
+
+    /// `path`, or the plain cache in the home directory.
+    fn path(path: Option<&str>) -> String {
+        path.map_or_else(|| Cache::default_path(".gpt-cache.log"), String::from)
+    }
+
+    /// Plain or encrypted cache, depending on `GPT_CACHE_ENCRYPT`, `GPT_CACHE_PASSPHRASE` and
+    /// `GPT_CACHE_KEY_FILE`.
+    pub fn from_env() -> Result<Self, String> {
+        match CacheKey::from_env()? {
+            Some(key) => Cache::encrypted(None, &key),
+            None => Ok(Cache::new(None)),
         }
     }
 
-    fn is_empty(&self) -> bool {
-        self.map.is_empty()
+    pub fn encrypted(path: Option<&str>, key: &CacheKey) -> Result<Self, String> {
+        let path = path.map_or_else(|| Cache::default_path(".gpt-cache.enc.log"), String::from);
+        finish_rekey(&path)?;
+        let store = EncryptedStore::open(Cache::store(&path, false), &path, key)?;
+
+        Ok(Cache {
+            store: Box::new(store),
+        })
     }
 
-    pub fn get(&mut self, key: &str) -> Option<String> {
-        if !std::path::Path::new(&self.path).exists() {
-            return None;
+    /// Re-encrypts the cache opened with `key` using `new_key`, returns the number of entries.
+    /// Entries are written to a new store, then a marker is created and the new files are
+    /// renamed over the old ones. A rekey interrupted before the marker leaves the old cache in
+    /// place, one interrupted after it is finished the next time the cache is opened.
+    pub fn rekey(path: Option<&str>, key: &CacheKey, new_key: &CacheKey) -> Result<usize, String> {
+        let path = path.map_or_else(|| Cache::default_path(".gpt-cache.enc.log"), String::from);
+        let rekeyed_path = rekeyed_path(&path);
+        let remove = |path: &str| {
+            for file in files(path) {
+                let _ = remove_file(file);
+            }
+        };
+
+        finish_rekey(&path)?;
+        let mut store = EncryptedStore::open(Cache::store(&path, false), &path, key)?;
+        // Left over by a rekey interrupted before the marker.
+        remove(&rekeyed_path);
+        let mut rekeyed =
+            EncryptedStore::open(Cache::store(&rekeyed_path, false), &rekeyed_path, new_key)?;
+        let count = store
+            .copy_to(&mut rekeyed)
+            .inspect_err(|_| remove(&rekeyed_path))?;
+        drop((store, rekeyed));
+
+        // Nothing was written to the new log when the cache was empty, the old one holds no
+        // entry either and must not be left with the new header.
+        if !std::path::Path::new(&rekeyed_path).exists() {
+            for file in [path.clone(), format!("{path}.idx")] {
+                match remove_file(&file) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        return Err(format!("Error: can't replace {file}: {e}"));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let marker = format!("{rekeyed_path}.done");
+        write(&marker, "").map_err(|e| format!("Error: can't write {marker}: {e}"))?;
+        finish_rekey(&path)?;
+        Ok(count)
+    }
+
+    fn default_path(file_name: &str) -> String {
+        std::env::var("GPT_CACHE_PATH")
+            .unwrap_or_else(|_| format!("{}/{}", dirs::home_dir().unwrap().display(), file_name))
+    }
+
+    fn store(path: &str, import_legacy: bool) -> Box<dyn CacheStore> {
+        if path.ends_with(".json") {
+            return Box::new(JsonStore::new(path));
+        }
+        let is_new = !std::path::Path::new(path).exists();
+        let mut store = IndexedStore::new(path);
+        if is_new && import_legacy {
+            Cache::import_legacy(&mut store);
+        }
+        Box::new(store)
+    }
+
+    /// Moves answers cached by versions using `~/.gpt-cache.json` into a freshly created store.
+    fn import_legacy(store: &mut IndexedStore) {
+        let legacy_path = format!("{}/.gpt-cache.json", dirs::home_dir().unwrap().display());
+        if !std::path::Path::new(&legacy_path).exists() {
+            return;
         }
-        if self.is_empty() {
-            let contents = read_to_string(&self.path).unwrap();
-            self.map = serde_json::from_str(&contents).unwrap();
+        for (key, value) in JsonStore::new(&legacy_path).entries().unwrap_or_default() {
+            store.set(&key, &value);
         }
-        self.map.get(key).cloned()
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        self.store.get(key)
     }
 
     pub fn set(&mut self, key: &str, value: &str) {
-        self.map.insert(key.to_string(), value.to_string());
-        let contents = serde_json::to_string(&self.map).unwrap();
-        write(&self.path, contents).unwrap();
+        self.store.set(key, value);
     }
 }
 
+/// Where [`Cache::rekey`] writes the new store of the cache at `path`.
+fn rekeyed_path(path: &str) -> String {
+    match path.strip_suffix(".json") {
+        Some(stem) => format!("{stem}.rekey.json"),
+        None => format!("{path}.rekey"),
+    }
+}
+
+/// Log, index and key header of the cache at `path`.
+fn files(path: &str) -> [String; 3] {
+    [
+        path.to_string(),
+        format!("{path}.idx"),
+        format!("{path}.key"),
+    ]
+}
+
+/// Renames the files of a rekey that got as far as its marker over the old ones. Files already
+/// renamed are skipped, so this can be interrupted and run again.
+fn finish_rekey(path: &str) -> Result<(), String> {
+    let rekeyed_path = rekeyed_path(path);
+    let marker = format!("{rekeyed_path}.done");
+    if !std::path::Path::new(&marker).exists() {
+        return Ok(());
+    }
+    // The old index must not outlive its log, a missing one is rebuilt.
+    let _ = remove_file(format!("{path}.idx"));
+    for (rekeyed_file, file) in files(&rekeyed_path).iter().zip(files(path)) {
+        match rename(rekeyed_file, &file) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Error: can't replace {file}: {e}")),
+        }
+    }
+    remove_file(&marker).map_err(|e| format!("Error: can't remove {marker}: {e}"))
+}
+
 #[cfg(test)]
 mod tests {
+    mod bench;
     mod rand_hash;
 
     use super::*;
@@ -48,15 +179,17 @@ mod tests {
 
     #[test]
     fn test_new_with_path() {
-        let cache = Cache::new(Some("/tmp/test_cache.json"));
-        assert_eq!(cache.path, "/tmp/test_cache.json");
+        assert_eq!(
+            Cache::path(Some("/tmp/test_cache.json")),
+            "/tmp/test_cache.json"
+        );
     }
 
     #[test]
     fn test_new_without_path() {
-        let cache = Cache::new(None);
-        let default_path = format!("{}/.gpt-cache.json", dirs::home_dir().unwrap().display());
-        assert_eq!(cache.path, default_path);
+        // Resolved without opening the cache, which could import the legacy one of the user.
+        let default_path = format!("{}/.gpt-cache.log", dirs::home_dir().unwrap().display());
+        assert_eq!(Cache::path(None), default_path);
     }
 
     #[test]
@@ -95,4 +228,256 @@ mod tests {
         assert_eq!(cache.get("key"), Some("value2".to_string()));
         remove_file(path).expect("can't remove cache file");
     }
+
+    #[test]
+    fn test_indexed_get_nonexistent_key() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let mut cache = Cache::new(Some(path));
+        assert_eq!(cache.get("nonexistent"), None);
+        assert!(!std::path::Path::new(path).exists());
+    }
+
+    #[test]
+    fn test_indexed_set_and_get_across_instances() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let mut cache = Cache::new(Some(path));
+        cache.set("key1", "value1");
+        cache.set("key2", "value2");
+        cache.set("key1", "value3");
+
+        let mut reopened = Cache::new(Some(path));
+        assert_eq!(reopened.get("key1"), Some("value3".to_string()));
+        assert_eq!(reopened.get("key2"), Some("value2".to_string()));
+        assert_eq!(reopened.get("key3"), None);
+        remove_file(path).expect("can't remove cache file");
+        remove_file(format!("{path}.idx")).expect("can't remove index file");
+    }
+
+    #[test]
+    fn test_indexed_rebuilds_missing_index() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let mut cache = Cache::new(Some(path));
+        cache.set("key", "value");
+        remove_file(format!("{path}.idx")).expect("can't remove index file");
+
+        let mut reopened = Cache::new(Some(path));
+        assert_eq!(reopened.get("key"), Some("value".to_string()));
+        remove_file(path).expect("can't remove cache file");
+        remove_file(format!("{path}.idx")).expect("can't remove index file");
+    }
+
+    #[test]
+    fn test_indexed_entries_keep_latest_value() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let mut store = IndexedStore::new(path);
+        store.set("key1", "value1");
+        store.set("key2", "value2");
+        store.set("key1", "value3");
+        assert_eq!(
+            store.entries().unwrap(),
+            vec![
+                ("key1".to_string(), "value3".to_string()),
+                ("key2".to_string(), "value2".to_string())
+            ]
+        );
+        remove_file(path).expect("can't remove cache file");
+        remove_file(format!("{path}.idx")).expect("can't remove index file");
+    }
+
+    #[test]
+    fn test_indexed_appends_after_torn_record() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let mut store = IndexedStore::new(path);
+        store.set("key1", "value1");
+        // header of a record of 100 bytes cut off after 2
+        let mut log = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        std::io::Write::write_all(&mut log, &[4, 0, 0, 0, 96, 0, 0, 0, b'k', b'e']).unwrap();
+        let mut index = std::fs::OpenOptions::new()
+            .append(true)
+            .open(format!("{path}.idx"))
+            .unwrap();
+        std::io::Write::write_all(&mut index, &[1, 2, 3]).unwrap();
+
+        let mut reopened = IndexedStore::new(path);
+        reopened.set("key2", "value2");
+        assert_eq!(reopened.get("key2"), Some("value2".to_string()));
+
+        remove_file(format!("{path}.idx")).expect("can't remove index file");
+        let mut rebuilt = IndexedStore::new(path);
+        assert_eq!(rebuilt.get("key1"), Some("value1".to_string()));
+        assert_eq!(rebuilt.get("key2"), Some("value2".to_string()));
+        remove_file(path).expect("can't remove cache file");
+        remove_file(format!("{path}.idx")).expect("can't remove index file");
+    }
+
+    #[test]
+    fn test_indexed_indexes_records_missing_from_index() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let mut store = IndexedStore::new(path);
+        store.set("key1", "value1");
+        store.set("key2", "value2");
+        // the entry of key2 was cut off halfway
+        let index = std::fs::OpenOptions::new()
+            .write(true)
+            .open(format!("{path}.idx"))
+            .unwrap();
+        index.set_len(24).unwrap();
+
+        let mut reopened = IndexedStore::new(path);
+        reopened.set("key3", "value3");
+        let mut reindexed = IndexedStore::new(path);
+        assert_eq!(reindexed.get("key1"), Some("value1".to_string()));
+        assert_eq!(reindexed.get("key2"), Some("value2".to_string()));
+        assert_eq!(reindexed.get("key3"), Some("value3".to_string()));
+        remove_file(path).expect("can't remove cache file");
+        remove_file(format!("{path}.idx")).expect("can't remove index file");
+    }
+
+    fn write_key_file(contents: &str) -> String {
+        let path = format!("/tmp/.gpt-cache-{}.secret", get_random_hash());
+        std::fs::write(&path, contents).expect("can't write key file");
+        path
+    }
+
+    fn remove_encrypted(path: &str) {
+        for file in [
+            path.to_string(),
+            format!("{path}.idx"),
+            format!("{path}.key"),
+        ] {
+            let _ = remove_file(file);
+        }
+    }
+
+    #[test]
+    fn test_encrypted_set_and_get() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let key_file = write_key_file("secret");
+        let key = CacheKey::KeyFile(key_file.clone());
+
+        let mut cache = Cache::encrypted(Some(path), &key).unwrap();
+        cache.set("list files", "ls -la");
+        let mut reopened = Cache::encrypted(Some(path), &key).unwrap();
+        assert_eq!(reopened.get("list files"), Some("ls -la".to_string()));
+
+        let raw = std::fs::read(path).unwrap();
+        let raw = String::from_utf8_lossy(&raw);
+        assert!(!raw.contains("list files"));
+        assert!(!raw.contains("ls -la"));
+        remove_encrypted(path);
+        remove_file(key_file).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_wrong_key() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let key_file = write_key_file("secret");
+        let other_key_file = write_key_file("other secret");
+
+        let mut cache = Cache::encrypted(Some(path), &CacheKey::KeyFile(key_file.clone())).unwrap();
+        cache.set("key", "value");
+        match Cache::encrypted(Some(path), &CacheKey::KeyFile(other_key_file.clone())) {
+            Ok(_) => panic!("Wrong key error was expected."),
+            Err(error) => assert_eq!(
+                error,
+                format!("Error: cache key does not match the one {path} was encrypted with.")
+            ),
+        }
+        remove_encrypted(path);
+        remove_file(key_file).unwrap();
+        remove_file(other_key_file).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_corrupted_header() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let key_file = write_key_file("secret");
+        let key = CacheKey::KeyFile(key_file.clone());
+
+        let mut cache = Cache::encrypted(Some(path), &key).unwrap();
+        cache.set("key", "value");
+        std::fs::write(format!("{path}.key"), "{").unwrap();
+        match Cache::encrypted(Some(path), &key) {
+            Ok(_) => panic!("Corrupted header error was expected."),
+            Err(error) => assert!(
+                error.starts_with(&format!("Error: corrupted cache key header {path}.key: ")),
+                "{error}"
+            ),
+        }
+        remove_file(format!("{path}.key")).unwrap();
+        assert!(Cache::encrypted(Some(path), &key).is_err());
+        assert!(!std::path::Path::new(&format!("{path}.key")).exists());
+        remove_encrypted(path);
+        remove_file(key_file).expect("can't remove key file");
+    }
+
+    #[test]
+    fn test_encrypted_rekey_with_passphrase() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let key_file = write_key_file("secret");
+        let old_key = CacheKey::KeyFile(key_file.clone());
+        let new_key = CacheKey::Passphrase("correct horse battery staple".to_string());
+
+        let mut cache = Cache::encrypted(Some(path), &old_key).unwrap();
+        cache.set("key1", "value1");
+        cache.set("key2", "value2");
+        assert_eq!(Cache::rekey(Some(path), &old_key, &new_key), Ok(2));
+
+        assert!(Cache::encrypted(Some(path), &old_key).is_err());
+        let mut reopened = Cache::encrypted(Some(path), &new_key).unwrap();
+        assert_eq!(reopened.get("key1"), Some("value1".to_string()));
+        assert_eq!(reopened.get("key2"), Some("value2".to_string()));
+        remove_encrypted(path);
+        remove_file(key_file).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_rekey_finished_after_interruption() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let key_file = write_key_file("secret");
+        let old_key = CacheKey::KeyFile(key_file.clone());
+        let new_key = CacheKey::Passphrase("correct horse battery staple".to_string());
+
+        Cache::encrypted(Some(path), &old_key)
+            .unwrap()
+            .set("key", "old value");
+        let rekeyed_path = &rekeyed_path(path);
+        Cache::encrypted(Some(rekeyed_path), &new_key)
+            .unwrap()
+            .set("key", "new value");
+        // interrupted after renaming the log
+        std::fs::write(format!("{rekeyed_path}.done"), "").unwrap();
+        rename(rekeyed_path, path).unwrap();
+
+        let mut reopened = Cache::encrypted(Some(path), &new_key).unwrap();
+        assert_eq!(reopened.get("key"), Some("new value".to_string()));
+        assert!(!std::path::Path::new(&format!("{rekeyed_path}.done")).exists());
+        assert!(!std::path::Path::new(&format!("{rekeyed_path}.key")).exists());
+        remove_encrypted(path);
+        remove_file(key_file).expect("can't remove key file");
+    }
+
+    #[test]
+    fn test_encrypted_rekey_keeps_undecryptable_cache() {
+        let path = &format!("/tmp/.gpt-cache-{}.log", get_random_hash());
+        let key_file = write_key_file("secret");
+        let old_key = CacheKey::KeyFile(key_file.clone());
+        let new_key = CacheKey::Passphrase("correct horse battery staple".to_string());
+
+        let mut cache = Cache::encrypted(Some(path), &old_key).unwrap();
+        cache.set("key", "value");
+        IndexedStore::new(path).set("corrupted", "not encrypted");
+        assert_eq!(
+            Cache::rekey(Some(path), &old_key, &new_key),
+            Err(String::from(
+                "Error: 1 of 2 cache entries can't be decrypted, the cache was left unchanged."
+            ))
+        );
+
+        let mut reopened = Cache::encrypted(Some(path), &old_key).unwrap();
+        assert_eq!(reopened.get("key"), Some("value".to_string()));
+        assert!(!std::path::Path::new(&format!("{path}.rekey.key")).exists());
+        remove_encrypted(path);
+        remove_file(key_file).unwrap();
+    }
 }
@@ -0,0 +1,281 @@
+use crate::cache::CacheStore;
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{read, read_to_string, write};
+use std::io::ErrorKind;
+
+const KEY_CHECK: &[u8] = b"gpt-cli cache key check";
+const NONCE_LEN: usize = 24;
+
+/// Secret the cache encryption key is derived from.
+pub enum CacheKey {
+    Passphrase(String),
+    KeyFile(String),
+}
+
+impl CacheKey {
+    /// `Ok(None)` means the cache is stored in plain text.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        CacheKey::resolve(
+            std::env::var("GPT_CACHE_KEY_FILE").ok(),
+            std::env::var("GPT_CACHE_PASSPHRASE").ok(),
+            std::env::var("GPT_CACHE_ENCRYPT").ok(),
+        )
+    }
+
+    fn resolve(
+        key_file: Option<String>,
+        passphrase: Option<String>,
+        encrypt: Option<String>,
+    ) -> Result<Option<Self>, String> {
+        if let Some(path) = key_file {
+            return Ok(Some(CacheKey::KeyFile(path)));
+        }
+        if let Some(passphrase) = passphrase {
+            return Ok(Some(CacheKey::Passphrase(passphrase)));
+        }
+        match encrypt.as_deref() {
+            Some("1") | Some("true") => Err(String::from(
+                "Error: GPT_CACHE_ENCRYPT is set but neither GPT_CACHE_PASSPHRASE nor GPT_CACHE_KEY_FILE is defined.",
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    fn derive(&self, salt: &[u8]) -> Result<[u8; 32], String> {
+        match self {
+            CacheKey::KeyFile(path) => {
+                let bytes = read(path)
+                    .map_err(|e| format!("Error: can't read cache key file {path}: {e}"))?;
+                if bytes.is_empty() {
+                    return Err(format!("Error: cache key file {path} is empty."));
+                }
+                Ok(Sha256::digest(bytes).into())
+            }
+            CacheKey::Passphrase(passphrase) => {
+                if passphrase.is_empty() {
+                    return Err(String::from("Error: cache passphrase is empty."));
+                }
+                let mut key = [0u8; 32];
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .map_err(|e| format!("Error: can't derive cache key: {e}"))?;
+                Ok(key)
+            }
+        }
+    }
+}
+
+/// Kept next to the cache as `{path}.key`, lets a wrong key be reported instead of silently
+/// missing every entry.
+#[derive(Serialize, Deserialize)]
+struct KeyHeader {
+    salt: String,
+    check: String,
+}
+
+/// Wraps another store, replacing keys with their HMAC and values with XChaCha20-Poly1305
+/// encrypted `[key, value]` pairs, so neither prompts nor answers are stored in plain text.
+pub struct EncryptedStore {
+    inner: Box<dyn CacheStore>,
+    header_path: String,
+    id_key: [u8; 32],
+    cipher: XChaCha20Poly1305,
+}
+
+impl EncryptedStore {
+    pub fn open(inner: Box<dyn CacheStore>, path: &str, key: &CacheKey) -> Result<Self, String> {
+        let header_path = format!("{path}.key");
+        let header = match read_to_string(&header_path) {
+            Ok(contents) => Some(
+                serde_json::from_str::<KeyHeader>(&contents)
+                    .map_err(|e| format!("Error: corrupted cache key header {header_path}: {e}"))?,
+            ),
+            // only a cache without entries may get a new key, entries of any other can't be read back
+            Err(e) if e.kind() == ErrorKind::NotFound && is_empty(path) => None,
+            Err(e) => {
+                return Err(format!(
+                    "Error: corrupted cache key header {header_path}: {e}"
+                ));
+            }
+        };
+
+        match header {
+            Some(header) => {
+                let salt = hex::decode(&header.salt)
+                    .map_err(|e| format!("Error: corrupted cache key header {header_path}: {e}"))?;
+                let store = EncryptedStore::with_key(inner, header_path, key.derive(&salt)?);
+                if store.check() != header.check {
+                    return Err(format!(
+                        "Error: cache key does not match the one {path} was encrypted with."
+                    ));
+                }
+                Ok(store)
+            }
+            None => {
+                let salt: [u8; 16] = rand::random();
+                let store = EncryptedStore::with_key(inner, header_path, key.derive(&salt)?);
+                store.write_header(&salt)?;
+                Ok(store)
+            }
+        }
+    }
+
+    fn with_key(inner: Box<dyn CacheStore>, header_path: String, master: [u8; 32]) -> Self {
+        EncryptedStore {
+            inner,
+            header_path,
+            id_key: hmac(&master, b"gpt-cli cache id"),
+            cipher: XChaCha20Poly1305::new(&hmac(&master, b"gpt-cli cache encryption").into()),
+        }
+    }
+
+    /// Copies every entry into `target`, encrypted with its key. Copies nothing when an entry
+    /// can't be decrypted, so a rekey never drops entries.
+    pub fn copy_to(&mut self, target: &mut EncryptedStore) -> Result<usize, String> {
+        let encrypted = self.inner.entries()?;
+        let entries: Vec<(String, String)> = encrypted
+            .iter()
+            .filter_map(|(_, encrypted)| self.decrypt(encrypted))
+            .collect();
+        if entries.len() < encrypted.len() {
+            return Err(format!(
+                "Error: {} of {} cache entries can't be decrypted, the cache was left unchanged.",
+                encrypted.len() - entries.len(),
+                encrypted.len()
+            ));
+        }
+        for (key, value) in entries.iter() {
+            target.set(key, value);
+        }
+        Ok(entries.len())
+    }
+
+    fn check(&self) -> String {
+        hex::encode(hmac(&self.id_key, KEY_CHECK))
+    }
+
+    fn write_header(&self, salt: &[u8]) -> Result<(), String> {
+        let header = KeyHeader {
+            salt: hex::encode(salt),
+            check: self.check(),
+        };
+        write(&self.header_path, serde_json::to_string(&header).unwrap()).map_err(|e| {
+            format!(
+                "Error: can't write cache key header {}: {e}",
+                self.header_path
+            )
+        })
+    }
+
+    fn id(&self, key: &str) -> String {
+        hex::encode(hmac(&self.id_key, key.as_bytes()))
+    }
+
+    fn encrypt(&self, key: &str, value: &str) -> String {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let plaintext = serde_json::to_vec(&(key, value)).unwrap();
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+            .expect("Failed to encrypt cache entry");
+        hex::encode([nonce.as_slice(), ciphertext.as_slice()].concat())
+    }
+
+    fn decrypt(&self, encrypted: &str) -> Option<(String, String)> {
+        let bytes = hex::decode(encrypted).ok()?;
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        serde_json::from_slice(&plaintext).ok()
+    }
+}
+
+impl CacheStore for EncryptedStore {
+    fn get(&mut self, key: &str) -> Option<String> {
+        let encrypted = self.inner.get(&self.id(key))?;
+        match self.decrypt(&encrypted) {
+            Some((stored_key, value)) if stored_key == key => Some(value),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        let id = self.id(key);
+        let encrypted = self.encrypt(key, value);
+        self.inner.set(&id, &encrypted);
+    }
+
+    /// Entries that can't be decrypted are left out, see [`EncryptedStore::copy_to`].
+    fn entries(&mut self) -> Result<Vec<(String, String)>, String> {
+        Ok(self
+            .inner
+            .entries()?
+            .iter()
+            .filter_map(|(_, encrypted)| self.decrypt(encrypted))
+            .collect())
+    }
+}
+
+/// Whether the log at `path` is missing or holds nothing.
+fn is_empty(path: &str) -> bool {
+    match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len() == 0,
+        Err(e) => e.kind() == ErrorKind::NotFound,
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_without_secret() {
+        assert!(matches!(CacheKey::resolve(None, None, None), Ok(None)));
+    }
+
+    #[test]
+    fn test_resolve_prefers_key_file() {
+        let key = CacheKey::resolve(
+            Some("/tmp/key".to_string()),
+            Some("passphrase".to_string()),
+            None,
+        );
+        assert!(matches!(key, Ok(Some(CacheKey::KeyFile(path))) if path == "/tmp/key"));
+    }
+
+    #[test]
+    fn test_resolve_encrypt_without_secret() {
+        match CacheKey::resolve(None, None, Some("1".to_string())) {
+            Err(error) => assert_eq!(
+                error,
+                "Error: GPT_CACHE_ENCRYPT is set but neither GPT_CACHE_PASSPHRASE nor GPT_CACHE_KEY_FILE is defined."
+            ),
+            _ => panic!("Missing key error was expected."),
+        }
+    }
+
+    #[test]
+    fn test_missing_key_file() {
+        let error = CacheKey::KeyFile("/nonexistent/gpt-cli.key".to_string())
+            .derive(&[])
+            .unwrap_err();
+        assert!(error.starts_with("Error: can't read cache key file /nonexistent/gpt-cli.key"));
+    }
+}
@@ -0,0 +1,261 @@
+use crate::cache::CacheStore;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const INDEX_ENTRY_LEN: usize = 16;
+
+/// Offset, key and value of log records.
+type Records = Vec<(u64, String, String)>;
+
+/// Append-only log of `key`/`value` records with a side index mapping a hash of the key to
+/// the offset of its latest record, so a lookup reads the index and a single record instead
+/// of the whole cache.
+///
+/// Log record: `[key_len: u32][value_len: u32][key][value]`, index entry: `[hash: u64][offset: u64]`,
+/// all little endian. When the index is missing it is rebuilt from the log. What an interrupted
+/// write leaves at the end of either file is cut off before the next record is appended.
+pub struct IndexedStore {
+    log_path: String,
+    index_path: String,
+    index: Option<HashMap<u64, u64>>,
+    /// Both files end with a whole record, so appending is safe.
+    repaired: bool,
+}
+
+impl IndexedStore {
+    pub fn new(path: &str) -> Self {
+        IndexedStore {
+            log_path: path.to_string(),
+            index_path: format!("{path}.idx"),
+            index: None,
+            repaired: false,
+        }
+    }
+
+    fn index(&mut self) -> &mut HashMap<u64, u64> {
+        if self.index.is_none() {
+            let index = match self.read_index() {
+                Ok(index) => index,
+                Err(_) => self.rebuild_index().unwrap_or_default(),
+            };
+            self.index = Some(index);
+        }
+        self.index.as_mut().unwrap()
+    }
+
+    fn read_index(&self) -> std::io::Result<HashMap<u64, u64>> {
+        let mut bytes = Vec::new();
+        File::open(&self.index_path)?.read_to_end(&mut bytes)?;
+        Ok(bytes
+            .chunks_exact(INDEX_ENTRY_LEN)
+            .map(|entry| {
+                let (hash, offset) = entry.split_at(8);
+                (
+                    u64::from_le_bytes(hash.try_into().unwrap()),
+                    u64::from_le_bytes(offset.try_into().unwrap()),
+                )
+            })
+            .collect())
+    }
+
+    fn rebuild_index(&self) -> std::io::Result<HashMap<u64, u64>> {
+        let mut index = HashMap::new();
+        let records = self.scan()?;
+        if records.is_empty() {
+            return Ok(index);
+        }
+        let mut index_bytes = Vec::new();
+        for (offset, key, _) in records {
+            index.insert(hash(&key), offset);
+            index_bytes.extend_from_slice(&hash(&key).to_le_bytes());
+            index_bytes.extend_from_slice(&offset.to_le_bytes());
+        }
+        std::fs::write(&self.index_path, index_bytes)?;
+        Ok(index)
+    }
+
+    fn scan(&self) -> std::io::Result<Records> {
+        self.scan_from(0).map(|(records, _)| records)
+    }
+
+    /// Records from `offset` up to the first one that is torn, and where that one starts.
+    fn scan_from(&self, mut offset: u64) -> std::io::Result<(Records, u64)> {
+        if !Path::new(&self.log_path).exists() {
+            return Ok((vec![], 0));
+        }
+        let mut file = File::open(&self.log_path)?;
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+        let mut records = Vec::new();
+        loop {
+            match read_record(&mut reader, len.saturating_sub(offset)) {
+                Ok((key, value)) => {
+                    let len = record_len(&key, &value);
+                    records.push((offset, key, value));
+                    offset += len;
+                }
+                // a truncated or garbled trailing record is what an interrupted write leaves behind
+                Err(e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::InvalidData) => {
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((records, offset))
+    }
+
+    fn read_at(&self, offset: u64) -> std::io::Result<(String, String)> {
+        let mut file = File::open(&self.log_path)?;
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(offset))?;
+        read_record(&mut BufReader::new(file), len.saturating_sub(offset))
+    }
+
+    /// Cuts a partial entry off the index and a torn record off the log. Records written after
+    /// the last indexed one, whose index entry was lost, are indexed again.
+    fn repair(&mut self) -> std::io::Result<()> {
+        if self.repaired {
+            return Ok(());
+        }
+        let index_len = file_len(&self.index_path)?;
+        let torn_entry = index_len % INDEX_ENTRY_LEN as u64;
+        if torn_entry != 0 {
+            OpenOptions::new()
+                .write(true)
+                .open(&self.index_path)?
+                .set_len(index_len - torn_entry)?;
+        }
+
+        let last = self.index().values().max().copied();
+        let start = last
+            .and_then(|offset| {
+                self.read_at(offset)
+                    .ok()
+                    .map(|(key, value)| offset + record_len(&key, &value))
+            })
+            .unwrap_or(0);
+        let (records, end) = self.scan_from(start)?;
+        for (offset, key, _) in records {
+            self.append_index(&key, offset)?;
+        }
+        if file_len(&self.log_path)? > end {
+            OpenOptions::new()
+                .write(true)
+                .open(&self.log_path)?
+                .set_len(end)?;
+        }
+        self.repaired = true;
+        Ok(())
+    }
+
+    fn append_index(&mut self, key: &str, offset: u64) -> std::io::Result<()> {
+        let mut index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.index_path)?;
+        let mut entry = [0u8; INDEX_ENTRY_LEN];
+        entry[..8].copy_from_slice(&hash(key).to_le_bytes());
+        entry[8..].copy_from_slice(&offset.to_le_bytes());
+        index.write_all(&entry)?;
+        self.index().insert(hash(key), offset);
+        Ok(())
+    }
+
+    fn append(&mut self, key: &str, value: &str) -> std::io::Result<()> {
+        self.repair()?;
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_path)?;
+        let offset = log.seek(SeekFrom::End(0))?;
+        let mut record = Vec::with_capacity(record_len(key, value) as usize);
+        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(key.as_bytes());
+        record.extend_from_slice(value.as_bytes());
+        log.write_all(&record)?;
+        self.append_index(key, offset)
+    }
+}
+
+impl CacheStore for IndexedStore {
+    fn get(&mut self, key: &str) -> Option<String> {
+        let offset = *self.index().get(&hash(key))?;
+        // hashes may collide, so the stored key has to match too
+        match self.read_at(offset) {
+            Ok((stored_key, value)) if stored_key == key => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The cache is best effort, an answer that can't be written is only reported.
+    fn set(&mut self, key: &str, value: &str) {
+        if let Err(e) = self.append(key, value) {
+            eprintln!("Can't write to cache {}: {e}", self.log_path);
+        }
+    }
+
+    fn entries(&mut self) -> Result<Vec<(String, String)>, String> {
+        let records = self
+            .scan()
+            .map_err(|e| format!("Error: can't read cache {}: {e}", self.log_path))?;
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        let mut entries: Vec<(String, String)> = Vec::new();
+        for (_, key, value) in records {
+            match positions.get(&key) {
+                Some(&position) => entries[position].1 = value,
+                None => {
+                    positions.insert(key.clone(), entries.len());
+                    entries.push((key, value));
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Length of the file, 0 when it doesn't exist.
+fn file_len(path: &str) -> std::io::Result<u64> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the record at the position of `reader`, with at most `available` bytes left in the log.
+fn read_record(reader: &mut impl Read, available: u64) -> std::io::Result<(String, String)> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    let key_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let value_len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    if (8 + key_len + value_len) as u64 > available {
+        return Err(std::io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "record goes past the end of the log",
+        ));
+    }
+    let mut key = vec![0u8; key_len];
+    reader.read_exact(&mut key)?;
+    let mut value = vec![0u8; value_len];
+    reader.read_exact(&mut value)?;
+    let invalid = |e| std::io::Error::new(ErrorKind::InvalidData, e);
+    Ok((
+        String::from_utf8(key).map_err(invalid)?,
+        String::from_utf8(value).map_err(invalid)?,
+    ))
+}
+
+fn record_len(key: &str, value: &str) -> u64 {
+    (8 + key.len() + value.len()) as u64
+}
+
+/// FNV-1a, used instead of `DefaultHasher` because the index must stay valid across builds.
+fn hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,48 @@
+use crate::cache::CacheStore;
+use std::collections::HashMap;
+use std::fs::{read_to_string, write};
+
+/// Legacy store keeping the whole cache as a single JSON object.
+pub struct JsonStore {
+    path: String,
+    map: HashMap<String, String>,
+}
+
+impl JsonStore {
+    pub fn new(path: &str) -> Self {
+        JsonStore {
+            path: path.to_string(),
+            map: HashMap::new(),
+        }
+    }
+
+    fn load(&mut self) {
+        if self.map.is_empty() && std::path::Path::new(&self.path).exists() {
+            let contents = read_to_string(&self.path).unwrap();
+            self.map = serde_json::from_str(&contents).unwrap();
+        }
+    }
+}
+
+impl CacheStore for JsonStore {
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.load();
+        self.map.get(key).cloned()
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        self.load();
+        self.map.insert(key.to_string(), value.to_string());
+        let contents = serde_json::to_string(&self.map).unwrap();
+        write(&self.path, contents).unwrap();
+    }
+
+    fn entries(&mut self) -> Result<Vec<(String, String)>, String> {
+        self.load();
+        Ok(self
+            .map
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
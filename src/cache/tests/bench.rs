@@ -0,0 +1,75 @@
+//! Compares the legacy JSON store with the indexed one on a cache of realistic size.
+//!
+//! Run with `cargo test --release bench_cache -- --ignored --nocapture`.
+
+use super::rand_hash::get_random_hash;
+use crate::cache::{CacheStore, IndexedStore, JsonStore};
+use std::collections::HashMap;
+use std::fs::{remove_file, write};
+use std::time::{Duration, Instant};
+
+const ROUNDS: u32 = 5;
+
+fn entry(i: usize) -> (String, String) {
+    let key = format!(
+        "[{{\"role\":\"system\",\"content\":\"You are a linux terminal command generator.\"}},{{\"role\":\"user\",\"content\":\"task number {i}\"}}]"
+    );
+    let value = format!(
+        "{{\"id\":\"chatcmpl-{i}\",\"object\":\"chat.completion\",\"created\":1678705627,\"model\":\"gpt-4o\",\"usage\":{{\"prompt_tokens\":45,\"completion_tokens\":3,\"total_tokens\":48}},\"choices\":[{{\"message\":{{\"role\":\"assistant\",\"content\":\"echo {i}\"}},\"finish_reason\":\"stop\",\"index\":0}}]}}"
+    );
+    (key, value)
+}
+
+/// Average time of the first `get` and of a `set` on a fresh store, which is what a single
+/// `p` invocation pays.
+fn measure(open: impl Fn() -> Box<dyn CacheStore>, entries: usize) -> (Duration, Duration) {
+    let mut get = Duration::ZERO;
+    let mut set = Duration::ZERO;
+    for round in 0..ROUNDS {
+        let (key, _) = entry(round as usize * 997 % entries);
+        let mut store = open();
+        let start = Instant::now();
+        assert!(store.get(&key).is_some());
+        get += start.elapsed();
+
+        let (key, value) = entry(entries + round as usize);
+        let start = Instant::now();
+        store.set(&key, &value);
+        set += start.elapsed();
+    }
+    (get / ROUNDS, set / ROUNDS)
+}
+
+fn bench(entries: usize) {
+    let json_path = format!("/tmp/.gpt-cache-bench-{}.json", get_random_hash());
+    let map: HashMap<String, String> = (0..entries).map(entry).collect();
+    write(&json_path, serde_json::to_string(&map).unwrap()).unwrap();
+    let (json_get, json_set) = measure(|| Box::new(JsonStore::new(&json_path)), entries);
+    remove_file(&json_path).unwrap();
+
+    let log_path = format!("/tmp/.gpt-cache-bench-{}.log", get_random_hash());
+    let mut store = IndexedStore::new(&log_path);
+    for i in 0..entries {
+        let (key, value) = entry(i);
+        store.set(&key, &value);
+    }
+    let (indexed_get, indexed_set) = measure(|| Box::new(IndexedStore::new(&log_path)), entries);
+    remove_file(&log_path).unwrap();
+    remove_file(format!("{log_path}.idx")).unwrap();
+
+    println!("{entries} entries");
+    println!("  json    first get {json_get:>12?}  set {json_set:>12?}");
+    println!("  indexed first get {indexed_get:>12?}  set {indexed_set:>12?}");
+}
+
+#[test]
+#[ignore]
+fn bench_cache_10k() {
+    bench(10_000);
+}
+
+#[test]
+#[ignore]
+fn bench_cache_100k() {
+    bench(100_000);
+}
@@ -0,0 +1,360 @@
+use crate::api_key::redact;
+use crate::error::GptError;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Response headers kept in recordings, the ones parsing and the retry policy look at.
+const RECORDED_HEADERS: [&str; 6] = [
+    "content-type",
+    "retry-after",
+    "x-ratelimit-remaining-requests",
+    "x-ratelimit-remaining-tokens",
+    "x-ratelimit-reset-requests",
+    "x-ratelimit-reset-tokens",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Sends requests and saves their responses.
+    Record,
+    /// Answers from saved responses, never touching the network.
+    Replay,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// Path and query, so recordings replay against any host.
+    pub path: String,
+    pub body: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    /// Hex SHA-256 of the request, see [`fingerprint`].
+    pub fingerprint: String,
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Tape {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Default)]
+struct State {
+    /// `None` until the file is read.
+    interactions: Option<Vec<Interaction>>,
+    /// Responses already replayed for each fingerprint.
+    played: HashMap<String, usize>,
+}
+
+/// File of recorded requests and responses, shared by clones of the client.
+#[derive(Clone)]
+pub struct Cassette {
+    path: String,
+    mode: Mode,
+    state: Arc<Mutex<State>>,
+}
+
+/// Path and query of `url`.
+fn path_of(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_string(),
+        },
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Identifies a request by its method, path, query and body, leaving out the host and headers.
+pub fn fingerprint(method: &str, url: &str, body: &Value) -> String {
+    let request = json!({ "method": method, "path": path_of(url), "body": body });
+    hex::encode(Sha256::digest(request.to_string().as_bytes()))
+}
+
+impl Cassette {
+    /// `GPT_CASSETTE` replayed, or recorded when `GPT_CASSETTE_MODE=record`.
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("GPT_CASSETTE").ok()?;
+        let mode = match std::env::var("GPT_CASSETTE_MODE").as_deref() {
+            Ok("record") => Mode::Record,
+            _ => Mode::Replay,
+        };
+        Some(Cassette::new(&path, mode))
+    }
+
+    pub fn new(path: &str, mode: Mode) -> Self {
+        Cassette {
+            path: path.to_string(),
+            mode,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.mode == Mode::Replay
+    }
+
+    /// Reads the recordings once. A missing file is an error only when replaying, recording
+    /// appends to it.
+    pub fn load(&self) -> Result<(), GptError> {
+        let mut state = self.state.lock().unwrap();
+        if state.interactions.is_some() {
+            return Ok(());
+        }
+        let tape = match std::fs::read_to_string(&self.path) {
+            Ok(content) => serde_json::from_str::<Tape>(&content).map_err(|e| {
+                GptError::Config(format!("Error: invalid GPT_CASSETTE {}: {e}", self.path))
+            })?,
+            Err(_) if self.mode == Mode::Record => Tape::default(),
+            Err(e) => {
+                return Err(GptError::Config(format!(
+                    "Error: can't read GPT_CASSETTE {}: {e}",
+                    self.path
+                )));
+            }
+        };
+        state.interactions = Some(tape.interactions);
+        Ok(())
+    }
+
+    /// Next response recorded for the request, the last one again once all were replayed.
+    /// A request that was never recorded is answered with `404`.
+    pub fn replay(&self, method: &str, url: &str, body: &Value) -> reqwest::Response {
+        let fingerprint = fingerprint(method, url, body);
+        let mut state = self.state.lock().unwrap();
+        let played = state.played.get(&fingerprint).copied().unwrap_or_default();
+        let recorded: Vec<&Interaction> = state
+            .interactions
+            .iter()
+            .flatten()
+            .filter(|interaction| interaction.fingerprint == fingerprint)
+            .collect();
+
+        let response = match recorded.get(played).or(recorded.last()) {
+            Some(interaction) => interaction.response.clone(),
+            None => RecordedResponse {
+                status: StatusCode::NOT_FOUND.as_u16(),
+                headers: BTreeMap::from([(
+                    String::from("content-type"),
+                    String::from("application/json"),
+                )]),
+                body: json!({
+                    "error": {
+                        "message": format!("no response to request {fingerprint} recorded in {}", self.path),
+                        "type": "cassette_miss"
+                    }
+                })
+                .to_string(),
+            },
+        };
+        state.played.insert(fingerprint, played + 1);
+        build_response(&response)
+    }
+
+    /// Saves the response to the request, with `secrets` redacted, and returns it unread.
+    pub async fn record(
+        &self,
+        method: &str,
+        url: &str,
+        body: &Value,
+        response: reqwest::Response,
+        secrets: &[&str],
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let status = response.status();
+        let headers = response.headers().clone();
+        let text = response.text().await?;
+
+        let interaction = Interaction {
+            fingerprint: fingerprint(method, url, body),
+            request: RecordedRequest {
+                method: method.to_string(),
+                path: redact(&path_of(url), secrets),
+                body: serde_json::from_str(&redact(&body.to_string(), secrets)).unwrap_or_default(),
+            },
+            response: RecordedResponse {
+                status: status.as_u16(),
+                headers: RECORDED_HEADERS
+                    .iter()
+                    .filter_map(|name| {
+                        let value = headers.get(*name)?.to_str().ok()?;
+                        Some((name.to_string(), redact(value, secrets)))
+                    })
+                    .collect(),
+                body: redact(&text, secrets),
+            },
+        };
+        if let Err(e) = self.save(interaction) {
+            eprintln!("Can't save GPT_CASSETTE {}: {e}", self.path);
+        }
+
+        let mut response = http::Response::new(text);
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        Ok(reqwest::Response::from(response))
+    }
+
+    fn save(&self, interaction: Interaction) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let interactions = state.interactions.get_or_insert_with(Vec::new);
+        interactions.push(interaction);
+        let tape = Tape {
+            interactions: interactions.clone(),
+        };
+        std::fs::write(&self.path, serde_json::to_string_pretty(&tape).unwrap())
+    }
+}
+
+fn build_response(recorded: &RecordedResponse) -> reqwest::Response {
+    let mut response = http::Response::new(recorded.body.clone());
+    *response.status_mut() =
+        StatusCode::from_u16(recorded.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let headers: &mut HeaderMap = response.headers_mut();
+    for (name, value) in &recorded.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.insert(name, value);
+        }
+    }
+    reqwest::Response::from(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> String {
+        std::env::temp_dir()
+            .join(format!("gpt-cassette-{}.json", rand::random::<u64>()))
+            .display()
+            .to_string()
+    }
+
+    #[test]
+    fn test_fingerprint_ignores_host() {
+        let body = json!({ "model": "gpt-4o", "messages": [] });
+        assert_eq!(
+            fingerprint("POST", "https://api.openai.com/v1/chat/completions", &body),
+            fingerprint("POST", "http://127.0.0.1:4321/v1/chat/completions", &body)
+        );
+        assert_ne!(
+            fingerprint("POST", "https://api.openai.com/v1/chat/completions", &body),
+            fingerprint(
+                "POST",
+                "https://api.openai.com/v1/chat/completions",
+                &json!({ "model": "gpt-4o-mini", "messages": [] })
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay_in_order() {
+        let path = temp_path();
+        let url = "https://api.openai.com/v1/chat/completions";
+        let body = json!({ "model": "gpt-4o" });
+        let interaction = |status: u16, body_text: &str| Interaction {
+            fingerprint: fingerprint("POST", url, &body),
+            request: RecordedRequest {
+                method: String::from("POST"),
+                path: String::from("/v1/chat/completions"),
+                body: body.clone(),
+            },
+            response: RecordedResponse {
+                status,
+                headers: BTreeMap::from([(String::from("retry-after"), String::from("0"))]),
+                body: body_text.to_string(),
+            },
+        };
+        let tape = Tape {
+            interactions: vec![interaction(429, "slow down"), interaction(200, "ok")],
+        };
+        std::fs::write(&path, serde_json::to_string(&tape).unwrap()).unwrap();
+
+        let cassette = Cassette::new(&path, Mode::Replay);
+        cassette.load().unwrap();
+        let first = cassette.replay("POST", url, &body);
+        assert_eq!(first.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(first.headers()["retry-after"], "0");
+        assert_eq!(first.text().await.unwrap(), "slow down");
+        for _ in 0..2 {
+            let next = cassette.replay("POST", url, &body);
+            assert_eq!(next.status(), StatusCode::OK);
+            assert_eq!(next.text().await.unwrap(), "ok");
+        }
+
+        let miss = cassette.replay("POST", url, &json!({ "model": "other" }));
+        assert_eq!(miss.status(), StatusCode::NOT_FOUND);
+        assert!(miss.text().await.unwrap().contains("cassette_miss"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_cassette() {
+        let path = temp_path();
+        assert!(matches!(
+            Cassette::new(&path, Mode::Replay).load(),
+            Err(GptError::Config(_))
+        ));
+        assert!(Cassette::new(&path, Mode::Record).load().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_record_redacts_secrets() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(401)
+                .header("content-type", "application/json")
+                .header("set-cookie", "session=abc")
+                .body(r#"{"error":{"message":"Incorrect API key provided: sk-secret."}}"#);
+        });
+        let path = temp_path();
+        let url = server.url("/v1/chat/completions");
+        let body = json!({ "model": "gpt-4o" });
+        let response = reqwest::Client::new()
+            .post(&url)
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+
+        let cassette = Cassette::new(&path, Mode::Record);
+        cassette.load().unwrap();
+        let response = cassette
+            .record("POST", &url, &body, response, &["sk-secret"])
+            .await
+            .unwrap();
+        // The caller still gets the original response.
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.text().await.unwrap().contains("sk-secret"));
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("sk-secret"));
+        assert!(!saved.contains("session=abc"));
+        let tape: Tape = serde_json::from_str(&saved).unwrap();
+        assert_eq!(tape.interactions[0].request.path, "/v1/chat/completions");
+        assert_eq!(
+            tape.interactions[0].response.body,
+            r#"{"error":{"message":"Incorrect API key provided: <redacted>."}}"#
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+use crate::decompose::decompose;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+/// How the model is asked for a [`CommandResponse`] instead of free text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StructuredOutput {
+    Off,
+    /// `response_format` JSON schema on OpenAI, instructions in the prompt elsewhere.
+    Auto,
+    Schema,
+    Prompt,
+}
+
+impl StructuredOutput {
+    /// `GPT_STRUCTURED`: `1` or `true` for auto, `schema` or `prompt` to force a method.
+    pub fn from_env() -> Self {
+        match std::env::var("GPT_STRUCTURED").as_deref() {
+            Ok("1") | Ok("true") | Ok("auto") => StructuredOutput::Auto,
+            Ok("schema") => StructuredOutput::Schema,
+            Ok("prompt") => StructuredOutput::Prompt,
+            _ => StructuredOutput::Off,
+        }
+    }
+
+    /// Whether the provider is sent a JSON schema, or instructions in the prompt.
+    pub fn uses_schema(self, is_open_ai: bool) -> bool {
+        match self {
+            StructuredOutput::Auto => is_open_ai,
+            StructuredOutput::Schema => true,
+            StructuredOutput::Off | StructuredOutput::Prompt => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Risk {
+    Low,
+    Medium,
+    High,
+}
+
+/// Command with facts about it that decide how it's presented and executed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandResponse {
+    pub command: String,
+    pub explanation: String,
+    /// Uses pipes, redirections, variables or other shell syntax.
+    pub requires_shell: bool,
+    pub modifies_files: bool,
+    pub needs_sudo: bool,
+    pub risk: Risk,
+}
+
+const FALLBACK_PROMPT: &str = r#"Respond only with a JSON object, without markdown, in this shape: {"command": "<linux command>", "explanation": "<one sentence>", "requires_shell": <true if it uses pipes, redirections, variables or other shell syntax>, "modifies_files": <true or false>, "needs_sudo": <true or false>, "risk": "<low, medium or high>"}"#;
+
+impl CommandResponse {
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "command": { "type": "string", "description": "Linux command doing the task." },
+                "explanation": { "type": "string", "description": "What the command does, in one sentence." },
+                "requires_shell": { "type": "boolean", "description": "Uses pipes, redirections, variables or other shell syntax." },
+                "modifies_files": { "type": "boolean" },
+                "needs_sudo": { "type": "boolean" },
+                "risk": { "type": "string", "enum": ["low", "medium", "high"] }
+            },
+            "required": ["command", "explanation", "requires_shell", "modifies_files", "needs_sudo", "risk"],
+            "additionalProperties": false
+        })
+    }
+
+    /// `response_format` of a chat completion request.
+    pub fn response_format() -> Value {
+        json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "command",
+                "strict": true,
+                "schema": CommandResponse::schema()
+            }
+        })
+    }
+
+    /// Adds instructions describing the JSON object to the system message of request `messages`.
+    pub fn add_fallback_prompt(messages: &mut Vec<Value>) {
+        match messages
+            .iter_mut()
+            .find(|message| message["role"] == "system")
+        {
+            Some(system) => {
+                let content = system["content"].as_str().unwrap_or_default();
+                system["content"] = json!(format!("{content}\n\n{FALLBACK_PROMPT}"));
+            }
+            None => messages.insert(0, json!({ "role": "system", "content": FALLBACK_PROMPT })),
+        }
+    }
+
+    /// Parses the answer, tolerating markdown fences around the object.
+    pub fn parse(answer: &str) -> Option<Self> {
+        let start = answer.find('{')?;
+        let end = answer.rfind('}')?;
+        serde_json::from_str(answer.get(start..=end)?).ok()
+    }
+
+    /// Structured answer, or facts guessed from the text when the model ignored the format.
+    pub fn from_answer(answer: &str) -> Self {
+        CommandResponse::parse(answer).unwrap_or_else(|| CommandResponse::plain(answer))
+    }
+
+    fn plain(answer: &str) -> Self {
+        let command = answer.trim().trim_matches('`').to_string();
+        CommandResponse {
+            requires_shell: command.contains(['|', '&', ';', '>', '<', '$', '*']),
+            needs_sudo: command.starts_with("sudo "),
+            modifies_files: false,
+            risk: Risk::Medium,
+            explanation: String::new(),
+            command,
+        }
+    }
+
+    /// `export`, `cd`, `source` and the like change the user's shell, running them in a child is pointless.
+    pub fn changes_current_shell(&self) -> bool {
+        let first = self.command.split_whitespace().next().unwrap_or_default();
+        matches!(first, "export" | "cd" | "source" | "." | "alias" | "unset")
+    }
+
+    /// Program and arguments to execute.
+    pub fn program(&self) -> (String, Vec<String>) {
+        if self.requires_shell {
+            (
+                String::from("bash"),
+                vec![String::from("-c"), self.command.clone()],
+            )
+        } else {
+            decompose(&self.command)
+        }
+    }
+
+    /// Command with its explanation and warnings, for the confirmation prompt.
+    pub fn describe(&self) -> String {
+        let mut lines = vec![self.command.green().to_string()];
+        if !self.explanation.is_empty() {
+            lines.push(self.explanation.dimmed().to_string());
+        }
+        let mut warnings = vec![];
+        if self.needs_sudo {
+            warnings.push("needs sudo");
+        }
+        if self.modifies_files {
+            warnings.push("modifies files");
+        }
+        match self.risk {
+            Risk::High => warnings.push("high risk"),
+            Risk::Medium if !warnings.is_empty() => warnings.push("medium risk"),
+            _ => {}
+        }
+        if !warnings.is_empty() {
+            let warnings = warnings.join(", ");
+            lines.push(match self.risk {
+                Risk::High => warnings.red().bold().to_string(),
+                _ => warnings.yellow().to_string(),
+            });
+        }
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(command: &str, requires_shell: bool) -> CommandResponse {
+        CommandResponse {
+            command: command.to_string(),
+            explanation: String::from("Does it."),
+            requires_shell,
+            modifies_files: false,
+            needs_sudo: false,
+            risk: Risk::Low,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let answer = r#"```json
+{"command": "df -h", "explanation": "Shows free disk space.", "requires_shell": false, "modifies_files": false, "needs_sudo": false, "risk": "low"}
+```"#;
+        assert_eq!(
+            CommandResponse::parse(answer),
+            Some(CommandResponse {
+                explanation: String::from("Shows free disk space."),
+                ..command("df -h", false)
+            })
+        );
+        assert_eq!(CommandResponse::parse("df -h"), None);
+    }
+
+    #[test]
+    fn test_from_plain_answer() {
+        let response = CommandResponse::from_answer("`sudo lspci | grep VGA`");
+        assert_eq!(response.command, "sudo lspci | grep VGA");
+        assert!(response.requires_shell && response.needs_sudo);
+    }
+
+    #[test]
+    fn test_program() {
+        assert_eq!(
+            command("ls -la", false).program(),
+            (String::from("ls"), vec![String::from("-la")])
+        );
+        assert_eq!(
+            command("echo $HOME > home.txt", true).program(),
+            (
+                String::from("bash"),
+                vec![String::from("-c"), String::from("echo $HOME > home.txt")]
+            )
+        );
+    }
+
+    #[test]
+    fn test_changes_current_shell() {
+        assert!(command("export EDITOR=vim", true).changes_current_shell());
+        assert!(command("cd /tmp", false).changes_current_shell());
+        assert!(!command("echo $HOME", true).changes_current_shell());
+    }
+
+    #[test]
+    fn test_add_fallback_prompt() {
+        let mut messages = vec![
+            json!({ "role": "system", "content": "You generate commands." }),
+            json!({ "role": "user", "content": "show calendar" }),
+        ];
+        CommandResponse::add_fallback_prompt(&mut messages);
+        let system = messages[0]["content"].as_str().unwrap();
+        assert!(system.starts_with("You generate commands.\n\nRespond only with a JSON object"));
+
+        let mut messages = vec![json!({ "role": "user", "content": "show calendar" })];
+        CommandResponse::add_fallback_prompt(&mut messages);
+        assert_eq!(messages[0]["role"], "system");
+    }
+
+    #[test]
+    fn test_schema_requires_every_property() {
+        let schema = CommandResponse::schema();
+        let properties = schema["properties"].as_object().unwrap();
+        let required = schema["required"].as_array().unwrap();
+        assert_eq!(properties.len(), required.len());
+        assert!(
+            required
+                .iter()
+                .all(|name| properties.contains_key(name.as_str().unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_uses_schema() {
+        assert!(StructuredOutput::Auto.uses_schema(true));
+        assert!(!StructuredOutput::Auto.uses_schema(false));
+        assert!(StructuredOutput::Schema.uses_schema(false));
+        assert!(!StructuredOutput::Prompt.uses_schema(true));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::cache::{Cache, CacheKey};
+use crate::fix;
+use crate::gpt3::Gpt;
+use crate::models;
+use crate::should_exit::ShouldExit;
+use crate::usage::{Ledger, month_report, today};
+use colored::Colorize;
+use inquire::Password;
+use tokio::runtime::Runtime;
+
+/// Subcommands recognized instead of a task description, `None` when `args` is a task.
+pub fn run(args: &[String]) -> Option<ShouldExit> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["cache", "rekey"] => Some(cache_rekey()),
+        ["usage"] => Some(usage()),
+        ["models"] => Some(list_models("")),
+        ["models", filter] => Some(list_models(filter)),
+        ["init", shell] => Some(init(shell)),
+        _ => None,
+    }
+}
+
+fn cache_rekey() -> ShouldExit {
+    let result = CacheKey::from_env().and_then(|key| {
+        let key = key.ok_or_else(|| {
+            String::from("Error: cache is not encrypted, set GPT_CACHE_PASSPHRASE or GPT_CACHE_KEY_FILE to the current key.")
+        })?;
+        let new_key = new_cache_key()?;
+        Cache::rekey(None, &key, &new_key)
+    });
+
+    match result {
+        Ok(count) => ShouldExit {
+            exit: true,
+            messages: vec![
+                format!("Cache re-encrypted, {count} entries moved to the new key.").green(),
+            ],
+            is_error: false,
+        },
+        Err(error) => ShouldExit {
+            exit: true,
+            messages: vec![error.red()],
+            is_error: true,
+        },
+    }
+}
+
+/// Shell hook recording the last command for `p fix`.
+fn init(shell: &str) -> ShouldExit {
+    match fix::hook(shell) {
+        Some(hook) => ShouldExit {
+            exit: true,
+            messages: vec![hook.normal()],
+            is_error: false,
+        },
+        None => ShouldExit {
+            exit: true,
+            messages: vec![format!("Error: unsupported shell {shell}, use bash or zsh.").red()],
+            is_error: true,
+        },
+    }
+}
+
+/// Models of the provider whose id contains `filter`. Errors of the provider exit with their own
+/// code, like those of a prompt.
+fn list_models(filter: &str) -> ShouldExit {
+    let client = Gpt::new(Some(false), None);
+    let listed = Runtime::new()
+        .unwrap()
+        .block_on(client.models())
+        .unwrap_or_else(|error| crate::exit_with_error(error));
+    let found = models::filter(&listed, filter);
+    if found.is_empty() {
+        return ShouldExit {
+            exit: true,
+            messages: vec![format!("No models matching {filter}.").yellow()],
+            is_error: true,
+        };
+    }
+    ShouldExit {
+        exit: true,
+        messages: models::table(&found)
+            .into_iter()
+            .map(|line| line.normal())
+            .collect(),
+        is_error: false,
+    }
+}
+
+/// Spend of the current month by profile and model.
+fn usage() -> ShouldExit {
+    match Ledger::load(&Ledger::default_path()) {
+        Ok(ledger) => ShouldExit {
+            exit: true,
+            messages: month_report(&ledger, &today())
+                .into_iter()
+                .map(|line| line.normal())
+                .collect(),
+            is_error: false,
+        },
+        Err(error) => ShouldExit {
+            exit: true,
+            messages: vec![error.red()],
+            is_error: true,
+        },
+    }
+}
+
+/// `GPT_CACHE_NEW_KEY_FILE` or a passphrase typed twice.
+fn new_cache_key() -> Result<CacheKey, String> {
+    if let Ok(path) = std::env::var("GPT_CACHE_NEW_KEY_FILE") {
+        return Ok(CacheKey::KeyFile(path));
+    }
+    Password::new("New cache passphrase:")
+        .prompt()
+        .map(CacheKey::Passphrase)
+        .map_err(|e| format!("Error: can't read new passphrase: {e}"))
+}
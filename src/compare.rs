@@ -0,0 +1,311 @@
+use crate::command_response::CommandResponse;
+use crate::error::GptError;
+use crate::gpt3::{Gpt, Gpt3Message, Gpt3Response};
+use crate::spinner::Spinner;
+use crate::usage::Pricing;
+use colored::Colorize;
+use futures::future::join_all;
+use inquire::Select;
+use std::time::{Duration, Instant};
+
+/// Longest command shown in the table, the full one is shown when choosing.
+const MAX_COMMAND_WIDTH: usize = 60;
+
+/// Answer of one of the compared models.
+pub struct Comparison {
+    pub label: String,
+    pub result: Result<Gpt3Response, GptError>,
+    pub latency: Duration,
+}
+
+impl Comparison {
+    /// Answer as returned by the model.
+    fn answer(&self) -> Option<&str> {
+        let response = self.result.as_ref().ok()?;
+        Some(response.choices.first()?.message.content.as_str())
+    }
+
+    /// Command of the answer, normalized so that equal commands compare equal.
+    fn command(&self, structured: bool) -> Option<String> {
+        let answer = self.answer()?;
+        let command = match structured {
+            true => CommandResponse::from_answer(answer).command,
+            false => answer.trim().trim_matches('`').to_string(),
+        };
+        Some(command.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+
+    fn cost(&self, pricing: &Pricing) -> String {
+        match &self.result {
+            Ok(response) if response.cached => String::from("cached"),
+            Ok(response) => pricing
+                .cost(
+                    &response.model,
+                    response.usage.prompt_tokens.max(0) as u64,
+                    response.usage.completion_tokens.max(0) as u64,
+                )
+                .map_or_else(|| String::from("unknown"), |cost| format!("${cost:.6}")),
+            Err(_) => String::from("-"),
+        }
+    }
+}
+
+/// Sends `messages` to every client at once, timing each answer.
+pub async fn ask_all(clients: &[Gpt], messages: &[Gpt3Message]) -> Vec<Comparison> {
+    let spinner = Spinner::start(&format!("Waiting for {} models", clients.len()));
+    let comparisons = join_all(clients.iter().map(|client| async move {
+        let started = Instant::now();
+        let result = client.ask(messages.to_vec()).await;
+        Comparison {
+            label: client.label(),
+            result,
+            latency: started.elapsed(),
+        }
+    }))
+    .await;
+    drop(spinner);
+    comparisons
+}
+
+/// Number of models that answered with the same command as each of them.
+fn agreement(commands: &[Option<String>]) -> Vec<usize> {
+    commands
+        .iter()
+        .map(|command| match command {
+            Some(command) => commands
+                .iter()
+                .filter(|other| other.as_ref() == Some(command))
+                .count(),
+            None => 0,
+        })
+        .collect()
+}
+
+fn shorten(command: &str) -> String {
+    let first_line = command.lines().next().unwrap_or_default();
+    if first_line.chars().count() > MAX_COMMAND_WIDTH || command.contains('\n') {
+        let shortened: String = first_line.chars().take(MAX_COMMAND_WIDTH - 1).collect();
+        format!("{shortened}…")
+    } else {
+        first_line.to_string()
+    }
+}
+
+/// Table of commands, latency, cost and agreement, the commands most models agree on in green.
+pub fn table(comparisons: &[Comparison], structured: bool, pricing: &Pricing) -> Vec<String> {
+    let commands: Vec<Option<String>> = comparisons
+        .iter()
+        .map(|comparison| comparison.command(structured))
+        .collect();
+    let agreement = agreement(&commands);
+    let most = agreement.iter().copied().max().unwrap_or_default();
+
+    let header = [
+        String::from("Model"),
+        String::from("Command"),
+        String::from("Latency"),
+        String::from("Cost"),
+        String::from("Agree"),
+    ];
+    let rows: Vec<[String; 5]> = comparisons
+        .iter()
+        .zip(&commands)
+        .zip(&agreement)
+        .map(|((comparison, command), agreed)| {
+            let command = match (&comparison.result, command) {
+                (Err(error), _) => shorten(&error.to_string()),
+                (Ok(_), command) => shorten(command.as_deref().unwrap_or_default()),
+            };
+            [
+                comparison.label.clone(),
+                command,
+                format!("{:.1}s", comparison.latency.as_secs_f64()),
+                comparison.cost(pricing),
+                match agreed {
+                    0 => String::from("-"),
+                    agreed => format!("{agreed}/{}", comparisons.len()),
+                },
+            ]
+        })
+        .collect();
+
+    let mut widths = header.clone().map(|cell| cell.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |row: &[String; 5]| {
+        row.iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    let mut lines = vec![line(&header).bold().to_string()];
+    for ((row, comparison), agreed) in rows.iter().zip(comparisons).zip(&agreement) {
+        let text = line(row);
+        lines.push(match comparison.result {
+            Err(_) => text.red().to_string(),
+            Ok(_) if *agreed > 1 && *agreed == most => text.green().to_string(),
+            Ok(_) => text,
+        });
+    }
+    lines
+}
+
+/// Lets the user pick one of the answers, `None` when no model answered or nothing was chosen.
+pub fn choose(comparisons: &[Comparison], structured: bool) -> Option<String> {
+    let answered: Vec<&Comparison> = comparisons
+        .iter()
+        .filter(|comparison| comparison.answer().is_some())
+        .collect();
+    if answered.is_empty() {
+        return None;
+    }
+    let labels: Vec<String> = answered
+        .iter()
+        .map(|comparison| {
+            format!(
+                "{}: {}",
+                comparison.label,
+                comparison.command(structured).unwrap_or_default()
+            )
+        })
+        .collect();
+    let choice = Select::new("Choose a command:", labels).raw_prompt().ok()?;
+    answered[choice.index].answer().map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn answered(label: &str, content: &str, cached: bool) -> Comparison {
+        let mut response: Gpt3Response = serde_json::from_value(json!({
+            "id": "testid",
+            "object": "chat.completion",
+            "created": 1678705627,
+            "model": "gpt-4o",
+            "usage": { "prompt_tokens": 1000, "completion_tokens": 100, "total_tokens": 1100 },
+            "choices": [
+                { "message": { "role": "assistant", "content": content }, "finish_reason": "stop", "index": 0 }
+            ]
+        }))
+        .unwrap();
+        response.cached = cached;
+        Comparison {
+            label: label.to_string(),
+            result: Ok(response),
+            latency: Duration::from_millis(1250),
+        }
+    }
+
+    fn failed(label: &str) -> Comparison {
+        Comparison {
+            label: label.to_string(),
+            result: Err(GptError::Timeout(String::from("operation timed out"))),
+            latency: Duration::from_secs(120),
+        }
+    }
+
+    #[test]
+    fn test_agreement() {
+        let comparisons = [
+            answered("openai:gpt-4o", "ls  -la", false),
+            answered("openai:gpt-4o-mini", "`ls -la`", false),
+            answered("gemini:gemini-2.0-flash", "ls -al", false),
+            failed("azure:gpt-4o-prod"),
+        ];
+        let commands: Vec<Option<String>> = comparisons
+            .iter()
+            .map(|comparison| comparison.command(false))
+            .collect();
+        assert_eq!(agreement(&commands), vec![2, 2, 1, 0]);
+    }
+
+    fn without_colors(line: &str) -> String {
+        let mut plain = String::new();
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|c| *c == 'm');
+            } else {
+                plain.push(c);
+            }
+        }
+        plain
+    }
+
+    #[test]
+    fn test_table() {
+        let comparisons = [
+            answered("openai:gpt-4o", "ls -la", false),
+            answered("gemini:gemini-2.0-flash", "ls -la", true),
+            failed("azure:gpt-4o-prod"),
+        ];
+        let lines: Vec<String> = table(&comparisons, false, &Pricing::default())
+            .iter()
+            .map(|line| without_colors(line))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Model                    Command                                        Latency  Cost       Agree",
+                "openai:gpt-4o            ls -la                                         1.2s     $0.003500  2/3",
+                "gemini:gemini-2.0-flash  ls -la                                         1.2s     cached     2/3",
+                "azure:gpt-4o-prod        Error: request timed out: operation timed out  120.0s   -          -",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shorten() {
+        assert_eq!(shorten("cal"), "cal");
+        assert_eq!(shorten("for f in *\ndo echo $f\ndone"), "for f in *…");
+        assert_eq!(shorten(&"a".repeat(100)).chars().count(), MAX_COMMAND_WIDTH);
+    }
+
+    #[tokio::test]
+    async fn test_ask_all() {
+        let server = httpmock::MockServer::start();
+        for (model, answer) in [("fast", "cal"), ("smart", "ncal")] {
+            server.mock(|when, then| {
+                when.method(httpmock::Method::POST)
+                    .json_body_partial(json!({ "model": model }).to_string());
+                then.status(200).json_body_obj(&json!({
+                    "id": "testid",
+                    "object": "chat.completion",
+                    "created": 1678705627,
+                    "model": model,
+                    "usage": { "prompt_tokens": 45, "completion_tokens": 1, "total_tokens": 46 },
+                    "choices": [
+                        { "message": { "role": "assistant", "content": answer }, "finish_reason": "stop", "index": 0 }
+                    ]
+                }));
+            });
+        }
+
+        let clients = Gpt::mock(&server.url("")).for_models("fast,smart");
+        let messages = vec![Gpt3Message {
+            role: String::from("user"),
+            content: String::from("show calendar"),
+        }];
+        let comparisons = ask_all(&clients, &messages).await;
+        let answers: Vec<(String, Option<&str>)> = comparisons
+            .iter()
+            .map(|comparison| (comparison.label.clone(), comparison.answer()))
+            .collect();
+        assert_eq!(
+            answers,
+            vec![
+                (String::from("openai:fast"), Some("cal")),
+                (String::from("openai:smart"), Some("ncal"))
+            ]
+        );
+    }
+}
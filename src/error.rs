@@ -0,0 +1,573 @@
+use reqwest::StatusCode;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+
+/// Error object returned by OpenAI compatible APIs as `{"error": {...}}`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ApiError {
+    #[serde(default)]
+    pub message: String,
+    #[serde(default, rename = "type")]
+    pub kind: Option<String>,
+    /// Azure sends some codes as numbers.
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub param: Option<String>,
+    /// Gemini's `NOT_FOUND`, `INVALID_ARGUMENT` and the like, the HTTP status on Azure.
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub status: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    error: ApiError,
+}
+
+/// Gemini tells what is wrong in the details of the error, eg. `API_KEY_INVALID` of a 400
+/// response.
+#[derive(Deserialize)]
+struct DetailsBody {
+    error: Details,
+}
+
+#[derive(Deserialize)]
+struct Details {
+    #[serde(default)]
+    details: Vec<Detail>,
+}
+
+#[derive(Deserialize)]
+struct Detail {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Error of Azure API Management in front of Azure OpenAI, eg. for a wrong key.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GatewayErrorBody {
+    status_code: u16,
+    message: String,
+}
+
+fn string_or_number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    Ok(
+        match Option::<serde_json::Value>::deserialize(deserializer)? {
+            Some(serde_json::Value::String(code)) => Some(code),
+            Some(serde_json::Value::Number(code)) => Some(code.to_string()),
+            _ => None,
+        },
+    )
+}
+
+impl ApiError {
+    pub fn parse(body: &str) -> Option<Self> {
+        serde_json::from_str::<ApiErrorBody>(body)
+            .map(|body| body.error)
+            .or_else(|_| {
+                serde_json::from_str::<GatewayErrorBody>(body).map(|body| ApiError {
+                    message: body.message,
+                    code: Some(body.status_code.to_string()),
+                    ..ApiError::default()
+                })
+            })
+            .ok()
+    }
+
+    fn is(&self, code: &str) -> bool {
+        [&self.code, &self.kind, &self.status]
+            .iter()
+            .any(|value| value.as_deref() == Some(code))
+    }
+
+    /// Message of a body without an OpenAI error object, eg. `{"error": "..."}` of Ollama or the
+    /// bare error object of vLLM.
+    fn loose(body: &str) -> Self {
+        let value: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+        let message = value["error"]
+            .as_str()
+            .or_else(|| value["message"].as_str())
+            .unwrap_or_default();
+        ApiError {
+            message: message.to_string(),
+            ..ApiError::default()
+        }
+    }
+
+    /// Reasons in the details of a Gemini error.
+    fn reasons(body: &str) -> Vec<String> {
+        serde_json::from_str::<DetailsBody>(body)
+            .map(|body| {
+                body.error
+                    .details
+                    .into_iter()
+                    .filter_map(|detail| detail.reason)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Every way asking the model can fail, each with its own exit code.
+#[derive(Debug)]
+pub enum GptError {
+    /// Variable the key of the provider in use is read from.
+    MissingCredentials(String),
+    AuthRejected(ApiError),
+    RateLimited(ApiError),
+    QuotaExhausted(ApiError),
+    ModelNotFound(ApiError),
+    ContextTooLong(ApiError),
+    Network(String),
+    Timeout(String),
+    MalformedResponse(String),
+    Provider {
+        status: StatusCode,
+        body: String,
+        error: Option<Box<ApiError>>,
+    },
+    NoCachedAnswer(String),
+    Cache(String),
+    Config(String),
+    BudgetExceeded(String),
+    /// Still cut off at the token limit after this many continuation requests.
+    Truncated(u32),
+    ContentFiltered(String),
+    /// Reading the key file or running the key command failed.
+    KeyUnavailable(String),
+    /// The usage ledger can't be read, locked or saved.
+    Usage(String),
+    Cancelled,
+}
+
+impl GptError {
+    /// Classifies an unsuccessful response by its status and the error object in its body.
+    pub fn from_response(status: StatusCode, body: String) -> Self {
+        let error = ApiError::parse(&body);
+        let api_error = || error.clone().unwrap_or_default();
+        let is = |code: &str| error.as_ref().is_some_and(|error| error.is(code));
+
+        match status.as_u16() {
+            401 | 403 => GptError::AuthRejected(api_error()),
+            429 if is("insufficient_quota") => GptError::QuotaExhausted(api_error()),
+            429 => GptError::RateLimited(api_error()),
+            404 if is("model_not_found") || is("DeploymentNotFound") || is("NOT_FOUND") => {
+                GptError::ModelNotFound(api_error())
+            }
+            400 if ApiError::reasons(&body)
+                .iter()
+                .any(|reason| reason == "API_KEY_INVALID") =>
+            {
+                GptError::AuthRejected(api_error())
+            }
+            400 if is("context_length_exceeded") => GptError::ContextTooLong(api_error()),
+            400 if is("content_filter") => GptError::ContentFiltered(api_error().message),
+            _ => GptError::Provider {
+                status,
+                body,
+                error: error.map(Box::new),
+            },
+        }
+    }
+
+    /// A 400 or 404 without a known code telling that `model` doesn't exist, eg. "is not a valid
+    /// model ID" of OpenRouter, or the 404 of Ollama and vLLM, so a similar model can be suggested.
+    pub fn about_model(self, model: &str) -> Self {
+        match self {
+            GptError::Provider {
+                status,
+                body,
+                error,
+            } if matches!(status.as_u16(), 400 | 404) => {
+                let api_error = error
+                    .as_deref()
+                    .cloned()
+                    .unwrap_or_else(|| ApiError::loose(&body));
+                let message = api_error.message.to_lowercase();
+                let missing = ["not a valid", "not found", "does not exist", "no such"]
+                    .iter()
+                    .any(|phrase| message.contains(phrase));
+                if missing && !model.is_empty() && api_error.message.contains(model) {
+                    GptError::ModelNotFound(api_error)
+                } else {
+                    GptError::Provider {
+                        status,
+                        body,
+                        error,
+                    }
+                }
+            }
+            error => error,
+        }
+    }
+
+    /// Failures of the service rather than of the request, another model may still answer.
+    pub fn is_outage(&self) -> bool {
+        match self {
+            GptError::RateLimited(_)
+            | GptError::QuotaExhausted(_)
+            | GptError::Network(_)
+            | GptError::Timeout(_)
+            | GptError::NoCachedAnswer(_) => true,
+            GptError::Provider { status, .. } => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GptError::MissingCredentials(_) => 10,
+            GptError::AuthRejected(_) => 11,
+            GptError::RateLimited(_) => 12,
+            GptError::QuotaExhausted(_) => 13,
+            GptError::ModelNotFound(_) => 14,
+            GptError::ContextTooLong(_) => 15,
+            GptError::Network(_) => 16,
+            GptError::Timeout(_) => 17,
+            GptError::MalformedResponse(_) => 18,
+            GptError::Provider { .. } => 19,
+            GptError::NoCachedAnswer(_) => 20,
+            GptError::Cache(_) => 21,
+            GptError::Config(_) => 22,
+            GptError::BudgetExceeded(_) => 23,
+            GptError::Truncated(_) => 24,
+            GptError::ContentFiltered(_) => 25,
+            GptError::KeyUnavailable(_) => 26,
+            GptError::Usage(_) => 27,
+            GptError::Cancelled => 130,
+        }
+    }
+
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            GptError::MissingCredentials(_) => Some(
+                "Please set the OPENAI_API_KEY environment variable to your OpenAI API key, AZURE_OPENAI_API_KEY for Azure or GEMINI_API_KEY for Gemini.",
+            ),
+            GptError::AuthRejected(_) => Some(
+                "Check that OPENAI_API_KEY, AZURE_OPENAI_API_KEY or GEMINI_API_KEY is valid and allowed to use this model and OPENAI_BASE_URL.",
+            ),
+            GptError::RateLimited(_) => {
+                Some("Wait a moment and try again, or raise GPT_MAX_ATTEMPTS to retry for longer.")
+            }
+            GptError::QuotaExhausted(_) => {
+                Some("Check your plan and billing details with your provider.")
+            }
+            GptError::ModelNotFound(_) => Some(
+                "Check GPT_MODEL or AZURE_OPENAI_DEPLOYMENT, the model may not exist or your key may have no access to it. See p --models.",
+            ),
+            GptError::ContextTooLong(_) => {
+                Some("Shorten the description or choose a model with a larger context window.")
+            }
+            GptError::Network(_) => Some(
+                "Check your connection and proxy settings, or use --offline to answer from the cache.",
+            ),
+            GptError::Timeout(_) => Some(
+                "The server did not answer in time, try again later or raise GPT_TIMEOUT and GPT_CONNECT_TIMEOUT.",
+            ),
+            GptError::MalformedResponse(_) => Some(
+                "The server did not return an OpenAI compatible chat completion, check OPENAI_BASE_URL.",
+            ),
+            GptError::Provider { .. } => None,
+            GptError::NoCachedAnswer(_) => {
+                Some("Ask the same question once while online to cache its answer.")
+            }
+            GptError::Cache(_) => {
+                Some("Check GPT_CACHE_ENCRYPT, GPT_CACHE_PASSPHRASE and GPT_CACHE_KEY_FILE.")
+            }
+            GptError::Config(_) => Some(
+                "Check GPT_PROXY, GPT_CA_BUNDLE, GPT_CLIENT_CERT, GPT_CLIENT_KEY and GPT_CASSETTE.",
+            ),
+            GptError::BudgetExceeded(_) => Some(
+                "Wait for the next period, raise the GPT_BUDGET_* limits or pass --force-budget.",
+            ),
+            GptError::Truncated(_) => {
+                Some("Raise --max-tokens or GPT_MAX_TOKENS, or ask for a shorter answer.")
+            }
+            GptError::ContentFiltered(_) => Some("Rephrase the description."),
+            GptError::KeyUnavailable(_) => Some(
+                "Check the file in OPENAI_API_KEY_FILE or the command in OPENAI_API_KEY_COMMAND.",
+            ),
+            GptError::Usage(_) => Some(
+                "Fix or remove the usage ledger, ~/.gpt-usage.json or the file in GPT_USAGE_PATH.",
+            ),
+            GptError::Cancelled => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for GptError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            GptError::Timeout(error.to_string())
+        } else if error.is_decode() {
+            GptError::MalformedResponse(error.to_string())
+        } else {
+            GptError::Network(error.to_string())
+        }
+    }
+}
+
+impl fmt::Display for GptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GptError::MissingCredentials(variable) => {
+                write!(f, "Error: {variable} environment variable is not defined.")
+            }
+            GptError::AuthRejected(error) => {
+                write!(f, "Error: authentication rejected: {}", error.message)
+            }
+            GptError::RateLimited(error) => write!(f, "Error: rate limited: {}", error.message),
+            GptError::QuotaExhausted(error) => {
+                write!(f, "Error: quota exhausted: {}", error.message)
+            }
+            GptError::ModelNotFound(error) => {
+                write!(f, "Error: model not found: {}", error.message)
+            }
+            GptError::ContextTooLong(error) => {
+                write!(f, "Error: context too long: {}", error.message)
+            }
+            GptError::Network(message) => write!(f, "Error: network failure: {message}"),
+            GptError::Timeout(message) => write!(f, "Error: request timed out: {message}"),
+            GptError::MalformedResponse(message) => {
+                write!(f, "Error: malformed response: {message}")
+            }
+            GptError::Provider {
+                status,
+                error: Some(error),
+                ..
+            } => write!(f, "Error: provider error ({status}): {}", error.message),
+            GptError::Provider { status, body, .. } => write!(
+                f,
+                "Request failed with status code: {status}\nError response body: {body}"
+            ),
+            GptError::NoCachedAnswer(reason) => {
+                write!(f, "Error: no cached answer for this prompt, and {reason}")
+            }
+            GptError::Cache(message)
+            | GptError::Config(message)
+            | GptError::KeyUnavailable(message)
+            | GptError::Usage(message) => write!(f, "{message}"),
+            GptError::BudgetExceeded(limits) => write!(f, "Error: budget exceeded: {limits}"),
+            GptError::Truncated(0) => {
+                write!(f, "Error: the answer was cut off at the token limit.")
+            }
+            GptError::Truncated(continuations) => write!(
+                f,
+                "Error: the answer was still cut off at the token limit after {continuations} continuations."
+            ),
+            GptError::ContentFiltered(reason) => {
+                write!(f, "Error: blocked by the content filter: {reason}")
+            }
+            GptError::Cancelled => write!(f, "Request cancelled."),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(status: u16, body: &str) -> GptError {
+        GptError::from_response(StatusCode::from_u16(status).unwrap(), body.to_string())
+    }
+
+    #[test]
+    fn test_parse_api_error() {
+        let error = ApiError::parse(
+            r#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error","param":null,"code":"invalid_api_key"}}"#,
+        )
+        .unwrap();
+        assert_eq!(error.message, "Incorrect API key provided");
+        assert_eq!(error.kind.as_deref(), Some("invalid_request_error"));
+        assert_eq!(error.code.as_deref(), Some("invalid_api_key"));
+        assert_eq!(error.param, None);
+    }
+
+    #[test]
+    fn test_classify_responses() {
+        assert!(matches!(
+            classify(401, r#"{"error":{"message":"bad key"}}"#),
+            GptError::AuthRejected(error) if error.message == "bad key"
+        ));
+        assert!(matches!(
+            classify(
+                429,
+                r#"{"error":{"message":"slow down","code":"rate_limit_exceeded"}}"#
+            ),
+            GptError::RateLimited(_)
+        ));
+        assert!(matches!(
+            classify(
+                429,
+                r#"{"error":{"message":"no money","type":"insufficient_quota"}}"#
+            ),
+            GptError::QuotaExhausted(_)
+        ));
+        assert!(matches!(
+            classify(
+                404,
+                r#"{"error":{"message":"no gpt-5","code":"model_not_found"}}"#
+            ),
+            GptError::ModelNotFound(_)
+        ));
+        assert!(matches!(
+            classify(
+                400,
+                r#"{"error":{"message":"too long","code":"context_length_exceeded"}}"#
+            ),
+            GptError::ContextTooLong(_)
+        ));
+        assert!(matches!(
+            classify(404, "not found"),
+            GptError::Provider { error: None, .. }
+        ));
+    }
+
+    #[test]
+    fn test_classify_azure_and_gemini_responses() {
+        assert!(matches!(
+            classify(
+                404,
+                r#"{"error":{"code":"DeploymentNotFound","message":"The API deployment for this resource does not exist."}}"#
+            ),
+            GptError::ModelNotFound(_)
+        ));
+        assert!(matches!(
+            classify(
+                400,
+                r#"{"error":{"message":"The response was filtered due to the prompt triggering Azure OpenAI's content management policy.","type":null,"param":"prompt","code":"content_filter","status":400,"innererror":{"code":"ResponsibleAIPolicyViolation","content_filter_result":{"violence":{"filtered":true,"severity":"medium"}}}}}"#
+            ),
+            GptError::ContentFiltered(message) if message.starts_with("The response was filtered")
+        ));
+        assert!(matches!(
+            classify(
+                401,
+                r#"{"statusCode":401,"message":"Access denied due to invalid subscription key."}"#
+            ),
+            GptError::AuthRejected(error) if error.code.as_deref() == Some("401")
+        ));
+        assert!(matches!(
+            classify(
+                404,
+                r#"{"error":{"code":404,"message":"models/gemini-9 is not found for API version v1beta","status":"NOT_FOUND"}}"#
+            ),
+            GptError::ModelNotFound(_)
+        ));
+        assert!(matches!(
+            classify(
+                400,
+                r#"{"error":{"code":400,"message":"API key not valid. Please pass a valid API key.","status":"INVALID_ARGUMENT","details":[{"@type":"type.googleapis.com/google.rpc.ErrorInfo","reason":"API_KEY_INVALID","domain":"googleapis.com"}]}}"#
+            ),
+            GptError::AuthRejected(error) if error.message.starts_with("API key not valid")
+        ));
+        assert!(matches!(
+            classify(429, r#"{"error":{"code":429,"message":"Requests have exceeded the call rate limit."}}"#),
+            GptError::RateLimited(error) if error.code.as_deref() == Some("429")
+        ));
+    }
+
+    #[test]
+    fn test_about_model() {
+        let about =
+            |status: u16, body: &str, model: &str| classify(status, body).about_model(model);
+
+        assert!(matches!(
+            about(
+                400,
+                r#"{"error":{"message":"openai/gpt-4x is not a valid model ID","code":400}}"#,
+                "openai/gpt-4x"
+            ),
+            GptError::ModelNotFound(error) if error.message == "openai/gpt-4x is not a valid model ID"
+        ));
+        assert!(matches!(
+            about(
+                404,
+                r#"{"error":"model \"llama9\" not found, try pulling it first"}"#,
+                "llama9"
+            ),
+            GptError::ModelNotFound(_)
+        ));
+        assert!(matches!(
+            about(
+                404,
+                r#"{"object":"error","message":"The model `qwen-9` does not exist.","type":"NotFoundError","param":null,"code":404}"#,
+                "qwen-9"
+            ),
+            GptError::ModelNotFound(_)
+        ));
+        assert!(matches!(
+            about(
+                400,
+                r#"{"error":{"message":"gpt-4o does not support tools","code":400}}"#,
+                "gpt-4o"
+            ),
+            GptError::Provider { .. }
+        ));
+        assert!(matches!(
+            about(404, "not found", "gpt-4o"),
+            GptError::Provider { error: None, .. }
+        ));
+    }
+
+    #[test]
+    fn test_is_outage() {
+        assert!(classify(429, r#"{"error":{"message":"slow down"}}"#).is_outage());
+        assert!(classify(503, "overloaded").is_outage());
+        assert!(GptError::Timeout(String::new()).is_outage());
+        assert!(!classify(401, r#"{"error":{"message":"bad key"}}"#).is_outage());
+        assert!(!classify(400, r#"{"error":{"message":"bad request"}}"#).is_outage());
+    }
+
+    #[test]
+    fn test_provider_error_message() {
+        assert_eq!(
+            classify(500, "unexpected").to_string(),
+            "Request failed with status code: 500 Internal Server Error\nError response body: unexpected"
+        );
+    }
+
+    #[test]
+    fn test_provider_error_message_from_api_error() {
+        assert_eq!(
+            classify(
+                500,
+                r#"{"error":{"message":"The server had an error","type":"server_error"}}"#
+            )
+            .to_string(),
+            "Error: provider error (500 Internal Server Error): The server had an error"
+        );
+    }
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let errors = [
+            GptError::MissingCredentials(String::new()),
+            GptError::AuthRejected(ApiError::default()),
+            GptError::RateLimited(ApiError::default()),
+            GptError::QuotaExhausted(ApiError::default()),
+            GptError::ModelNotFound(ApiError::default()),
+            GptError::ContextTooLong(ApiError::default()),
+            GptError::Network(String::new()),
+            GptError::Timeout(String::new()),
+            GptError::MalformedResponse(String::new()),
+            classify(500, ""),
+            GptError::NoCachedAnswer(String::new()),
+            GptError::Cache(String::new()),
+            GptError::Config(String::new()),
+            GptError::BudgetExceeded(String::new()),
+            GptError::Truncated(0),
+            GptError::ContentFiltered(String::new()),
+            GptError::KeyUnavailable(String::new()),
+            GptError::Usage(String::new()),
+            GptError::Cancelled,
+        ];
+        let mut codes: Vec<i32> = errors.iter().map(GptError::exit_code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0) && !codes.contains(&1));
+    }
+}
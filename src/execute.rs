@@ -0,0 +1,166 @@
+use crate::api_key;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+/// Bytes of stderr kept to explain a failure to the model.
+const MAX_STDERR_TAIL: usize = 4000;
+
+/// Exit code shells use for a command that could not be started.
+const NOT_STARTED: i32 = 127;
+
+/// How an executed command ended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Execution {
+    pub command: String,
+    /// `None` when the command was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub stderr_tail: String,
+}
+
+impl Execution {
+    /// Exited with a non-zero code. Commands killed by a signal, eg. by Ctrl+C, are not failures.
+    pub fn failed(&self) -> bool {
+        matches!(self.exit_code, Some(code) if code != 0)
+    }
+
+    /// Message asking the model to correct the command.
+    pub fn fix_request(&self) -> String {
+        let mut request = format!(
+            "The command `{}` failed with exit status {}.",
+            self.command,
+            self.exit_code.unwrap_or_default()
+        );
+        if !self.stderr_tail.trim().is_empty() {
+            request.push_str(&format!("\nstderr:\n{}", self.stderr_tail.trim_end()));
+        }
+        request.push_str("\nRespond with a corrected command doing the original task.");
+        request
+    }
+}
+
+/// Runs the program with output streamed to the terminal. Stderr is piped to keep its tail only
+/// with `capture_stderr`, as a program writing to a pipe loses its colors, progress bars and
+/// prompts that check for a terminal.
+pub fn run(command: &str, program: &str, args: &[String], capture_stderr: bool) -> Execution {
+    let stderr = if capture_stderr {
+        Stdio::piped()
+    } else {
+        Stdio::inherit()
+    };
+    let spawned = api_key::scrub(Command::new(program).args(args))
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(stderr)
+        .spawn();
+
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(error) => {
+            let message = format!("Failed to execute {program}: {error}");
+            eprintln!("{message}");
+            return Execution {
+                command: command.to_string(),
+                exit_code: Some(NOT_STARTED),
+                stderr_tail: message,
+            };
+        }
+    };
+
+    let tail = child.stderr.take().map_or_else(Vec::new, |stderr| {
+        stream_tail(stderr, std::io::stderr(), MAX_STDERR_TAIL)
+    });
+    let status = child.wait().expect("Failed to wait for command");
+
+    Execution {
+        command: command.to_string(),
+        exit_code: status.code(),
+        stderr_tail: String::from_utf8_lossy(&tail).to_string(),
+    }
+}
+
+/// Copies `reader` to `writer` as it comes, returning its last `limit` bytes.
+pub fn stream_tail(mut reader: impl Read, mut writer: impl Write, limit: usize) -> Vec<u8> {
+    let mut tail = vec![];
+    let mut buffer = [0; 4096];
+    while let Ok(read) = reader.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+        let _ = writer.write_all(&buffer[..read]);
+        let _ = writer.flush();
+        tail.extend_from_slice(&buffer[..read]);
+        if tail.len() > limit {
+            tail.drain(..tail.len() - limit);
+        }
+    }
+    tail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bash(script: &str) -> Execution {
+        run(
+            script,
+            "bash",
+            &[String::from("-c"), script.to_string()],
+            true,
+        )
+    }
+
+    #[test]
+    fn test_run_success() {
+        let execution = bash("true");
+        assert_eq!(execution.exit_code, Some(0));
+        assert!(!execution.failed());
+    }
+
+    #[test]
+    fn test_run_failure_keeps_stderr() {
+        let execution = bash("echo 'no such file' >&2; exit 3");
+        assert_eq!(execution.exit_code, Some(3));
+        assert_eq!(execution.stderr_tail, "no such file\n");
+        assert!(execution.failed());
+    }
+
+    #[test]
+    fn test_run_keeps_only_tail_of_stderr() {
+        let execution = bash("head -c 10000 /dev/zero | tr '\\0' a >&2; echo end >&2; exit 1");
+        assert_eq!(execution.stderr_tail.len(), MAX_STDERR_TAIL);
+        assert!(execution.stderr_tail.ends_with("aend\n"));
+    }
+
+    #[test]
+    fn test_run_without_capture() {
+        let script = "echo 'no such file' >&2; exit 3";
+        let execution = run(
+            script,
+            "bash",
+            &[String::from("-c"), script.to_string()],
+            false,
+        );
+        assert_eq!(execution.exit_code, Some(3));
+        assert_eq!(execution.stderr_tail, "");
+    }
+
+    #[test]
+    fn test_run_missing_program() {
+        let execution = run("no-such-program-gpt", "no-such-program-gpt", &[], false);
+        assert_eq!(execution.exit_code, Some(NOT_STARTED));
+        assert!(execution.stderr_tail.starts_with("Failed to execute"));
+    }
+
+    #[test]
+    fn test_fix_request() {
+        let execution = Execution {
+            command: String::from("ls /nope"),
+            exit_code: Some(2),
+            stderr_tail: String::from("ls: cannot access '/nope': No such file or directory\n"),
+        };
+        assert_eq!(
+            execution.fix_request(),
+            "The command `ls /nope` failed with exit status 2.\nstderr:\nls: cannot access '/nope': No such file or directory\nRespond with a corrected command doing the original task."
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use crate::execute::{self, Execution};
+use inquire::Confirm;
+
+const BASH_HOOK: &str = r#"__gpt_record_last_command() {
+    local exit_status=$?
+    local command
+    command=$(HISTTIMEFORMAT= builtin history 1 | sed 's/^ *[0-9]*[* ] *//')
+    printf '%s bash\n%s\n' "$exit_status" "$command" > "${GPT_LAST_COMMAND_PATH:-$HOME/.gpt-last-command}"
+    return $exit_status
+}
+PROMPT_COMMAND="__gpt_record_last_command${PROMPT_COMMAND:+;$PROMPT_COMMAND}""#;
+
+const ZSH_HOOK: &str = r#"__gpt_preexec() {
+    __gpt_command=$1
+}
+__gpt_precmd() {
+    local exit_status=$?
+    if [[ -n $__gpt_command ]]; then
+        printf '%s zsh\n%s\n' "$exit_status" "$__gpt_command" > "${GPT_LAST_COMMAND_PATH:-$HOME/.gpt-last-command}"
+    fi
+    unset __gpt_command
+}
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec __gpt_preexec
+add-zsh-hook precmd __gpt_precmd"#;
+
+/// Script for `eval "$(p init bash)"` recording the last command and its exit status.
+pub fn hook(shell: &str) -> Option<&'static str> {
+    match shell {
+        "bash" => Some(BASH_HOOK),
+        "zsh" => Some(ZSH_HOOK),
+        _ => None,
+    }
+}
+
+/// Command typed in the shell before `p fix`.
+#[derive(Debug, PartialEq)]
+pub struct LastCommand {
+    pub command: String,
+    /// Known only when recorded by the shell hook.
+    pub exit_code: Option<i32>,
+    /// Shell the hook recorded the command from.
+    pub shell: Option<String>,
+}
+
+impl LastCommand {
+    /// Recorded by the shell hook, or the last entry of the shell history.
+    pub fn load() -> Option<Self> {
+        std::fs::read_to_string(recorded_path())
+            .ok()
+            .and_then(|recorded| LastCommand::parse_recorded(&recorded))
+            .or_else(|| {
+                std::fs::read(history_path()).ok().and_then(|history| {
+                    LastCommand::parse_history(&String::from_utf8_lossy(&history))
+                })
+            })
+    }
+
+    /// Exit status and shell in the first line, the command in the rest. `p fix` itself, recorded
+    /// when it's run twice in a row, is left to the history.
+    fn parse_recorded(recorded: &str) -> Option<Self> {
+        let (header, command) = recorded.split_once('\n')?;
+        let command = command.trim_end_matches('\n');
+        let mut header = header.split_whitespace();
+        (!command.trim().is_empty() && !is_fix(command)).then(|| LastCommand {
+            command: command.to_string(),
+            exit_code: header.next().and_then(|status| status.parse().ok()),
+            shell: header.next().map(String::from),
+        })
+    }
+
+    /// Recorded shell, or the user's shell, to run the command again.
+    fn shell(&self) -> String {
+        self.shell
+            .clone()
+            .or_else(|| std::env::var("SHELL").ok())
+            .unwrap_or_else(|| String::from("bash"))
+    }
+
+    /// Last command of a bash or zsh history, skipping timestamps and `p fix` itself.
+    fn parse_history(history: &str) -> Option<Self> {
+        history
+            .lines()
+            .rev()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| match line.strip_prefix(": ") {
+                // zsh extended history, `: <timestamp>:<duration>;<command>`
+                Some(extended) => extended
+                    .split_once(';')
+                    .map_or(line, |(_, command)| command),
+                None => line,
+            })
+            .map(str::trim)
+            .find(|command| !command.is_empty() && !is_fix(command))
+            .map(|command| LastCommand {
+                command: command.to_string(),
+                exit_code: None,
+                shell: None,
+            })
+    }
+}
+
+/// `p fix` or `gpt-cli fix`, also run by its path. Other `fix` subcommands, eg. `git fix`, are
+/// commands to fix.
+fn is_fix(command: &str) -> bool {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    match words.as_slice() {
+        [program, "fix"] => matches!(program.rsplit('/').next(), Some("p" | "gpt-cli")),
+        _ => false,
+    }
+}
+
+/// `GPT_LAST_COMMAND_PATH` or `~/.gpt-last-command`, written by the shell hook.
+fn recorded_path() -> String {
+    std::env::var("GPT_LAST_COMMAND_PATH")
+        .unwrap_or_else(|_| format!("{}/.gpt-last-command", dirs::home_dir().unwrap().display()))
+}
+
+/// `HISTFILE`, or the default history of the user's shell.
+fn history_path() -> String {
+    std::env::var("HISTFILE").unwrap_or_else(|_| {
+        let file = match std::env::var("SHELL") {
+            Ok(shell) if shell.ends_with("zsh") => ".zsh_history",
+            _ => ".bash_history",
+        };
+        format!("{}/{file}", dirs::home_dir().unwrap().display())
+    })
+}
+
+/// Message asking the model to correct the command, with its error output when it was re-run.
+fn request(last: &LastCommand, execution: Option<&Execution>) -> String {
+    let exit_code = execution
+        .and_then(|execution| execution.exit_code)
+        .or(last.exit_code);
+    let mut request = match exit_code {
+        Some(code) if code != 0 => format!(
+            "I ran `{}` in my shell and it failed with exit status {code}.",
+            last.command
+        ),
+        _ => format!("I ran `{}` in my shell and it didn't work.", last.command),
+    };
+    if let Some(execution) = execution
+        && !execution.stderr_tail.trim().is_empty()
+    {
+        request.push_str(&format!("\nstderr:\n{}", execution.stderr_tail.trim_end()));
+    }
+    request.push_str("\nRespond with a corrected command doing what I meant.");
+    request
+}
+
+/// Asks whether `command` may run again with its stderr captured, so it can be sent to the model.
+pub fn confirm_rerun(command: &str) -> bool {
+    Confirm::new(&format!(
+        "Run `{command}` again to capture its error output?"
+    ))
+    .with_default(false)
+    .with_help_message("Without it, only the command and its exit status are sent")
+    .prompt()
+    .unwrap_or(false)
+}
+
+/// Task description of `p fix`, re-running the last command when the user agrees.
+pub fn prepare() -> Result<String, String> {
+    let last = LastCommand::load().ok_or_else(|| {
+        String::from("Can't find the last command, add `eval \"$(p init bash)\"` or `eval \"$(p init zsh)\"` to your shell config.")
+    })?;
+
+    let execution = confirm_rerun(&last.command).then(|| {
+        execute::run(
+            &last.command,
+            &last.shell(),
+            &[String::from("-c"), last.command.clone()],
+            true,
+        )
+    });
+    Ok(request(&last, execution.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_recorded() {
+        assert_eq!(
+            LastCommand::parse_recorded("127 zsh\ngti status\n"),
+            Some(LastCommand {
+                command: String::from("gti status"),
+                exit_code: Some(127),
+                shell: Some(String::from("zsh")),
+            })
+        );
+        assert_eq!(LastCommand::parse_recorded("0 bash\n\n"), None);
+        assert_eq!(LastCommand::parse_recorded("0 bash\np fix\n"), None);
+    }
+
+    #[test]
+    fn test_parse_recorded_without_shell() {
+        let last = LastCommand::parse_recorded("1\ngti status\n").unwrap();
+        assert_eq!(last.exit_code, Some(1));
+        assert_eq!(last.shell, None);
+    }
+
+    #[test]
+    fn test_parse_bash_history() {
+        let history = "ls\n#1700000000\ngit pus origin\n#1700000001\np fix\n";
+        assert_eq!(
+            LastCommand::parse_history(history),
+            Some(LastCommand {
+                command: String::from("git pus origin"),
+                exit_code: None,
+                shell: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_history_keeps_other_fix_commands() {
+        let history = "ls\ngit fix\n/usr/local/bin/gpt-cli fix\n";
+        assert_eq!(
+            LastCommand::parse_history(history).unwrap().command,
+            "git fix"
+        );
+    }
+
+    #[test]
+    fn test_parse_zsh_extended_history() {
+        let history = ": 1700000000:0;ls\n: 1700000005:2;tar xf archive.tar.gz -d out\n";
+        assert_eq!(
+            LastCommand::parse_history(history).unwrap().command,
+            "tar xf archive.tar.gz -d out"
+        );
+        assert_eq!(LastCommand::parse_history(""), None);
+    }
+
+    #[test]
+    fn test_hook() {
+        assert!(hook("bash").unwrap().contains("PROMPT_COMMAND"));
+        assert!(hook("zsh").unwrap().contains("add-zsh-hook precmd"));
+        assert_eq!(hook("fish"), None);
+    }
+
+    #[test]
+    fn test_request() {
+        let last = LastCommand {
+            command: String::from("gti status"),
+            exit_code: Some(127),
+            shell: None,
+        };
+        assert_eq!(
+            request(&last, None),
+            "I ran `gti status` in my shell and it failed with exit status 127.\nRespond with a corrected command doing what I meant."
+        );
+
+        let execution = Execution {
+            command: last.command.clone(),
+            exit_code: Some(127),
+            stderr_tail: String::from("bash: gti: command not found\n"),
+        };
+        assert_eq!(
+            request(&last, Some(&execution)),
+            "I ran `gti status` in my shell and it failed with exit status 127.\nstderr:\nbash: gti: command not found\nRespond with a corrected command doing what I meant."
+        );
+    }
+}
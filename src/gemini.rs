@@ -0,0 +1,358 @@
+use crate::error::GptError;
+use crate::gpt3::Gpt3Response;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Sampling parameters of a chat completion and their names in `generationConfig`.
+const GENERATION_CONFIG: [(&str, &str); 7] = [
+    ("temperature", "temperature"),
+    ("top_p", "topP"),
+    ("max_tokens", "maxOutputTokens"),
+    ("seed", "seed"),
+    ("stop", "stopSequences"),
+    ("presence_penalty", "presencePenalty"),
+    ("frequency_penalty", "frequencyPenalty"),
+];
+
+/// `generateContent` request equivalent to the chat completion request `data`.
+pub fn request(data: &Value) -> Value {
+    let messages = data["messages"].as_array().map_or(&[][..], Vec::as_slice);
+    let system: Vec<&str> = messages
+        .iter()
+        .filter(|message| message["role"] == "system")
+        .filter_map(|message| message["content"].as_str())
+        .collect();
+    let contents: Vec<Value> = messages
+        .iter()
+        .filter(|message| message["role"] != "system")
+        .map(|message| {
+            json!({
+                "role": if message["role"] == "assistant" { "model" } else { "user" },
+                "parts": [{ "text": message["content"].as_str().unwrap_or_default() }]
+            })
+        })
+        .collect();
+
+    let mut config = Map::new();
+    for (chat_name, gemini_name) in GENERATION_CONFIG {
+        if !data[chat_name].is_null() {
+            config.insert(gemini_name.to_string(), data[chat_name].clone());
+        }
+    }
+    let schema = &data["response_format"]["json_schema"]["schema"];
+    if !schema.is_null() {
+        config.insert(String::from("responseMimeType"), json!("application/json"));
+        config.insert(String::from("responseSchema"), response_schema(schema));
+    }
+
+    let mut request = json!({ "contents": contents });
+    if !system.is_empty() {
+        request["systemInstruction"] = json!({ "parts": [{ "text": system.join("\n\n") }] });
+    }
+    if !config.is_empty() {
+        request["generationConfig"] = Value::Object(config);
+    }
+    request
+}
+
+/// Gemini accepts a subset of JSON schema, without `additionalProperties`.
+fn response_schema(schema: &Value) -> Value {
+    match schema {
+        Value::Object(object) => Value::Object(
+            object
+                .iter()
+                .filter(|(key, _)| *key != "additionalProperties")
+                .map(|(key, value)| (key.clone(), response_schema(value)))
+                .collect(),
+        ),
+        _ => schema.clone(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateContentResponse {
+    #[serde(default)]
+    candidates: Vec<Candidate>,
+    prompt_feedback: Option<PromptFeedback>,
+    #[serde(default)]
+    usage_metadata: UsageMetadata,
+    #[serde(default)]
+    model_version: String,
+    #[serde(default)]
+    response_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Candidate {
+    content: Option<Content>,
+    finish_reason: Option<String>,
+    #[serde(default)]
+    index: i32,
+    #[serde(default)]
+    safety_ratings: Vec<SafetyRating>,
+}
+
+#[derive(Deserialize)]
+struct Content {
+    #[serde(default)]
+    parts: Vec<Part>,
+}
+
+#[derive(Deserialize)]
+struct Part {
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PromptFeedback {
+    block_reason: Option<String>,
+    #[serde(default)]
+    safety_ratings: Vec<SafetyRating>,
+}
+
+#[derive(Deserialize)]
+struct SafetyRating {
+    category: String,
+    #[serde(default)]
+    probability: String,
+    #[serde(default)]
+    blocked: bool,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageMetadata {
+    #[serde(default)]
+    prompt_token_count: i32,
+    #[serde(default)]
+    candidates_token_count: i32,
+    #[serde(default)]
+    total_token_count: i32,
+}
+
+/// `finish_reason` of a chat completion for a Gemini `finishReason`.
+fn finish_reason(reason: &str) -> String {
+    match reason {
+        "STOP" => String::from("stop"),
+        "MAX_TOKENS" => String::from("length"),
+        "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" | "IMAGE_SAFETY" => {
+            String::from("content_filter")
+        }
+        other => other.to_lowercase(),
+    }
+}
+
+/// Why the content was blocked, with the harm categories rated as likely.
+fn blocked_because(reason: &str, ratings: &[SafetyRating]) -> String {
+    let categories: Vec<&str> = ratings
+        .iter()
+        .filter(|rating| rating.blocked || matches!(rating.probability.as_str(), "MEDIUM" | "HIGH"))
+        .map(|rating| rating.category.as_str())
+        .collect();
+    match categories.is_empty() {
+        true => reason.to_string(),
+        false => format!("{reason}, {}", categories.join(", ")),
+    }
+}
+
+/// Chat completion equivalent to the `generateContent` response `body`.
+pub fn response(body: &str) -> Result<Gpt3Response, GptError> {
+    let response: GenerateContentResponse =
+        serde_json::from_str(body).map_err(|e| GptError::MalformedResponse(e.to_string()))?;
+
+    if response.candidates.is_empty() {
+        return Err(match response.prompt_feedback {
+            Some(PromptFeedback {
+                block_reason: Some(reason),
+                safety_ratings,
+            }) => GptError::ContentFiltered(format!(
+                "prompt blocked ({})",
+                blocked_because(&reason, &safety_ratings)
+            )),
+            _ => GptError::MalformedResponse(String::from("response contains no candidates")),
+        });
+    }
+    let is_blocked = |candidate: &Candidate| {
+        candidate
+            .finish_reason
+            .as_deref()
+            .map(finish_reason)
+            .as_deref()
+            == Some("content_filter")
+    };
+    if response.candidates.iter().all(is_blocked) {
+        let candidate = &response.candidates[0];
+        let reason = candidate.finish_reason.as_deref().unwrap_or_default();
+        return Err(GptError::ContentFiltered(format!(
+            "answer blocked ({})",
+            blocked_because(reason, &candidate.safety_ratings)
+        )));
+    }
+
+    let choices: Vec<Value> = response
+        .candidates
+        .iter()
+        .map(|candidate| {
+            let text: String = candidate
+                .content
+                .iter()
+                .flat_map(|content| &content.parts)
+                .filter_map(|part| part.text.as_deref())
+                .collect();
+            json!({
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": candidate.finish_reason.as_deref().map(finish_reason),
+                "index": candidate.index
+            })
+        })
+        .collect();
+    let usage = &response.usage_metadata;
+
+    serde_json::from_value(json!({
+        "id": response.response_id,
+        "object": "chat.completion",
+        "created": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        "model": response.model_version,
+        "usage": {
+            "prompt_tokens": usage.prompt_token_count,
+            "completion_tokens": usage.candidates_token_count,
+            "total_tokens": usage.total_token_count
+        },
+        "choices": choices
+    }))
+    .map_err(|e| GptError::MalformedResponse(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let data = json!({
+            "model": "gemini-2.0-flash",
+            "messages": [
+                { "role": "system", "content": "You generate commands." },
+                { "role": "user", "content": "show calendar" },
+                { "role": "assistant", "content": "cal" },
+                { "role": "user", "content": "for 2024" }
+            ],
+            "temperature": 0.0,
+            "max_tokens": 100,
+            "stop": ["END"]
+        });
+        assert_eq!(
+            request(&data),
+            json!({
+                "systemInstruction": { "parts": [{ "text": "You generate commands." }] },
+                "contents": [
+                    { "role": "user", "parts": [{ "text": "show calendar" }] },
+                    { "role": "model", "parts": [{ "text": "cal" }] },
+                    { "role": "user", "parts": [{ "text": "for 2024" }] }
+                ],
+                "generationConfig": {
+                    "temperature": 0.0,
+                    "maxOutputTokens": 100,
+                    "stopSequences": ["END"]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_request_with_schema() {
+        let data = json!({
+            "messages": [{ "role": "user", "content": "show calendar" }],
+            "response_format": {
+                "type": "json_schema",
+                "json_schema": {
+                    "schema": {
+                        "type": "object",
+                        "properties": { "command": { "type": "string" } },
+                        "additionalProperties": false
+                    }
+                }
+            }
+        });
+        let config = &request(&data)["generationConfig"];
+        assert_eq!(config["responseMimeType"], "application/json");
+        assert_eq!(
+            config["responseSchema"],
+            json!({ "type": "object", "properties": { "command": { "type": "string" } } })
+        );
+    }
+
+    #[test]
+    fn test_response() {
+        let response = response(
+            r#"{
+                "candidates": [{
+                    "content": { "parts": [{ "text": "cal " }, { "text": "2024" }], "role": "model" },
+                    "finishReason": "STOP",
+                    "index": 0,
+                    "safetyRatings": [{ "category": "HARM_CATEGORY_HATE_SPEECH", "probability": "NEGLIGIBLE" }]
+                }],
+                "usageMetadata": { "promptTokenCount": 10, "candidatesTokenCount": 2, "totalTokenCount": 12 },
+                "modelVersion": "gemini-2.0-flash",
+                "responseId": "abc"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(response.choices[0].message.content, "cal 2024");
+        assert_eq!(response.model, "gemini-2.0-flash");
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["choices"][0]["finish_reason"], "stop");
+        assert_eq!(json["usage"]["total_tokens"], 12);
+    }
+
+    #[test]
+    fn test_finish_reason() {
+        assert_eq!(finish_reason("MAX_TOKENS"), "length");
+        assert_eq!(finish_reason("RECITATION"), "content_filter");
+        assert_eq!(
+            finish_reason("MALFORMED_FUNCTION_CALL"),
+            "malformed_function_call"
+        );
+    }
+
+    #[test]
+    fn test_blocked_prompt() {
+        let error = response(
+            r#"{
+                "promptFeedback": {
+                    "blockReason": "SAFETY",
+                    "safetyRatings": [
+                        { "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "probability": "HIGH", "blocked": true },
+                        { "category": "HARM_CATEGORY_HARASSMENT", "probability": "NEGLIGIBLE" }
+                    ]
+                }
+            }"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Error: blocked by the content filter: prompt blocked (SAFETY, HARM_CATEGORY_DANGEROUS_CONTENT)"
+        );
+    }
+
+    #[test]
+    fn test_blocked_answer() {
+        let error = response(
+            r#"{
+                "candidates": [{
+                    "finishReason": "SAFETY",
+                    "index": 0,
+                    "safetyRatings": [{ "category": "HARM_CATEGORY_DANGEROUS_CONTENT", "probability": "MEDIUM" }]
+                }]
+            }"#,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error, GptError::ContentFiltered(reason) if reason == "answer blocked (SAFETY, HARM_CATEGORY_DANGEROUS_CONTENT)")
+        );
+    }
+}
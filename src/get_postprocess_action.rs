@@ -1,3 +1,4 @@
+use crate::command_response::CommandResponse;
 use std::env;
 
 #[derive(Debug, PartialEq)]
@@ -8,8 +9,8 @@ pub enum PostprocessAction {
     Out,
 }
 
-pub fn get_postprocess_action(answer_text: &str) -> PostprocessAction {
-    let action_by_env = match env::var("GPT_POST") {
+fn get_action_by_env() -> PostprocessAction {
+    match env::var("GPT_POST") {
         Ok(val) => match val.as_str() {
             "confirm" => PostprocessAction::Confirm,
             "copy" => PostprocessAction::Copy,
@@ -17,7 +18,11 @@ pub fn get_postprocess_action(answer_text: &str) -> PostprocessAction {
             _ => PostprocessAction::Confirm,
         },
         Err(_) => PostprocessAction::Confirm,
-    };
+    }
+}
+
+pub fn get_postprocess_action(answer_text: &str) -> PostprocessAction {
+    let action_by_env = get_action_by_env();
 
     if (answer_text.contains('$') || answer_text.starts_with("export"))
         && action_by_env == PostprocessAction::Confirm
@@ -28,9 +33,26 @@ pub fn get_postprocess_action(answer_text: &str) -> PostprocessAction {
     action_by_env
 }
 
+/// Commands changing the user's shell are copied, as executing them in a child has no effect.
+pub fn get_command_postprocess_action(command: &CommandResponse) -> PostprocessAction {
+    command_action(command, get_action_by_env())
+}
+
+fn command_action(
+    command: &CommandResponse,
+    action_by_env: PostprocessAction,
+) -> PostprocessAction {
+    if command.changes_current_shell() && action_by_env == PostprocessAction::Confirm {
+        return PostprocessAction::Copy;
+    }
+
+    action_by_env
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::command_response::CommandResponse;
     use std::env;
 
     #[test]
@@ -93,6 +115,26 @@ mod tests {
         assert_eq!(action, PostprocessAction::Out);
     }
 
+    #[test]
+    fn test_command_action() {
+        let echo = CommandResponse::from_answer(
+            r#"{"command": "echo $HOME", "explanation": "", "requires_shell": true, "modifies_files": false, "needs_sudo": false, "risk": "low"}"#,
+        );
+        assert_eq!(
+            command_action(&echo, PostprocessAction::Confirm),
+            PostprocessAction::Confirm
+        );
+        let export = CommandResponse::from_answer("export EDITOR=vim");
+        assert_eq!(
+            command_action(&export, PostprocessAction::Confirm),
+            PostprocessAction::Copy
+        );
+        assert_eq!(
+            command_action(&export, PostprocessAction::Out),
+            PostprocessAction::Out
+        );
+    }
+
     #[test]
     fn test_get_postprocess_action_env_invalid() {
         unsafe {
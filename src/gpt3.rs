@@ -1,58 +1,498 @@
+use crate::api_key::ApiKey;
+use crate::budget::Budget;
 use crate::cache::Cache;
-use async_recursion::async_recursion;
-use serde::{Deserialize, Serialize};
+use crate::cassette::Cassette;
+use crate::command_response::{CommandResponse, StructuredOutput};
+use crate::error::GptError;
+use crate::http::{HttpConfig, interruptible};
+use crate::models::{self, ModelInfo, ModelsCache};
+use crate::profile;
+use crate::provider::{Provider, fallbacks_from_env, parse_models};
+use crate::retry::RetryPolicy;
+use crate::sampling::Sampling;
+use crate::spinner::{Spinner, clear_line};
+use crate::tools::{self, ToolCall};
+use crate::trace::Trace;
+use crate::usage;
+use colored::Colorize;
+use futures::future::join_all;
+use reqwest::Method;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::json;
-use std::thread;
-use std::time::Duration;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+#[derive(Clone)]
 pub(crate) struct Gpt {
     debug: bool,
-    api_key: String,
-    openapi_host: String,
+    offline: bool,
+    api_key: ApiKey,
+    provider: Provider,
+    model: String,
+    /// Provider and model pairs tried in order when the model fails with an outage.
+    fallbacks: Vec<(Provider, String)>,
+    /// Asks a model other than the configured one, so its answers are cached under the model.
+    keyed_by_model: bool,
+    /// Show a spinner while waiting, off when several models are asked at once.
+    spinner: bool,
+    retry_policy: RetryPolicy,
+    http: HttpConfig,
+    /// Records responses to `GPT_CASSETTE`, or answers from it.
+    cassette: Option<Cassette>,
+    /// Logs requests and responses for `-v` and `-vv`.
+    trace: Trace,
+    /// Overrides the cache configured by the environment.
+    cache_path: Option<String>,
+    /// Overrides where listings of models are cached.
+    models_path: Option<String>,
+    /// Usage ledger updated after every answer that was not served from the cache.
+    ledger_path: Option<String>,
+    budget: Budget,
+    /// Send requests even past the budget.
+    force_budget: bool,
+    /// Number of answers to choose from.
+    candidates: u32,
+    /// Sampling set in the environment or on the command line.
+    sampling: Sampling,
+    /// Sampling of the current system prompt and number of candidates when nothing is configured.
+    default_sampling: Sampling,
+    structured: StructuredOutput,
+    /// Let the model call read-only local tools before answering.
+    tools: bool,
+    /// Read and write the answer cache, see [`Gpt::caches_answers`].
+    answer_cache: bool,
 }
 
 impl Gpt {
-    const OPEN_AI_HOST: &'static str = "https://api.openai.com";
+    /// Requests sent after an answer cut off at the token limit before giving up.
+    const MAX_CONTINUATIONS: u32 = 3;
+    /// Rounds of tool calls before the model is made to answer without them.
+    const MAX_TOOL_ITERATIONS: u32 = 5;
+    const CONTINUE_PROMPT: &'static str =
+        "Continue exactly where your answer was cut off, without repeating anything.";
 
     pub(crate) fn new(debug: Option<bool>, openapi_host: Option<&str>) -> Self {
-        let api_key = std::env::var("OPENAI_API_KEY")
-            .unwrap_or_else(|_| String::new());
+        let provider = Provider::from_env(openapi_host);
+        let api_key = provider.api_key();
+        let model =
+            std::env::var("GPT_MODEL").unwrap_or_else(|_| String::from(provider.default_model()));
 
-        let openapi_host = std::env::var("OPENAI_BASE_URL")
-            .ok()
-            .unwrap_or_else(|| String::from(openapi_host.unwrap_or(Gpt::OPEN_AI_HOST)));
+        let offline = matches!(
+            std::env::var("GPT_OFFLINE").as_deref(),
+            Ok("1") | Ok("true")
+        );
 
         Self {
             api_key,
             debug: debug.unwrap_or(false),
-            openapi_host,
+            offline,
+            fallbacks: fallbacks_from_env(&provider),
+            provider,
+            model,
+            keyed_by_model: false,
+            spinner: true,
+            retry_policy: RetryPolicy::from_env(),
+            http: HttpConfig::from_env(),
+            cassette: Cassette::from_env(),
+            trace: Trace::from_env(0),
+            cache_path: None,
+            models_path: None,
+            ledger_path: None,
+            budget: Budget::from_env(),
+            force_budget: false,
+            candidates: 1,
+            sampling: Sampling::from_env(),
+            default_sampling: Sampling::defaults(Gpt::generates_commands(), 1),
+            structured: StructuredOutput::from_env(),
+            tools: matches!(std::env::var("GPT_TOOLS").as_deref(), Ok("1") | Ok("true")),
+            answer_cache: true,
+        }
+    }
+
+    /// Answer only from the cache, never touching the network.
+    pub(crate) fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = self.offline || offline;
+        self
+    }
+
+    /// Records token usage of answers from the API in the ledger at `path`.
+    pub(crate) fn with_ledger(mut self, path: String) -> Self {
+        self.ledger_path = Some(path);
+        self
+    }
+
+    pub(crate) fn with_force_budget(mut self, force_budget: bool) -> Self {
+        self.force_budget = force_budget;
+        self
+    }
+
+    pub(crate) fn with_candidates(mut self, candidates: u32) -> Self {
+        self.candidates = candidates.max(1);
+        self.default_sampling = Sampling::defaults(Gpt::generates_commands(), self.candidates);
+        self
+    }
+
+    /// Overrides sampling parameters from the environment with the ones given on the command line.
+    pub(crate) fn with_sampling(mut self, overrides: &Sampling) -> Self {
+        self.sampling = self.sampling.merge(overrides);
+        self
+    }
+
+    /// Ask for a [`CommandResponse`] instead of free text.
+    pub(crate) fn with_structured(mut self, structured: bool) -> Self {
+        if structured && self.structured == StructuredOutput::Off {
+            self.structured = StructuredOutput::Auto;
+        }
+        self
+    }
+
+    /// Traces HTTP requests at `level`, 0 for none.
+    pub(crate) fn with_verbosity(mut self, level: u8) -> Self {
+        self.trace.level = level;
+        self
+    }
+
+    pub(crate) fn with_tools(mut self, tools: bool) -> Self {
+        self.tools = self.tools || tools;
+        self
+    }
+
+    /// Client of the agent, asking for one free text step at a time. Steps are never cached, as
+    /// each one depends on what the previous ones changed.
+    pub(crate) fn for_agent(self) -> Self {
+        Gpt {
+            structured: StructuredOutput::Off,
+            tools: false,
+            answer_cache: false,
+            ..self.with_candidates(1)
+        }
+    }
+
+    /// Clients of the comma separated `provider:model` pairs in `list`, asking without a spinner.
+    pub(crate) fn for_models(&self, list: &str) -> Vec<Gpt> {
+        parse_models(list, &self.provider)
+            .iter()
+            .map(|(provider, model)| Gpt {
+                spinner: false,
+                ..self.with_model(provider, model)
+            })
+            .collect()
+    }
+
+    pub(crate) fn is_structured(&self) -> bool {
+        self.structured != StructuredOutput::Off
+    }
+
+    /// Defaults with the configured values over them.
+    fn effective_sampling(&self) -> Sampling {
+        self.default_sampling.clone().merge(&self.sampling)
+    }
+
+    /// Messages alone for default sampling and free text answers of the configured OpenAI model, so
+    /// answers cached before these were configurable are still found. Answers of other providers
+    /// are kept apart by their provider and model.
+    fn cache_key(&self, messages: &[Gpt3Message]) -> String {
+        let sampling = self.effective_sampling();
+        let keyed_by_model =
+            self.keyed_by_model || !matches!(self.provider, Provider::OpenAi { .. });
+        if sampling == self.default_sampling && !self.is_structured() && !keyed_by_model {
+            serde_json::to_string(messages).unwrap()
+        } else {
+            let mut key = json!({
+                "messages": messages,
+                "sampling": sampling,
+                "structured": self.is_structured()
+            });
+            if keyed_by_model {
+                key["model"] = json!(self.label());
+            }
+            serde_json::to_string(&key).unwrap()
+        }
+    }
+
+    /// `provider:model`, as in `GPT_FALLBACK`.
+    pub(crate) fn label(&self) -> String {
+        format!("{}:{}", self.provider.name(), self.model)
+    }
+
+    /// Client asking `model` of `provider` instead of the configured model, without fallbacks.
+    fn with_model(&self, provider: &Provider, model: &str) -> Gpt {
+        let api_key = if provider.name() == self.provider.name() {
+            self.api_key.clone()
+        } else {
+            provider.api_key()
+        };
+        Gpt {
+            api_key,
+            provider: provider.clone(),
+            model: model.to_string(),
+            fallbacks: vec![],
+            keyed_by_model: *provider != self.provider || model != self.model,
+            ..self.clone()
+        }
+    }
+
+    /// Only answers of hosted providers are cached, see [`Provider::is_hosted`], and never steps of
+    /// the agent. A cassette bypasses the cache, so every request is recorded or replayed.
+    fn caches_answers(&self) -> bool {
+        self.answer_cache && self.cassette.is_none() && self.provider.is_hosted()
+    }
+
+    fn cached(&self, cache: &mut Cache, key: &str) -> Option<Gpt3Response> {
+        if !self.caches_answers() {
+            return None;
+        }
+        cache
+            .get(key)
+            .map(|cached_data| Gpt3Response::from_cache(&cached_data))
+    }
+
+    fn open_cache(&self) -> Result<Cache, GptError> {
+        match &self.cache_path {
+            Some(path) => Ok(Cache::new(Some(path))),
+            None => Cache::from_env().map_err(GptError::Cache),
         }
     }
 
     pub fn is_open_ai(&self) -> bool {
-        self.openapi_host == Gpt::OPEN_AI_HOST
+        self.provider.is_open_ai()
+    }
+
+    /// Client of a mock server that sends every request once, with a cache of its own.
+    #[cfg(test)]
+    pub(crate) fn mock(host: &str) -> Self {
+        let mut gpt = Gpt::isolated(false, Some(host));
+        gpt.api_key = ApiKey::from("test_key");
+        gpt.retry_policy = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
+        gpt
+    }
+
+    /// Client that never reads or writes the cache of the user.
+    #[cfg(test)]
+    pub(crate) fn isolated(debug: bool, openapi_host: Option<&str>) -> Self {
+        let mut gpt = Gpt::new(Some(debug), openapi_host);
+        gpt.cache_path = Some(format!(
+            "/tmp/.gpt-cache-test-{}.log",
+            rand::random::<u64>()
+        ));
+        gpt
+    }
+
+    /// Whether the default prompt is used, making answers commands to run.
+    fn generates_commands() -> bool {
+        profile::var("GPT_SYSTEM_PROMPT").is_err()
     }
 
     pub(crate) fn get_system_prompt() -> String {
-        match std::env::var("GPT_SYSTEM_PROMPT") {
+        match profile::var("GPT_SYSTEM_PROMPT") {
             Ok(val) => val,
-            Err(_) => String::from("You are a linux terminal command generator. I will describe a task and you will respond with linux command, do not include any description, explanation or any extrenous syntax.")
+            Err(_) => String::from(
+                "You are a linux terminal command generator. I will describe a task and you will respond with linux command, do not include any description, explanation or any extrenous syntax.",
+            ),
+        }
+    }
+
+    /// Reads the key, running its command the first time, so that `send` can use it.
+    fn check_api_key(&self) -> Result<(), GptError> {
+        self.api_key.value().map(|_| ())
+    }
+
+    /// Checks the provider is configured and reads the key, unless answers are replayed from the cassette.
+    fn check_credentials(&self) -> Result<(), GptError> {
+        self.provider.validate()?;
+        match &self.cassette {
+            Some(cassette) => {
+                cassette.load()?;
+                if !cassette.is_replaying() {
+                    self.check_api_key()?;
+                }
+                Ok(())
+            }
+            None => self.check_api_key(),
+        }
+    }
+
+    /// Sends `data`, retrying rate limits, server errors and connection failures with the retry policy.
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        data: &serde_json::Value,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let mut attempt = 1;
+        let api_key = self.api_key.value().unwrap_or_default();
+        let body = self.provider.request_body(data);
+
+        loop {
+            let result = self
+                .exchange(client, Method::POST, url, Some(&body), &api_key)
+                .await;
+
+            if attempt >= self.retry_policy.max_attempts {
+                return result;
+            }
+            let (reason, delay) = match &result {
+                Ok(response) if RetryPolicy::is_retriable(response.status()) => (
+                    response.status().to_string(),
+                    self.retry_policy.delay(attempt, response.headers()),
+                ),
+                Err(e) if e.is_connect() || e.is_timeout() => {
+                    (e.to_string(), self.retry_policy.backoff(attempt))
+                }
+                _ => return result,
+            };
+
+            clear_line();
+            eprintln!(
+                "{}",
+                format!(
+                    "Request failed ({reason}), retrying in {:.1}s (attempt {}/{})",
+                    delay.as_secs_f64(),
+                    attempt + 1,
+                    self.retry_policy.max_attempts
+                )
+                .yellow()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Sends the request once, or replays it from the cassette, tracing both ends.
+    async fn exchange(
+        &self,
+        client: &reqwest::Client,
+        method: Method,
+        url: &str,
+        body: Option<&serde_json::Value>,
+        api_key: &str,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let (auth_name, auth_value) = self.provider.auth_header(api_key);
+        let mut request = client
+            .request(method.clone(), url)
+            .header(auth_name, auth_value);
+        if let Some(body) = body {
+            request = request
+                .header("Content-Type", "application/json")
+                .json(body);
+        }
+        let request = request.build()?;
+        self.trace.request(&request, &[api_key]);
+
+        let started = Instant::now();
+        let body = body.unwrap_or(&serde_json::Value::Null);
+        let result = match &self.cassette {
+            Some(cassette) if cassette.is_replaying() => {
+                Ok(cassette.replay(method.as_str(), url, body))
+            }
+            cassette => match (cassette, client.execute(request).await) {
+                (Some(cassette), Ok(response)) => {
+                    cassette
+                        .record(method.as_str(), url, body, response, &[api_key])
+                        .await
+                }
+                (_, result) => result,
+            },
+        };
+        match result {
+            Ok(response) => {
+                self.trace
+                    .response(response, started.elapsed(), &[api_key])
+                    .await
+            }
+            Err(e) => {
+                self.trace.failure(&e, started.elapsed());
+                Err(e)
+            }
         }
     }
 
-    fn check_api_key(&self) -> Result<(), String> {
-        if self.api_key.len().gt(&0) {
-            Ok(())
+    /// Models of the provider, from a listing cached for a day, or of any age when offline.
+    pub(crate) async fn models(&self) -> Result<Vec<ModelInfo>, GptError> {
+        let url = self.provider.models_url();
+        let path = self
+            .models_path
+            .clone()
+            .unwrap_or_else(ModelsCache::default_path);
+        let mut cache = ModelsCache::load(&path);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let max_age = if self.offline {
+            u64::MAX
         } else {
-            Err(String::from(
-                "Error: OPENAI_API_KEY environment variable is not defined.",
-            ))
+            models::MAX_AGE
+        };
+        if let Some(models) = cache.get(&url, now, max_age) {
+            return Ok(models);
+        }
+        if self.offline {
+            return Err(GptError::NoCachedAnswer(String::from(
+                "offline mode is on.",
+            )));
+        }
+
+        self.check_credentials()?;
+        let api_key = self.api_key.value().unwrap_or_default();
+        let client = self.http.client()?;
+        let spinner = self.spinner.then(|| Spinner::start("Listing models"));
+        let response =
+            interruptible(self.exchange(&client, Method::GET, &url, None, &api_key)).await??;
+        drop(spinner);
+
+        let status = response.status();
+        let body = response.text().await?;
+        if !status.is_success() {
+            return Err(GptError::from_response(status, body));
+        }
+        let models = models::parse(&self.provider, &body, &usage::Pricing::from_env())?;
+        cache.set(&url, now, &models);
+        if let Err(e) = cache.save(&path) {
+            eprintln!("Can't save {path}: {e}");
+        }
+        Ok(models)
+    }
+
+    /// Listed model close to the configured one, when it doesn't exist.
+    pub(crate) async fn similar_model(&self) -> Option<String> {
+        // Azure is configured with deployment names, which are not listed.
+        if matches!(self.provider, Provider::Azure { .. }) {
+            return None;
+        }
+        let models = self.models().await.ok()?;
+        models::similar(&self.model, &models).map(String::from)
+    }
+
+    /// Asks the configured model, then the fallbacks in order while they fail with an outage.
+    pub(crate) async fn ask(&self, messages: Vec<Gpt3Message>) -> Result<Gpt3Response, GptError> {
+        let mut result = self.ask_model(messages.clone()).await;
+
+        for (provider, model) in &self.fallbacks {
+            match &result {
+                Err(error) if error.is_outage() && !self.offline => {
+                    let fallback = self.with_model(provider, model);
+                    clear_line();
+                    eprintln!(
+                        "{}",
+                        format!("{error}\nFalling back to {}.", fallback.label()).yellow()
+                    );
+                    result = fallback.ask_model(messages.clone()).await;
+                    if result.is_ok() {
+                        eprintln!("{}", format!("Answered by {}.", fallback.label()).dimmed());
+                    }
+                }
+                _ => break,
+            }
         }
+
+        result
     }
 
-    #[async_recursion]
-    pub(crate) async fn ask(&self, messages: Vec<Gpt3Message>) -> Result<Gpt3Response, String> {
-        let base_url = format!("{}/v1/chat/completions", self.openapi_host);
+    async fn ask_model(&self, messages: Vec<Gpt3Message>) -> Result<Gpt3Response, GptError> {
+        let base_url = self.provider.chat_url();
 
         if self.debug {
             let response = Gpt3Response {
@@ -65,10 +505,12 @@ impl Gpt {
                     completion_tokens: 3,
                     total_tokens: 48,
                 },
+                cached: false,
                 choices: vec![Choice {
                     message: Message {
                         role: "assistant".to_string(),
                         content: "npx ncu -i".to_string(),
+                        tool_calls: None,
                     },
                     finish_reason: Some("stop".to_string()),
                     index: 0,
@@ -77,109 +519,428 @@ impl Gpt {
             return Ok(response);
         }
 
-        self.check_api_key()?;
+        let mut cache = self.open_cache()?;
+        let key = self.cache_key(&messages);
+
+        if self.offline {
+            return self
+                .cached(&mut cache, &key)
+                .ok_or_else(|| GptError::NoCachedAnswer(String::from("offline mode is on.")));
+        }
 
-        let mut cache = Cache::new(None);
-        let key = serde_json::to_string(&messages).unwrap();
+        self.check_credentials()?;
 
-        if self.is_open_ai() {
-            if let Some(cached_data) = cache.get(&key) {
-                let response: Gpt3Response = serde_json::from_str(&cached_data).unwrap();
-                return Ok(response);
-            }
+        if self.candidates <= 1
+            && let Some(cached) = self.cached(&mut cache, &key)
+        {
+            return Ok(cached);
         }
 
-        let model = std::env::var("GPT_MODEL").unwrap_or_else(|_| String::from("gpt-4o"));
+        let model = self.model.clone();
 
-        let data = json!({
+        let mut data = json!({
             "model": model,
             "messages": messages
         });
+        if let serde_json::Value::Object(parameters) = json!(
+            self.effective_sampling()
+                .for_model(&model, &self.default_sampling)
+        ) {
+            data.as_object_mut().unwrap().extend(parameters);
+        }
+        if self.structured.uses_schema(self.is_open_ai()) {
+            data["response_format"] = CommandResponse::response_format();
+        } else if self.is_structured() {
+            CommandResponse::add_fallback_prompt(data["messages"].as_array_mut().unwrap());
+        }
+        if self.tools && self.candidates <= 1 {
+            if !self.provider.supports_tools() {
+                return Err(GptError::Config(format!(
+                    "Error: tools are not supported with {}, unset GPT_TOOLS or --tools.",
+                    self.label()
+                )));
+            }
+            data["tools"] = tools::definitions();
+        }
+        // OpenAI returns several choices for `n`, other hosts may ignore it, so they get parallel requests.
+        let requests = if self.candidates > 1 && !self.is_open_ai() {
+            self.candidates
+        } else {
+            1
+        };
+        if self.candidates > 1 && self.is_open_ai() {
+            data["n"] = json!(self.candidates);
+        }
 
-        let response = reqwest::Client::new()
-            .post(base_url)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .json(&data)
-            .send()
-            .await
-            .map_err(|e| format!("{e}"))?;
+        if let Some(path) = &self.ledger_path {
+            self.budget
+                .enforce(path, &usage::today(), self.force_budget)?;
+        }
 
-        let status = response.status();
+        let client = self.http.client()?;
+        let spinner = self
+            .spinner
+            .then(|| Spinner::start(&format!("Waiting for {model}")));
+        let results = interruptible(join_all(
+            (0..requests).map(|_| self.send(&client, &base_url, &data)),
+        ))
+        .await?;
+        drop(spinner);
 
-        if status.is_success() {
-            // Parse the response body
-            let json = response
-                .json::<Gpt3Response>()
-                .await
-                .map_err(|e| format!("{e}"))?;
+        let mut responses = vec![];
+        let mut failure = None;
+        let mut unreachable = None;
+        for result in results {
+            match result {
+                Ok(response) => match self.read_response(response, &model).await {
+                    Ok(response) => responses.push(response),
+                    Err(error) => failure = failure.or(Some(error)),
+                },
+                Err(e) if e.is_connect() => unreachable = unreachable.or(Some(e)),
+                Err(e) => failure = failure.or(Some(e.into())),
+            }
+        }
 
-            // Use the parsed data
-            match json.choices[0].finish_reason.as_deref() {
-                Some("stop") | Some("") | None => {
-                    let response_str = serde_json::to_string(&json).unwrap();
-                    if self.is_open_ai() {
-                        cache.set(&key, &response_str);
-                    }
+        let Some(json) = Gpt3Response::merge(responses) else {
+            if let Some(error) = failure {
+                return Err(error);
+            }
+            let e = unreachable.expect("every request either failed or succeeded");
+            return match self.cached(&mut cache, &key) {
+                Some(cached) => {
+                    eprintln!("Network is unreachable, answering from cache.");
+                    Ok(cached)
                 }
-                Some(_) => {
-                    println!("{:?}", json.choices[0]);
+                None => Err(GptError::NoCachedAnswer(format!(
+                    "the network is unreachable: {e}"
+                ))),
+            };
+        };
+
+        let json = if self.candidates > 1 {
+            json.without_incomplete_choices()?
+        } else {
+            let (data, json) = self
+                .call_tools(&client, &base_url, data, &model, json)
+                .await?;
+            self.continue_truncated(&client, &base_url, &data, &model, json)
+                .await?
+        };
+
+        // Candidates are cached only once one of them is chosen.
+        if self.caches_answers() && self.candidates <= 1 && json.choices[0].is_finished() {
+            cache.set(&key, &serde_json::to_string(&json).unwrap());
+        }
+
+        Ok(json)
+    }
+
+    /// Asks the model to go on while the answer is cut off at the token limit, stitching the parts.
+    async fn continue_truncated(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        data: &serde_json::Value,
+        model: &str,
+        mut json: Gpt3Response,
+    ) -> Result<Gpt3Response, GptError> {
+        let mut continuations = 0;
+
+        loop {
+            match json.choices[0].finish_reason.as_deref() {
+                Some("length") if continuations < Gpt::MAX_CONTINUATIONS => {}
+                Some("length") => return Err(GptError::Truncated(continuations)),
+                Some("content_filter") => {
+                    return Err(GptError::ContentFiltered(String::from(
+                        "the provider withheld the answer",
+                    )));
                 }
+                _ => return Ok(json),
+            }
+            continuations += 1;
+
+            let mut data = data.clone();
+            let messages = data["messages"].as_array_mut().unwrap();
+            messages.push(json!({
+                "role": "assistant",
+                "content": json.choices[0].message.content
+            }));
+            messages.push(json!({
+                "role": "user",
+                "content": Gpt::CONTINUE_PROMPT
+            }));
+
+            let continuation = self
+                .follow_up(
+                    client,
+                    url,
+                    &data,
+                    &format!("Waiting for {model} to continue"),
+                )
+                .await?;
+            json = json.continued_by(continuation);
+        }
+    }
+
+    /// Runs the tools the model asks for and sends their results back, until it answers. The last
+    /// round forbids further calls. Returns the request data extended with the calls and results.
+    async fn call_tools(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        mut data: serde_json::Value,
+        model: &str,
+        mut json: Gpt3Response,
+    ) -> Result<(serde_json::Value, Gpt3Response), GptError> {
+        for iteration in 1..=Gpt::MAX_TOOL_ITERATIONS {
+            let message = &json.choices[0].message;
+            let calls: &[ToolCall] = message.tool_calls.as_deref().unwrap_or_default();
+            if calls.is_empty() {
+                return Ok((data, json));
+            }
+
+            let messages = data["messages"].as_array_mut().unwrap();
+            messages.push(json!(message));
+            for call in calls.iter() {
+                clear_line();
+                eprintln!(
+                    "{}",
+                    format!(
+                        "Running tool {}({})",
+                        call.function.name, call.function.arguments
+                    )
+                    .dimmed()
+                );
+                messages.push(json!({
+                    "role": "tool",
+                    "tool_call_id": call.id,
+                    "content": tools::call(&call.function)
+                }));
             }
+            if iteration == Gpt::MAX_TOOL_ITERATIONS {
+                data["tool_choice"] = json!("none");
+            }
+
+            let next = self
+                .follow_up(client, url, &data, &format!("Waiting for {model}"))
+                .await?;
+            json = json.followed_by(next);
+        }
+
+        match json.choices[0].message.tool_calls.as_deref() {
+            Some([_, ..]) => Err(GptError::MalformedResponse(String::from(
+                "the model kept calling tools instead of answering",
+            ))),
+            _ => Ok((data, json)),
+        }
+    }
 
+    /// Sends a request continuing the conversation of the first one.
+    async fn follow_up(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        data: &serde_json::Value,
+        label: &str,
+    ) -> Result<Gpt3Response, GptError> {
+        let model = data["model"].as_str().unwrap_or_default();
+        let spinner = self.spinner.then(|| Spinner::start(label));
+        let response = interruptible(self.send(client, url, data)).await??;
+        drop(spinner);
+        self.read_response(response, model).await
+    }
+
+    /// Parses a completion, recording its usage, or classifies the error it reports.
+    async fn read_response(
+        &self,
+        response: reqwest::Response,
+        model: &str,
+    ) -> Result<Gpt3Response, GptError> {
+        let status = response.status();
+
+        if status.is_success() {
+            // Parse the response body
+            let body = response.text().await?;
+            let json = self.provider.parse_response(&body)?;
+            if json.choices.is_empty() {
+                return Err(GptError::MalformedResponse(String::from(
+                    "response contains no choices",
+                )));
+            }
+            if let Some(path) = &self.ledger_path {
+                usage::record(path, model, &json.usage);
+            }
             Ok(json)
-        } else if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
-            let error_body = response.text().await.map_err(|e| format!("{e}"))?;
-            let error_json = serde_json::from_str::<serde_json::Value>(&error_body)
-                .map_err(|e| format!("{e}"))?;
-            let seconds_to_wait = error_json["seconds_to_wait"].as_u64().unwrap_or_default();
-            thread::sleep(Duration::from_secs(seconds_to_wait));
-
-            self.ask(messages).await
         } else {
-            let error_body = response.text().await.map_err(|e| format!("{e}"))?;
-            Err(format!(
-                "Request failed with status code: {}\nError response body: {}",
-                status, error_body
-            ))
+            let error_body = response.text().await?;
+            Err(GptError::from_response(status, error_body).about_model(model))
         }
     }
+
+    /// Caches `answer`, one of the candidates in `response`, as the answer to `messages`, when
+    /// answers of the provider are cached.
+    pub(crate) fn prefer(
+        &self,
+        messages: &[Gpt3Message],
+        response: &Gpt3Response,
+        answer: &str,
+    ) -> Result<(), GptError> {
+        let choice = response
+            .choices
+            .iter()
+            .find(|choice| choice.message.content.trim() == answer)
+            .ok_or_else(|| GptError::MalformedResponse(format!("no candidate {answer}")))?;
+        let preferred = Gpt3Response {
+            choices: vec![Choice {
+                index: 0,
+                ..choice.clone()
+            }],
+            ..response.clone()
+        };
+
+        if self.caches_answers() {
+            self.open_cache()?.set(
+                &self.cache_key(messages),
+                &serde_json::to_string(&preferred).unwrap(),
+            );
+        }
+        Ok(())
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Gpt3Message {
     pub(crate) role: String,
     pub(crate) content: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Gpt3Response {
     id: String,
     object: String,
     created: i64,
-    model: String,
-    usage: Usage,
+    pub(crate) model: String,
+    pub(crate) usage: Usage,
     pub choices: Vec<Choice>,
+    /// Served from the cache, so no tokens were spent on it.
+    #[serde(skip)]
+    pub(crate) cached: bool,
+}
+
+impl Gpt3Response {
+    fn from_cache(cached_data: &str) -> Self {
+        Gpt3Response {
+            cached: true,
+            ..serde_json::from_str(cached_data).unwrap()
+        }
+    }
+
+    /// Joins answers to parallel requests into one response with all their choices.
+    fn merge(responses: Vec<Gpt3Response>) -> Option<Self> {
+        let mut responses = responses.into_iter();
+        let mut merged = responses.next()?;
+        for response in responses {
+            merged.usage.add(&response.usage);
+            merged.choices.extend(response.choices);
+        }
+        for (index, choice) in merged.choices.iter_mut().enumerate() {
+            choice.index = index as i32;
+        }
+        Some(merged)
+    }
+
+    /// Appends the answer of a continuation request to the first choice.
+    fn continued_by(mut self, continuation: Gpt3Response) -> Self {
+        self.usage.add(&continuation.usage);
+        if let Some(next) = continuation.choices.into_iter().next() {
+            self.choices[0].message.content += &next.message.content;
+            self.choices[0].finish_reason = next.finish_reason;
+        }
+        self
+    }
+
+    /// Replaces the answer with the one of a request following it, keeping the usage of both.
+    fn followed_by(mut self, next: Gpt3Response) -> Self {
+        self.usage.add(&next.usage);
+        self.choices = next.choices;
+        self
+    }
+
+    /// Drops candidates cut off or filtered, failing when none is left.
+    fn without_incomplete_choices(mut self) -> Result<Self, GptError> {
+        let first_reason = self.choices[0].finish_reason.clone();
+        self.choices.retain(|choice| {
+            !matches!(
+                choice.finish_reason.as_deref(),
+                Some("length") | Some("content_filter")
+            )
+        });
+        match first_reason.as_deref() {
+            _ if !self.choices.is_empty() => Ok(self),
+            Some("content_filter") => Err(GptError::ContentFiltered(String::from(
+                "the provider withheld the answer",
+            ))),
+            _ => Err(GptError::Truncated(0)),
+        }
+    }
+
+    /// Trimmed answers of all choices without duplicates, in the order they came.
+    pub(crate) fn distinct_answers(&self) -> Vec<String> {
+        let mut answers: Vec<String> = vec![];
+        for choice in self.choices.iter() {
+            let answer = choice.message.content.trim();
+            if !answer.is_empty() && !answers.iter().any(|a| a == answer) {
+                answers.push(answer.to_string());
+            }
+        }
+        answers
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Usage {
+    pub(crate) prompt_tokens: i32,
+    pub(crate) completion_tokens: i32,
+    pub(crate) total_tokens: i32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Usage {
-    prompt_tokens: i32,
-    completion_tokens: i32,
-    total_tokens: i32,
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Choice {
     pub message: Message,
     finish_reason: Option<String>,
     index: i32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Choice {
+    /// Whether the model ended the answer itself, rather than being cut off or filtered.
+    fn is_finished(&self) -> bool {
+        matches!(
+            self.finish_reason.as_deref(),
+            Some("stop") | Some("") | None
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     role: String,
+    /// Empty when the model calls tools instead of answering.
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+}
+
+fn null_as_empty<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
 }
 
 #[cfg(test)]
@@ -212,7 +973,7 @@ mod tests {
         unsafe {
             std::env::set_var("OPENAI_API_KEY", "test_key");
         }
-        let gpt = Gpt::new(Some(true), None);
+        let gpt = Gpt::isolated(true, None);
         let messages = vec![Gpt3Message {
             content: "hello".to_string(),
             role: "user".to_string(),
@@ -231,7 +992,7 @@ mod tests {
         unsafe {
             std::env::remove_var("OPENAI_API_KEY");
         }
-        let gpt = Gpt::new(Some(false), None);
+        let gpt = Gpt::isolated(false, None);
         let messages = vec![Gpt3Message {
             content: "hello".to_string(),
             role: "user".to_string(),
@@ -239,7 +1000,7 @@ mod tests {
         let result = futures::executor::block_on(gpt.ask(messages));
         assert!(result.is_err());
         // Check that the error message is correct
-        let error = result.unwrap_err();
+        let error = result.unwrap_err().to_string();
         assert!(error.contains("Error: OPENAI_API_KEY environment variable is not defined."));
     }
 
@@ -286,7 +1047,7 @@ mod tests {
         unsafe {
             std::env::set_var("OPENAI_API_KEY", "test_key");
         }
-        let gpt = Gpt::new(Some(false), Some(&server.url("")));
+        let gpt = Gpt::isolated(false, Some(&server.url("")));
         let messages = vec![
             Gpt3Message {
                 role: "system".to_string(),
@@ -310,6 +1071,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(clippy::assertions_on_constants)]
     async fn test_ask_fail() {
         let server = httpmock::MockServer::start();
 
@@ -325,7 +1087,11 @@ mod tests {
         unsafe {
             std::env::set_var("OPENAI_API_KEY", "test_key");
         }
-        let gpt = Gpt::new(Some(false), Some(&server.url("")));
+        let mut gpt = Gpt::isolated(false, Some(&server.url("")));
+        gpt.retry_policy = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
         let messages = vec![
             Gpt3Message {
                 role: "system".to_string(),
@@ -343,11 +1109,1138 @@ mod tests {
             Err(error_message) => {
                 // Assert
                 assert_eq!(
-                    error_message,
+                    error_message.to_string(),
                     "Request failed with status code: 500 Internal Server Error\nError response body: {\"message\":\"unexpected error\",\"status\":\"error\"}"
                 );
                 mock.assert();
             }
         }
     }
+
+    /// Client of OpenAI reached through `proxy`, with an answer to `messages` in the cache.
+    fn cached_answer_gpt(proxy: &str, cache_path: &str, messages: &[Gpt3Message]) -> Gpt {
+        let mut cache = Cache::new(Some(cache_path));
+        cache.set(
+            &serde_json::to_string(messages).unwrap(),
+            &json!({
+                "id": "cachedid",
+                "object": "chat.completion",
+                "created": 1678705627,
+                "model": "gpt-4o",
+                "usage": { "prompt_tokens": 45, "completion_tokens": 3, "total_tokens": 48 },
+                "choices": [
+                    {
+                        "message": { "role": "assistant", "content": "cal" },
+                        "finish_reason": "stop",
+                        "index": 0
+                    }
+                ]
+            })
+            .to_string(),
+        );
+        let mut gpt = Gpt::isolated(false, None);
+        gpt.provider = Provider::OpenAi {
+            host: String::from(Provider::OPEN_AI_HOST),
+        };
+        gpt.http.proxy = Some(proxy.to_string());
+        gpt.cache_path = Some(cache_path.to_string());
+        gpt.api_key = ApiKey::from("test_key");
+        gpt.retry_policy = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
+        gpt
+    }
+
+    fn show_calendar() -> Vec<Gpt3Message> {
+        vec![Gpt3Message {
+            role: "user".to_string(),
+            content: "show calendar".to_string(),
+        }]
+    }
+
+    #[tokio::test]
+    async fn test_ask_offline_from_cache() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(500);
+        });
+        let cache_path = &format!("/tmp/.gpt-cache-offline-{}.json", rand::random::<u64>());
+        let gpt =
+            cached_answer_gpt(&server.url(""), cache_path, &show_calendar()).with_offline(true);
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+
+        assert_eq!(response.choices[0].message.content, "cal");
+        assert!(response.cached);
+        mock.assert_hits(0);
+        std::fs::remove_file(cache_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ask_offline_without_cached_answer() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(500);
+        });
+        let cache_path = &format!("/tmp/.gpt-cache-offline-{}.json", rand::random::<u64>());
+        let mut gpt = Gpt::isolated(false, Some(&server.url(""))).with_offline(true);
+        gpt.cache_path = Some(cache_path.to_string());
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Error: no cached answer for this prompt, and offline mode is on."
+        );
+        mock.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn test_ask_offline_ignores_cache_of_custom_host() {
+        let cache_path = &format!("/tmp/.gpt-cache-offline-{}.json", rand::random::<u64>());
+        let mut gpt = cached_answer_gpt("http://127.0.0.1:9", cache_path, &show_calendar())
+            .with_offline(true);
+        gpt.provider = Provider::OpenAi {
+            host: String::from("http://127.0.0.1:9"),
+        };
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert!(matches!(error, GptError::NoCachedAnswer(_)));
+        std::fs::remove_file(cache_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ask_unreachable_network_falls_back_to_cache() {
+        let cache_path = &format!("/tmp/.gpt-cache-offline-{}.json", rand::random::<u64>());
+        let gpt = cached_answer_gpt("http://127.0.0.1:9", cache_path, &show_calendar());
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+
+        assert_eq!(response.choices[0].message.content, "cal");
+        std::fs::remove_file(cache_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ask_unreachable_network_without_cached_answer() {
+        let cache_path = &format!("/tmp/.gpt-cache-offline-{}.json", rand::random::<u64>());
+        let mut gpt = Gpt::isolated(false, Some("http://127.0.0.1:9"));
+        gpt.cache_path = Some(cache_path.to_string());
+        gpt.api_key = ApiKey::from("test_key");
+        gpt.retry_policy = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert!(error.to_string().starts_with(
+            "Error: no cached answer for this prompt, and the network is unreachable"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_ask_retries_server_errors() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/v1/chat/completions");
+            then.status(503)
+                .header("retry-after", "0")
+                .body("overloaded");
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url("")));
+        gpt.api_key = ApiKey::from("test_key");
+        gpt.retry_policy = RetryPolicy {
+            max_attempts: 3,
+            ..RetryPolicy::default()
+        };
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Request failed with status code: 503 Service Unavailable\nError response body: overloaded"
+        );
+        mock.assert_hits(3);
+    }
+
+    #[tokio::test]
+    async fn test_ask_does_not_retry_client_errors() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/v1/chat/completions");
+            then.status(400).body("bad request");
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url("")));
+        gpt.api_key = ApiKey::from("test_key");
+
+        assert!(gpt.ask(show_calendar()).await.is_err());
+        mock.assert_hits(1);
+    }
+
+    #[tokio::test]
+    async fn test_ask_auth_rejected() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/v1/chat/completions");
+            then.status(401).json_body_obj(&json!({
+                "error": {
+                    "message": "Incorrect API key provided: sk-xxx.",
+                    "type": "invalid_request_error",
+                    "param": null,
+                    "code": "invalid_api_key"
+                }
+            }));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url("")));
+        gpt.api_key = ApiKey::from("test_key");
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert!(matches!(error, GptError::AuthRejected(_)));
+        assert_eq!(
+            error.to_string(),
+            "Error: authentication rejected: Incorrect API key provided: sk-xxx."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ask_malformed_response() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/v1/chat/completions");
+            then.status(200).body("<html>proxy login</html>");
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url("")));
+        gpt.api_key = ApiKey::from("test_key");
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert!(matches!(error, GptError::MalformedResponse(_)));
+    }
+
+    #[tokio::test]
+    async fn test_ask_timeout() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/v1/chat/completions");
+            then.status(200).delay(std::time::Duration::from_secs(5));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url("")));
+        gpt.api_key = ApiKey::from("test_key");
+        gpt.retry_policy = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
+        gpt.http = HttpConfig {
+            timeout: std::time::Duration::from_millis(200),
+            ..HttpConfig::default()
+        };
+
+        let started = Instant::now();
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert!(matches!(error, GptError::Timeout(_)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_ask_records_usage_of_answers_from_api() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/v1/chat/completions");
+            then.status(200).json_body_obj(&json!({
+                "id": "testid",
+                "object": "chat.completion",
+                "created": 1678705627,
+                "model": "gpt-4o-2024-08-06",
+                "usage": { "prompt_tokens": 45, "completion_tokens": 3, "total_tokens": 48 },
+                "choices": [
+                    {
+                        "message": { "role": "assistant", "content": "cal" },
+                        "finish_reason": "stop",
+                        "index": 0
+                    }
+                ]
+            }));
+        });
+        let ledger_path = &format!("/tmp/.gpt-usage-{}.json", rand::random::<u64>());
+        let mut gpt =
+            Gpt::isolated(false, Some(&server.url(""))).with_ledger(ledger_path.to_string());
+        gpt.api_key = ApiKey::from("test_key");
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+        assert!(!response.cached);
+        gpt.ask(show_calendar()).await.unwrap();
+
+        let total = usage::Ledger::load(ledger_path)
+            .unwrap()
+            .total(&usage::today());
+        assert_eq!(total.requests, 2);
+        assert_eq!(total.prompt_tokens, 90);
+        assert_eq!(total.completion_tokens, 6);
+        std::fs::remove_file(ledger_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ask_does_not_record_usage_of_cached_answers() {
+        let cache_path = &format!("/tmp/.gpt-cache-offline-{}.json", rand::random::<u64>());
+        let ledger_path = &format!("/tmp/.gpt-usage-{}.json", rand::random::<u64>());
+        let gpt = cached_answer_gpt("http://127.0.0.1:9", cache_path, &show_calendar())
+            .with_offline(true)
+            .with_ledger(ledger_path.to_string());
+
+        assert!(gpt.ask(show_calendar()).await.unwrap().cached);
+
+        assert!(!std::path::Path::new(ledger_path).exists());
+        std::fs::remove_file(cache_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ask_refuses_past_budget() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(500);
+        });
+        let ledger_path = &format!("/tmp/.gpt-usage-{}.json", rand::random::<u64>());
+        let mut ledger = usage::Ledger::default();
+        ledger.record(
+            &usage::today(),
+            "gpt-4o",
+            "default",
+            &usage::UsageEntry {
+                requests: 1,
+                prompt_tokens: 100,
+                completion_tokens: 10,
+                cost: 0.0,
+            },
+        );
+        ledger.save(ledger_path).unwrap();
+        let mut gpt =
+            Gpt::isolated(false, Some(&server.url(""))).with_ledger(ledger_path.to_string());
+        gpt.api_key = ApiKey::from("test_key");
+        gpt.budget = Budget {
+            limits: vec![crate::budget::Limit {
+                period: crate::budget::Period::Day,
+                unit: crate::budget::Unit::Tokens,
+                amount: 100.0,
+            }],
+            ..Budget::default()
+        };
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert!(matches!(error, GptError::BudgetExceeded(_)));
+        mock.assert_hits(0);
+        std::fs::remove_file(ledger_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ask_candidates_in_parallel() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/v1/chat/completions")
+                .json_body_partial(r#"{"temperature": 0.7}"#);
+            then.status(200).json_body_obj(&json!({
+                "id": "testid",
+                "object": "chat.completion",
+                "created": 1678705627,
+                "model": "llama3",
+                "usage": { "prompt_tokens": 45, "completion_tokens": 3, "total_tokens": 48 },
+                "choices": [
+                    {
+                        "message": { "role": "assistant", "content": "cal " },
+                        "finish_reason": "stop",
+                        "index": 0
+                    }
+                ]
+            }));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url(""))).with_candidates(3);
+        gpt.api_key = ApiKey::from("test_key");
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+
+        mock.assert_hits(3);
+        assert_eq!(response.choices.len(), 3);
+        assert_eq!(response.choices[2].index, 2);
+        assert_eq!(response.usage.total_tokens, 144);
+        assert_eq!(response.distinct_answers(), vec!["cal"]);
+    }
+
+    #[test]
+    fn test_distinct_answers() {
+        let choice = |content: &str| Choice {
+            message: Message {
+                role: "assistant".to_string(),
+                content: content.to_string(),
+                tool_calls: None,
+            },
+            finish_reason: Some("stop".to_string()),
+            index: 0,
+        };
+        let response = Gpt3Response {
+            id: "testid".to_string(),
+            object: "chat.completion".to_string(),
+            created: 1678705627,
+            model: "gpt-4o".to_string(),
+            usage: Usage {
+                prompt_tokens: 45,
+                completion_tokens: 9,
+                total_tokens: 54,
+            },
+            choices: vec![choice("cal"), choice("ncal\n"), choice(" cal"), choice("")],
+            cached: false,
+        };
+
+        assert_eq!(response.distinct_answers(), vec!["cal", "ncal"]);
+    }
+
+    #[tokio::test]
+    async fn test_prefer_caches_chosen_candidate() {
+        let cache_path = &format!("/tmp/.gpt-cache-offline-{}.json", rand::random::<u64>());
+        let gpt = cached_answer_gpt("http://127.0.0.1:9", cache_path, &show_calendar());
+        let mut response = gpt.ask(show_calendar()).await.unwrap();
+        response.choices.push(Choice {
+            message: Message {
+                role: "assistant".to_string(),
+                content: "ncal".to_string(),
+                tool_calls: None,
+            },
+            finish_reason: Some("stop".to_string()),
+            index: 1,
+        });
+
+        gpt.prefer(&show_calendar(), &response, "ncal").unwrap();
+
+        let preferred = gpt.with_offline(true).ask(show_calendar()).await.unwrap();
+        assert_eq!(preferred.choices.len(), 1);
+        assert_eq!(preferred.choices[0].message.content, "ncal");
+        assert_eq!(preferred.choices[0].index, 0);
+        std::fs::remove_file(cache_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_prefer_skips_cache_of_custom_host() {
+        let cache_path = &format!("/tmp/.gpt-cache-offline-{}.json", rand::random::<u64>());
+        let mut gpt = cached_answer_gpt("http://127.0.0.1:9", cache_path, &show_calendar());
+        let response = gpt
+            .clone()
+            .with_offline(true)
+            .ask(show_calendar())
+            .await
+            .unwrap();
+        gpt.provider = Provider::OpenAi {
+            host: String::from("http://127.0.0.1:9"),
+        };
+        let messages = vec![Gpt3Message {
+            role: "user".to_string(),
+            content: "show calendar of 2024".to_string(),
+        }];
+
+        gpt.prefer(&messages, &response, "cal").unwrap();
+
+        assert_eq!(
+            Cache::new(Some(cache_path)).get(&gpt.cache_key(&messages)),
+            None
+        );
+        std::fs::remove_file(cache_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cassette_bypasses_cache() {
+        let cache_path = &format!("/tmp/.gpt-cache-offline-{}.json", rand::random::<u64>());
+        let cassette_path = format!("/tmp/.gpt-cassette-{}.json", rand::random::<u64>());
+        let mut gpt = cached_answer_gpt("http://127.0.0.1:9", cache_path, &show_calendar());
+        gpt.cassette = Some(Cassette::new(&cassette_path, crate::cassette::Mode::Record));
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert!(matches!(error, GptError::NoCachedAnswer(_)), "{error:?}");
+        std::fs::remove_file(cache_path).unwrap();
+        let _ = std::fs::remove_file(cassette_path);
+    }
+
+    #[tokio::test]
+    async fn test_agent_client_skips_cache() {
+        let cache_path = &format!("/tmp/.gpt-cache-offline-{}.json", rand::random::<u64>());
+        let gpt = cached_answer_gpt("http://127.0.0.1:9", cache_path, &show_calendar())
+            .with_structured(true)
+            .with_tools(true)
+            .with_candidates(3)
+            .for_agent();
+
+        assert!(!gpt.is_structured());
+        assert!(!gpt.tools);
+        assert_eq!(gpt.candidates, 1);
+        let error = gpt
+            .with_offline(true)
+            .ask(show_calendar())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, GptError::NoCachedAnswer(_)));
+        std::fs::remove_file(cache_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ask_sends_sampling_parameters() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/v1/chat/completions")
+                .json_body_partial(r#"{"temperature": 0.5, "seed": 7, "stop": ["\n"]}"#);
+            then.status(200).json_body_obj(&json!({
+                "id": "testid",
+                "object": "chat.completion",
+                "created": 1678705627,
+                "model": "gpt-4o",
+                "usage": { "prompt_tokens": 45, "completion_tokens": 3, "total_tokens": 48 },
+                "choices": [
+                    {
+                        "message": { "role": "assistant", "content": "cal" },
+                        "finish_reason": "stop",
+                        "index": 0
+                    }
+                ]
+            }));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url(""))).with_sampling(&Sampling {
+            temperature: Some(0.5),
+            seed: Some(7),
+            stop: vec![String::from("\n")],
+            ..Sampling::default()
+        });
+        gpt.api_key = ApiKey::from("test_key");
+
+        gpt.ask(show_calendar()).await.unwrap();
+
+        mock.assert();
+    }
+
+    #[test]
+    fn test_cache_key_includes_configured_sampling() {
+        let gpt = Gpt::isolated(false, None);
+        let legacy_key = serde_json::to_string(&show_calendar()).unwrap();
+        assert_eq!(gpt.cache_key(&show_calendar()), legacy_key);
+
+        let gpt = gpt.with_sampling(&Sampling {
+            max_tokens: Some(50),
+            ..Sampling::default()
+        });
+        let key = gpt.cache_key(&show_calendar());
+        assert_ne!(key, legacy_key);
+        assert!(key.contains(r#""max_tokens":50"#));
+    }
+
+    fn completion(content: &str, finish_reason: &str) -> serde_json::Value {
+        json!({
+            "id": "testid",
+            "object": "chat.completion",
+            "created": 1678705627,
+            "model": "gpt-4o",
+            "usage": { "prompt_tokens": 45, "completion_tokens": 3, "total_tokens": 48 },
+            "choices": [
+                {
+                    "message": { "role": "assistant", "content": content },
+                    "finish_reason": finish_reason,
+                    "index": 0
+                }
+            ]
+        })
+    }
+
+    fn is_continuation(request: &httpmock::prelude::HttpMockRequest) -> bool {
+        String::from_utf8_lossy(request.body.as_deref().unwrap_or_default())
+            .contains(Gpt::CONTINUE_PROMPT)
+    }
+
+    #[tokio::test]
+    async fn test_ask_continues_truncated_answer() {
+        let server = httpmock::MockServer::start();
+        let first = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .matches(|request| !is_continuation(request));
+            then.status(200)
+                .json_body_obj(&completion("tar -czf backup.tar.gz /home/us", "length"));
+        });
+        let continuation = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .matches(is_continuation)
+                .body_contains("tar -czf backup.tar.gz /home/us");
+            then.status(200)
+                .json_body_obj(&completion("er/documents", "stop"));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url("")));
+        gpt.api_key = ApiKey::from("test_key");
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+
+        assert_eq!(
+            response.choices[0].message.content,
+            "tar -czf backup.tar.gz /home/user/documents"
+        );
+        assert_eq!(response.usage.total_tokens, 96);
+        first.assert();
+        continuation.assert();
+    }
+
+    #[tokio::test]
+    async fn test_ask_gives_up_on_truncated_answer() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200)
+                .json_body_obj(&completion("yes ", "length"));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url("")));
+        gpt.api_key = ApiKey::from("test_key");
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert!(matches!(error, GptError::Truncated(3)));
+        mock.assert_hits(4);
+    }
+
+    #[tokio::test]
+    async fn test_ask_content_filter() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200)
+                .json_body_obj(&completion("", "content_filter"));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url("")));
+        gpt.api_key = ApiKey::from("test_key");
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Error: blocked by the content filter: the provider withheld the answer"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ask_drops_truncated_candidates() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200)
+                .json_body_obj(&completion("cal -", "length"));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url(""))).with_candidates(2);
+        gpt.api_key = ApiKey::from("test_key");
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert!(matches!(error, GptError::Truncated(0)));
+    }
+
+    #[tokio::test]
+    async fn test_ask_structured_with_schema() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .json_body_partial(r#"{"response_format": {"type": "json_schema"}}"#);
+            then.status(200).json_body_obj(&completion(
+                r#"{"command": "cal", "explanation": "Shows a calendar.", "requires_shell": false, "modifies_files": false, "needs_sudo": false, "risk": "low"}"#,
+                "stop",
+            ));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url("")));
+        gpt.api_key = ApiKey::from("test_key");
+        gpt.structured = StructuredOutput::Schema;
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+
+        mock.assert();
+        let command = CommandResponse::from_answer(&response.choices[0].message.content);
+        assert_eq!(command.command, "cal");
+        assert_eq!(command.explanation, "Shows a calendar.");
+    }
+
+    #[tokio::test]
+    async fn test_ask_structured_with_prompt() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .body_contains("Respond only with a JSON object");
+            then.status(200).json_body_obj(&completion("cal", "stop"));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url(""))).with_structured(true);
+        gpt.api_key = ApiKey::from("test_key");
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+
+        mock.assert();
+        assert_eq!(
+            CommandResponse::from_answer(&response.choices[0].message.content).command,
+            "cal"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ask_calls_tools() {
+        let server = httpmock::MockServer::start();
+        let tool_call = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .json_body_partial(r#"{"tools": [{"type": "function"}]}"#)
+                .matches(|request| {
+                    !String::from_utf8_lossy(request.body.as_deref().unwrap_or_default())
+                        .contains(r#""role":"tool""#)
+                });
+            then.status(200).json_body_obj(&json!({
+                "id": "testid",
+                "object": "chat.completion",
+                "created": 1678705627,
+                "model": "gpt-4o",
+                "usage": { "prompt_tokens": 45, "completion_tokens": 3, "total_tokens": 48 },
+                "choices": [
+                    {
+                        "message": {
+                            "role": "assistant",
+                            "content": null,
+                            "tool_calls": [{
+                                "id": "call_1",
+                                "type": "function",
+                                "function": { "name": "which", "arguments": "{\"program\": \"sh\"}" }
+                            }]
+                        },
+                        "finish_reason": "tool_calls",
+                        "index": 0
+                    }
+                ]
+            }));
+        });
+        let answer = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .body_contains(r#""role":"tool""#)
+                .body_contains(r#""tool_call_id":"call_1""#)
+                .body_contains("/sh");
+            then.status(200)
+                .json_body_obj(&completion("sh -c 'cal'", "stop"));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url(""))).with_tools(true);
+        gpt.api_key = ApiKey::from("test_key");
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+
+        tool_call.assert();
+        answer.assert();
+        assert_eq!(response.choices[0].message.content, "sh -c 'cal'");
+        assert_eq!(response.usage.total_tokens, 96);
+    }
+
+    #[tokio::test]
+    async fn test_ask_caps_tool_iterations() {
+        let server = httpmock::MockServer::start();
+        let forced = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .json_body_partial(r#"{"tool_choice": "none"}"#);
+            then.status(200).json_body_obj(&completion("cal", "stop"));
+        });
+        let calls = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200).json_body_obj(&json!({
+                "id": "testid",
+                "object": "chat.completion",
+                "created": 1678705627,
+                "model": "gpt-4o",
+                "usage": { "prompt_tokens": 45, "completion_tokens": 3, "total_tokens": 48 },
+                "choices": [
+                    {
+                        "message": {
+                            "role": "assistant",
+                            "content": "",
+                            "tool_calls": [{
+                                "id": "call_1",
+                                "type": "function",
+                                "function": { "name": "os_release", "arguments": "{}" }
+                            }]
+                        },
+                        "finish_reason": "tool_calls",
+                        "index": 0
+                    }
+                ]
+            }));
+        });
+        let mut gpt = Gpt::isolated(false, Some(&server.url(""))).with_tools(true);
+        gpt.api_key = ApiKey::from("test_key");
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+
+        assert_eq!(response.choices[0].message.content, "cal");
+        calls.assert_hits(Gpt::MAX_TOOL_ITERATIONS as usize);
+        forced.assert_hits(1);
+    }
+
+    fn azure_gpt(server: &httpmock::MockServer) -> Gpt {
+        let mut gpt = Gpt::mock(&server.url(""));
+        gpt.provider = Provider::Azure {
+            endpoint: server.url(""),
+            deployment: String::from("gpt-4o-prod"),
+            api_version: String::from("2024-10-21"),
+        };
+        gpt.cache_path = Some(format!(
+            "/tmp/.gpt-cache-azure-{}.json",
+            rand::random::<u64>()
+        ));
+        gpt
+    }
+
+    #[tokio::test]
+    async fn test_ask_azure() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/openai/deployments/gpt-4o-prod/chat/completions")
+                .query_param("api-version", "2024-10-21")
+                .header("api-key", "test_key")
+                .matches(|request| {
+                    !request
+                        .headers
+                        .as_ref()
+                        .is_some_and(|headers| headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("authorization")))
+                });
+            then.status(200).json_body_obj(&json!({
+                "id": "chatcmpl-azure",
+                "object": "chat.completion",
+                "created": 1678705627,
+                "model": "gpt-4o-2024-08-06",
+                "prompt_filter_results": [
+                    { "prompt_index": 0, "content_filter_results": { "hate": { "filtered": false, "severity": "safe" } } }
+                ],
+                "usage": { "prompt_tokens": 45, "completion_tokens": 1, "total_tokens": 46 },
+                "choices": [
+                    {
+                        "message": { "role": "assistant", "content": "cal" },
+                        "finish_reason": "stop",
+                        "index": 0,
+                        "content_filter_results": { "hate": { "filtered": false, "severity": "safe" } }
+                    }
+                ]
+            }));
+        });
+
+        let response = azure_gpt(&server).ask(show_calendar()).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "cal");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_ask_azure_content_filter() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(400).json_body_obj(&json!({
+                "error": {
+                    "message": "The response was filtered due to the prompt triggering Azure OpenAI's content management policy.",
+                    "type": null,
+                    "param": "prompt",
+                    "code": "content_filter",
+                    "status": 400,
+                    "innererror": { "code": "ResponsibleAIPolicyViolation" }
+                }
+            }));
+        });
+
+        let error = azure_gpt(&server).ask(show_calendar()).await.unwrap_err();
+        assert!(matches!(error, GptError::ContentFiltered(_)));
+        assert_eq!(error.exit_code(), 25);
+    }
+
+    #[tokio::test]
+    async fn test_ask_azure_filtered_answer() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200).json_body_obj(&json!({
+                "id": "chatcmpl-azure",
+                "object": "chat.completion",
+                "created": 1678705627,
+                "model": "gpt-4o-2024-08-06",
+                "usage": { "prompt_tokens": 45, "completion_tokens": 0, "total_tokens": 45 },
+                "choices": [
+                    {
+                        "message": { "role": "assistant", "content": null },
+                        "finish_reason": "content_filter",
+                        "index": 0,
+                        "content_filter_results": { "violence": { "filtered": true, "severity": "high" } }
+                    }
+                ]
+            }));
+        });
+
+        let error = azure_gpt(&server).ask(show_calendar()).await.unwrap_err();
+        assert!(matches!(error, GptError::ContentFiltered(_)));
+    }
+
+    #[tokio::test]
+    async fn test_ask_azure_deployment_not_found() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(404).json_body_obj(&json!({
+                "error": {
+                    "code": "DeploymentNotFound",
+                    "message": "The API deployment for this resource does not exist."
+                }
+            }));
+        });
+
+        let error = azure_gpt(&server).ask(show_calendar()).await.unwrap_err();
+        assert!(matches!(error, GptError::ModelNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_ask_gemini() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/v1beta/models/gemini-2.0-flash:generateContent")
+                .header("x-goog-api-key", "test_key")
+                .json_body_partial(
+                    r#"{"contents": [{"role": "user", "parts": [{"text": "show calendar"}]}]}"#,
+                );
+            then.status(200).json_body_obj(&json!({
+                "candidates": [{
+                    "content": { "parts": [{ "text": "cal" }], "role": "model" },
+                    "finishReason": "STOP",
+                    "index": 0
+                }],
+                "usageMetadata": { "promptTokenCount": 10, "candidatesTokenCount": 1, "totalTokenCount": 11 },
+                "modelVersion": "gemini-2.0-flash"
+            }));
+        });
+
+        let mut gpt = Gpt::mock(&server.url(""));
+        gpt.provider = Provider::Gemini {
+            host: server.url(""),
+            model: String::from("gemini-2.0-flash"),
+        };
+        gpt.cache_path = Some(format!(
+            "/tmp/.gpt-cache-gemini-{}.json",
+            rand::random::<u64>()
+        ));
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "cal");
+        assert!(!response.cached);
+
+        let cached = gpt.ask(show_calendar()).await.unwrap();
+        assert!(cached.cached);
+        mock.assert_hits(1);
+    }
+
+    #[tokio::test]
+    async fn test_ask_gemini_rejects_tools() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200);
+        });
+
+        let mut gpt = Gpt::mock(&server.url("")).with_tools(true);
+        gpt.provider = Provider::Gemini {
+            host: server.url(""),
+            model: String::from("gemini-2.0-flash"),
+        };
+
+        match gpt.ask(show_calendar()).await {
+            Err(GptError::Config(message)) => assert!(
+                message.starts_with("Error: tools are not supported with "),
+                "{message}"
+            ),
+            other => panic!("Config error was expected, got {other:?}"),
+        }
+        mock.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn test_ask_falls_back_on_outage() {
+        let primary = httpmock::MockServer::start();
+        let backup = httpmock::MockServer::start();
+        let limited = primary.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(429)
+                .json_body_obj(&json!({ "error": { "message": "Rate limit reached" } }));
+        });
+        let answered = backup.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .json_body_partial(r#"{"model": "backup-model"}"#);
+            then.status(200).json_body_obj(&completion("cal", "stop"));
+        });
+
+        let mut gpt = Gpt::mock(&primary.url(""));
+        gpt.fallbacks = vec![(
+            Provider::OpenAi {
+                host: backup.url(""),
+            },
+            String::from("backup-model"),
+        )];
+
+        let response = gpt.ask(show_calendar()).await.unwrap();
+        assert_eq!(response.choices[0].message.content, "cal");
+        limited.assert();
+        answered.assert();
+    }
+
+    #[tokio::test]
+    async fn test_ask_does_not_fall_back_on_rejected_request() {
+        let primary = httpmock::MockServer::start();
+        let backup = httpmock::MockServer::start();
+        primary.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(401)
+                .json_body_obj(&json!({ "error": { "message": "Incorrect API key" } }));
+        });
+        let unused = backup.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(200).json_body_obj(&completion("cal", "stop"));
+        });
+
+        let mut gpt = Gpt::mock(&primary.url(""));
+        gpt.fallbacks = vec![(
+            Provider::OpenAi {
+                host: backup.url(""),
+            },
+            String::from("backup-model"),
+        )];
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+        assert!(matches!(error, GptError::AuthRejected(_)));
+        unused.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn test_ask_reports_fallback_of_unknown_provider() {
+        let server = httpmock::MockServer::start();
+        let unknown = server.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .json_body_partial(r#"{"model": "bedrock:claude"}"#);
+            then.status(404).json_body_obj(&json!({
+                "error": { "message": "The model `bedrock:claude` does not exist", "code": "model_not_found" }
+            }));
+        });
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(429)
+                .json_body_obj(&json!({ "error": { "message": "Rate limit reached" } }));
+        });
+
+        let mut gpt = Gpt::mock(&server.url(""));
+        gpt.fallbacks = parse_models("bedrock:claude", &gpt.provider);
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+        assert!(matches!(error, GptError::ModelNotFound(_)));
+        unknown.assert();
+    }
+
+    #[test]
+    fn test_cache_key_of_fallback_includes_model() {
+        let gpt = Gpt::mock("http://localhost");
+        let fallback = gpt.with_model(
+            &Provider::named("gemini", "gemini-2.0-flash").unwrap(),
+            "gemini-2.0-flash",
+        );
+        let key: serde_json::Value =
+            serde_json::from_str(&fallback.cache_key(&show_calendar())).unwrap();
+        assert_eq!(key["model"], "gemini:gemini-2.0-flash");
+        assert_ne!(
+            gpt.cache_key(&show_calendar()),
+            fallback.cache_key(&show_calendar())
+        );
+    }
+
+    #[test]
+    fn test_cache_key_of_other_providers_includes_model() {
+        let mut gpt = Gpt::mock("http://localhost");
+        let legacy_key = gpt.cache_key(&show_calendar());
+        gpt.provider = Provider::named("gemini", "gemini-2.0-flash").unwrap();
+        gpt.model = String::from("gemini-2.0-flash");
+
+        let key: serde_json::Value =
+            serde_json::from_str(&gpt.cache_key(&show_calendar())).unwrap();
+
+        assert_eq!(key["model"], "gemini:gemini-2.0-flash");
+        assert_ne!(gpt.cache_key(&show_calendar()), legacy_key);
+    }
+
+    fn models_gpt(server: &httpmock::MockServer) -> (Gpt, String) {
+        let path = format!("/tmp/.gpt-models-{}.json", rand::random::<u64>());
+        let mut gpt = Gpt::mock(&server.url(""));
+        gpt.models_path = Some(path.clone());
+        (gpt, path)
+    }
+
+    #[tokio::test]
+    async fn test_models_are_cached() {
+        let server = httpmock::MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(httpmock::Method::GET)
+                .path("/v1/models")
+                .header("Authorization", "Bearer test_key");
+            then.status(200).json_body_obj(&json!({
+                "object": "list",
+                "data": [
+                    { "id": "gpt-4o-mini", "object": "model", "created": 1721172741, "owned_by": "system" },
+                    { "id": "gpt-4o", "object": "model", "created": 1715367049, "owned_by": "system" }
+                ]
+            }));
+        });
+
+        let (gpt, path) = models_gpt(&server);
+        let ids = |models: Vec<ModelInfo>| -> Vec<String> {
+            models.into_iter().map(|model| model.id).collect()
+        };
+        assert_eq!(ids(gpt.models().await.unwrap()), ["gpt-4o", "gpt-4o-mini"]);
+        assert_eq!(ids(gpt.models().await.unwrap()), ["gpt-4o", "gpt-4o-mini"]);
+        mock.assert_hits(1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ask_classifies_missing_model_of_ollama() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::POST);
+            then.status(404)
+                .body(r#"{"error":"model \"llama9\" not found, try pulling it first"}"#);
+        });
+        let mut gpt = Gpt::mock(&server.url(""));
+        gpt.model = String::from("llama9");
+
+        let error = gpt.ask(show_calendar()).await.unwrap_err();
+
+        assert!(matches!(error, GptError::ModelNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_similar_model() {
+        let server = httpmock::MockServer::start();
+        server.mock(|when, then| {
+            when.method(httpmock::Method::GET).path("/v1/models");
+            then.status(200).json_body_obj(&json!({
+                "data": [{ "id": "gpt-4o" }, { "id": "o3-mini" }]
+            }));
+        });
+
+        let (mut gpt, path) = models_gpt(&server);
+        gpt.model = String::from("gpt4o");
+        assert_eq!(gpt.similar_model().await.as_deref(), Some("gpt-4o"));
+        gpt.model = String::from("llama3");
+        assert_eq!(gpt.similar_model().await, None);
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,322 @@
+use crate::error::GptError;
+use crate::profile;
+use reqwest::{Certificate, Identity, NoProxy, Proxy};
+use std::future::Future;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Settings of the HTTP client used to reach the API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    pub connect_timeout: Duration,
+    /// Limit for a single request, from connecting until the whole body is read.
+    pub timeout: Duration,
+    /// Proxy for every request. Without it `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` are used.
+    pub proxy: Option<String>,
+    /// Comma separated hosts reached without `proxy`.
+    pub no_proxy: Option<String>,
+    /// PEM bundle of certificate authorities trusted besides the system ones.
+    pub ca_bundle: Option<String>,
+    /// PEM certificate and PKCS#8 PEM key presented for mutual TLS.
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(120),
+            proxy: None,
+            no_proxy: None,
+            ca_bundle: None,
+            client_cert: None,
+            client_key: None,
+        }
+    }
+}
+
+impl HttpConfig {
+    /// Default settings overridden by `GPT_CONNECT_TIMEOUT` and `GPT_TIMEOUT` (in seconds),
+    /// `GPT_PROXY`, `NO_PROXY`, `GPT_CA_BUNDLE`, `GPT_CLIENT_CERT` and `GPT_CLIENT_KEY`, the `GPT_`
+    /// ones also from their `{NAME}_{PROFILE}` variants for the current `GPT_PROFILE`.
+    pub fn from_env() -> Self {
+        let default = HttpConfig::default();
+        let seconds = |name: &str| {
+            profile::var(name)
+                .ok()
+                .and_then(|value| value.parse::<f64>().ok())
+                .and_then(|value| Duration::try_from_secs_f64(value).ok())
+        };
+
+        HttpConfig {
+            connect_timeout: seconds("GPT_CONNECT_TIMEOUT").unwrap_or(default.connect_timeout),
+            timeout: seconds("GPT_TIMEOUT").unwrap_or(default.timeout),
+            proxy: profile::var("GPT_PROXY").ok(),
+            no_proxy: std::env::var("NO_PROXY")
+                .or_else(|_| std::env::var("no_proxy"))
+                .ok(),
+            ca_bundle: profile::var("GPT_CA_BUNDLE").ok(),
+            client_cert: profile::var("GPT_CLIENT_CERT").ok(),
+            client_key: profile::var("GPT_CLIENT_KEY").ok(),
+        }
+    }
+
+    pub fn client(&self) -> Result<reqwest::Client, GptError> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout);
+
+        if let Some(proxy) = &self.proxy {
+            let proxy = Proxy::all(proxy)
+                .map_err(|e| GptError::Config(format!("Error: invalid GPT_PROXY {proxy}: {e}")))?
+                .no_proxy(self.no_proxy.as_deref().and_then(NoProxy::from_string));
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(path) = &self.ca_bundle {
+            let certificates = Certificate::from_pem_bundle(&read_file("GPT_CA_BUNDLE", path)?)
+                .map_err(|e| {
+                    GptError::Config(format!("Error: invalid GPT_CA_BUNDLE {path}: {e}"))
+                })?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = Identity::from_pkcs8_pem(
+                    &read_file("GPT_CLIENT_CERT", cert)?,
+                    &read_file("GPT_CLIENT_KEY", key)?,
+                )
+                .map_err(|e| GptError::Config(format!("Error: invalid client certificate: {e}")))?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(GptError::Config(String::from(
+                    "Error: GPT_CLIENT_CERT and GPT_CLIENT_KEY have to be set together.",
+                )));
+            }
+        }
+
+        builder
+            .build()
+            .map_err(|e| GptError::Config(format!("Error: can't create HTTP client: {e}")))
+    }
+}
+
+fn read_file(variable: &str, path: &str) -> Result<Vec<u8>, GptError> {
+    std::fs::read(path)
+        .map_err(|e| GptError::Config(format!("Error: can't read {variable} {path}: {e}")))
+}
+
+/// Drops `request` and returns `GptError::Cancelled` when `cancel` completes first.
+pub async fn cancellable<T>(
+    request: impl Future<Output = T>,
+    cancel: impl Future,
+) -> Result<T, GptError> {
+    tokio::select! {
+        output = request => Ok(output),
+        _ = cancel => Err(GptError::Cancelled),
+    }
+}
+
+/// Set by the SIGINT handler of [`InterruptGuard`].
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Guards alive, and the disposition of SIGINT before the first of them.
+static GUARDS: Mutex<(usize, Option<libc::sigaction>)> = Mutex::new((0, None));
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Catches Ctrl-C while alive, then gives SIGINT back its previous disposition, so Ctrl-C at
+/// later prompts or while a command runs still ends the process.
+struct InterruptGuard;
+
+impl InterruptGuard {
+    fn install() -> Self {
+        let mut guards = GUARDS.lock().unwrap();
+        if guards.0 == 0 {
+            INTERRUPTED.store(false, Ordering::SeqCst);
+            // SAFETY: both structs are fully initialized, zeroed `sa_mask` is the empty set.
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_interrupt as extern "C" fn(libc::c_int) as usize;
+                let mut previous: libc::sigaction = std::mem::zeroed();
+                libc::sigaction(libc::SIGINT, &action, &mut previous);
+                guards.1 = Some(previous);
+            }
+        }
+        guards.0 += 1;
+        InterruptGuard
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        let mut guards = GUARDS.lock().unwrap();
+        guards.0 -= 1;
+        if guards.0 == 0
+            && let Some(previous) = guards.1.take()
+        {
+            // SAFETY: `previous` was filled in by `sigaction` in `install`.
+            unsafe {
+                libc::sigaction(libc::SIGINT, &previous, std::ptr::null_mut());
+            }
+        }
+    }
+}
+
+/// Completes once Ctrl-C was pressed while an [`InterruptGuard`] is alive.
+async fn interrupted() {
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Like [`cancellable`], cancelled by Ctrl-C.
+pub async fn interruptible<T>(request: impl Future<Output = T>) -> Result<T, GptError> {
+    let _guard = InterruptGuard::install();
+    cancellable(request, interrupted()).await
+}
+
+#[cfg(test)]
+mod tests {
+    mod tls_server;
+
+    use super::*;
+    use tls_server::{Authority, serve};
+
+    const ANSWER: &str = r#"{"answer":"ok"}"#;
+
+    fn write_pem(contents: &[u8]) -> String {
+        let path = format!("/tmp/.gpt-cli-{}.pem", rand::random::<u64>());
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    async fn request(config: &HttpConfig, url: &str) -> Result<String, String> {
+        let client = config.client().map_err(|e| e.to_string())?;
+        let response = client.post(url).send().await.map_err(|e| e.to_string())?;
+        response.text().await.map_err(|e| e.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_ca_bundle_trusts_private_authority() {
+        let authority = Authority::new();
+        let port = serve(&authority, false, ANSWER);
+        let url = format!("https://localhost:{port}/v1/chat/completions");
+
+        assert!(request(&HttpConfig::default(), &url).await.is_err());
+
+        let ca_bundle = write_pem(&authority.ca.cert);
+        let config = HttpConfig {
+            ca_bundle: Some(ca_bundle.clone()),
+            ..HttpConfig::default()
+        };
+        assert_eq!(request(&config, &url).await.unwrap(), ANSWER);
+        std::fs::remove_file(ca_bundle).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate() {
+        let authority = Authority::new();
+        let port = serve(&authority, true, ANSWER);
+        let url = format!("https://localhost:{port}/v1/chat/completions");
+        let ca_bundle = write_pem(&authority.ca.cert);
+        let client_cert = write_pem(&authority.client.cert);
+        let client_key = write_pem(&authority.client.key);
+
+        let without_identity = HttpConfig {
+            ca_bundle: Some(ca_bundle.clone()),
+            ..HttpConfig::default()
+        };
+        assert!(request(&without_identity, &url).await.is_err());
+
+        let with_identity = HttpConfig {
+            client_cert: Some(client_cert.clone()),
+            client_key: Some(client_key.clone()),
+            ..without_identity
+        };
+        assert_eq!(request(&with_identity, &url).await.unwrap(), ANSWER);
+        for path in [ca_bundle, client_cert, client_key] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_client_certificate_without_key() {
+        let config = HttpConfig {
+            client_cert: Some(String::from("/tmp/cert.pem")),
+            ..HttpConfig::default()
+        };
+        assert_eq!(
+            config.client().unwrap_err().to_string(),
+            "Error: GPT_CLIENT_CERT and GPT_CLIENT_KEY have to be set together."
+        );
+    }
+
+    #[test]
+    fn test_missing_ca_bundle() {
+        let config = HttpConfig {
+            ca_bundle: Some(String::from("/nonexistent/ca.pem")),
+            ..HttpConfig::default()
+        };
+        assert!(matches!(config.client(), Err(GptError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let proxy = httpmock::MockServer::start();
+        let mock = proxy.mock(|when, then| {
+            when.path("/v1/chat/completions");
+            then.status(200).body(ANSWER);
+        });
+        let config = HttpConfig {
+            proxy: Some(proxy.url("")),
+            ..HttpConfig::default()
+        };
+
+        let answer = request(&config, "http://api.gpt-cli.invalid/v1/chat/completions").await;
+
+        assert_eq!(answer.unwrap(), ANSWER);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_no_proxy() {
+        let proxy = httpmock::MockServer::start();
+        let mock = proxy.mock(|when, then| {
+            when.path("/v1/chat/completions");
+            then.status(200).body(ANSWER);
+        });
+        let config = HttpConfig {
+            proxy: Some(proxy.url("")),
+            no_proxy: Some(String::from("localhost,.gpt-cli.invalid")),
+            ..HttpConfig::default()
+        };
+
+        let answer = request(&config, "http://api.gpt-cli.invalid/v1/chat/completions").await;
+
+        assert!(answer.is_err());
+        mock.assert_hits(0);
+    }
+
+    #[tokio::test]
+    async fn test_cancellable_completes() {
+        let result = cancellable(async { 42 }, std::future::pending::<()>()).await;
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_cancellable_cancelled() {
+        let (cancel, cancelled) = tokio::sync::oneshot::channel::<()>();
+        cancel.send(()).unwrap();
+        let result = cancellable(std::future::pending::<u8>(), cancelled).await;
+        assert!(matches!(result, Err(GptError::Cancelled)));
+    }
+}
@@ -0,0 +1,131 @@
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use openssl::x509::{X509, X509NameBuilder};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+
+/// PEM encoded certificate with its PKCS#8 key.
+pub struct Credentials {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+    x509: X509,
+    pkey: PKey<Private>,
+}
+
+fn credentials(name: &str, issuer: Option<&Credentials>) -> Credentials {
+    let pkey = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_text("CN", name).unwrap();
+    let subject = subject.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+    builder
+        .set_serial_number(&serial.to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder.set_pubkey(&pkey).unwrap();
+    match issuer {
+        Some(issuer) => {
+            builder.set_issuer_name(issuer.x509.subject_name()).unwrap();
+            let san = SubjectAlternativeName::new()
+                .dns("localhost")
+                .build(&builder.x509v3_context(Some(&issuer.x509), None))
+                .unwrap();
+            builder.append_extension(san).unwrap();
+            builder.sign(&issuer.pkey, MessageDigest::sha256()).unwrap();
+        }
+        None => {
+            builder.set_issuer_name(&subject).unwrap();
+            builder
+                .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                .unwrap();
+            builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+        }
+    }
+    let x509 = builder.build();
+
+    Credentials {
+        cert: x509.to_pem().unwrap(),
+        key: pkey.private_key_to_pem_pkcs8().unwrap(),
+        x509,
+        pkey,
+    }
+}
+
+/// Certificate authority signing both the server and the client certificate.
+pub struct Authority {
+    pub ca: Credentials,
+    pub server: Credentials,
+    pub client: Credentials,
+}
+
+impl Authority {
+    pub fn new() -> Self {
+        let ca = credentials("gpt-cli test CA", None);
+        let server = credentials("localhost", Some(&ca));
+        let client = credentials("gpt-cli test client", Some(&ca));
+        Authority { ca, server, client }
+    }
+}
+
+/// Serves `body` as the answer to every request over TLS on `https://localhost:{port}`,
+/// rejecting clients without a certificate signed by the authority when `require_client_cert`.
+pub fn serve(authority: &Authority, require_client_cert: bool, body: &'static str) -> u16 {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor.set_certificate(&authority.server.x509).unwrap();
+    acceptor.set_private_key(&authority.server.pkey).unwrap();
+    if require_client_cert {
+        acceptor
+            .cert_store_mut()
+            .add_cert(authority.ca.x509.clone())
+            .unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    let acceptor = acceptor.build();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = acceptor.accept(stream.unwrap()) else {
+                continue;
+            };
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':')
+                    && name.eq_ignore_ascii_case("content-length")
+                {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+            let mut request_body = vec![0u8; content_length];
+            let _ = reader.read_exact(&mut request_body);
+            let _ = write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    });
+    port
+}
@@ -1,45 +1,85 @@
 extern crate core;
 
+mod agent;
+mod api_key;
+mod budget;
 mod cache;
+mod cassette;
+mod command_response;
+mod commands;
+mod compare;
 mod decompose;
+mod error;
+mod execute;
+mod fix;
+mod gemini;
 mod get_postprocess_action;
 mod gpt3;
+mod http;
+mod models;
+mod options;
+mod profile;
+mod provider;
+mod retry;
+mod sampling;
 mod should_exit;
+mod spinner;
+mod tools;
+mod trace;
+mod usage;
 
-use inquire::Confirm;
+use inquire::{Confirm, Select};
 
 use colored::*;
 use std::env;
 
-use crate::get_postprocess_action::{get_postprocess_action, PostprocessAction};
+use crate::command_response::CommandResponse;
+use crate::error::GptError;
+use crate::execute::Execution;
+use crate::get_postprocess_action::{
+    PostprocessAction, get_command_postprocess_action, get_postprocess_action,
+};
 use crate::gpt3::Gpt3Message;
-use crate::should_exit::{should_exit, ShouldExit};
-use std::process::{Command, Stdio};
+use crate::options::parse_options;
+use crate::should_exit::{ShouldExit, should_exit};
 use tokio::runtime::Runtime;
 
-fn postprocess(answer_text: &String) {
-    let action = get_postprocess_action(answer_text);
+/// Presents the answer, or the command of a structured answer, and runs it when confirmed.
+/// Returns how the command ended, when it was executed.
+fn postprocess(answer_text: &String, command: Option<&CommandResponse>) -> Option<Execution> {
+    let (action, answer_text, description) = match command {
+        Some(command) => (
+            get_command_postprocess_action(command),
+            &command.command,
+            command.describe(),
+        ),
+        None => (
+            get_postprocess_action(answer_text),
+            answer_text,
+            answer_text.green().to_string(),
+        ),
+    };
 
     match action {
         PostprocessAction::Confirm => {
-            let ans = Confirm::new(&format!("Execute.:\n\n{}\n\n", answer_text.green()))
+            let ans = Confirm::new(&format!("Execute.:\n\n{}\n\n", description))
                 .with_default(true)
                 .with_help_message("Pressing enter you confirm execution of this command")
                 .prompt();
 
             match ans {
                 Ok(true) => {
-                    let (command_name, command_args) = decompose::decompose(answer_text);
+                    let (command_name, command_args) = match command {
+                        Some(command) => command.program(),
+                        None => decompose::decompose(answer_text),
+                    };
 
-                    let mut child = Command::new(command_name)
-                        .args(command_args)
-                        .stdin(Stdio::inherit())
-                        .stdout(Stdio::inherit())
-                        .stderr(Stdio::inherit())
-                        .spawn()
-                        .expect("Failed to execute command");
-
-                    child.wait().expect("Failed to wait for command");
+                    return Some(execute::run(
+                        answer_text,
+                        &command_name,
+                        &command_args,
+                        false,
+                    ));
                 }
                 Ok(false) => println!("That's too bad, I've heard great things about it."),
                 Err(_) => println!("Error with questionnaire, try again later"),
@@ -61,6 +101,27 @@ fn postprocess(answer_text: &String) {
             println!("{}", answer_text);
         }
     }
+    None
+}
+
+/// Runs the failed command again with its stderr captured, when the user agrees.
+fn capture_stderr(execution: Execution, command: Option<&CommandResponse>) -> Execution {
+    if !fix::confirm_rerun(&execution.command) {
+        return execution;
+    }
+    let (program, args) = match command {
+        Some(command) => command.program(),
+        None => decompose::decompose(&execution.command),
+    };
+    execute::run(&execution.command, &program, &args, true)
+}
+
+fn ask_for_fix() -> bool {
+    Confirm::new("The command failed — ask for a fix?")
+        .with_default(true)
+        .with_help_message("The command and its error are sent to the model")
+        .prompt()
+        .unwrap_or(false)
 }
 
 fn exit_with_messages_if_required(should_exit: ShouldExit) {
@@ -82,48 +143,234 @@ fn exit_with_messages_if_required(should_exit: ShouldExit) {
     }
 }
 
+/// Prints the error with a hint on stderr and exits with the code of its kind.
+fn exit_with_error(error: GptError) -> ! {
+    exit_with_suggestion(error, None)
+}
+
+/// Like [`exit_with_error`], proposing `model` in place of a model that doesn't exist.
+fn exit_with_suggestion(error: GptError, model: Option<String>) -> ! {
+    eprintln!("{}", error.to_string().red());
+    if let Some(hint) = error.hint() {
+        eprintln!("{}", hint);
+    }
+    if let Some(model) = model {
+        eprintln!("Did you mean {}? Set GPT_MODEL={model}.", model.green());
+    }
+    std::process::exit(error.exit_code());
+}
+
+/// Runs the agent loop and exits, successfully only when the model reached the goal.
+async fn run_agent(client: &gpt3::Gpt, goal: &str) -> ! {
+    match agent::run(client, goal).await {
+        Err(error) => exit_with_error(error),
+        Ok(transcript) => {
+            let (message, code) = match transcript.outcome {
+                agent::Outcome::Done => (
+                    transcript
+                        .summary
+                        .unwrap_or_else(|| String::from("Goal reached."))
+                        .green(),
+                    0,
+                ),
+                agent::Outcome::Stopped => ("Agent stopped.".yellow(), 1),
+                agent::Outcome::StepLimit => (
+                    format!(
+                        "Step limit of {} reached, raise GPT_AGENT_MAX_STEPS to go further.",
+                        agent::max_steps()
+                    )
+                    .yellow(),
+                    1,
+                ),
+            };
+            println!("{message}");
+            std::process::exit(code);
+        }
+    }
+}
+
+/// Lets the user pick one of distinct candidate commands, shown by their command when structured.
+fn choose_candidate(mut answers: Vec<String>, structured: bool) -> String {
+    if structured {
+        let mut commands: Vec<String> = vec![];
+        answers.retain(|answer| {
+            let command = CommandResponse::from_answer(answer).command;
+            let distinct = !commands.contains(&command);
+            if distinct {
+                commands.push(command);
+            }
+            distinct
+        });
+    }
+    if answers.len() == 1 {
+        return answers.into_iter().next().unwrap();
+    }
+    let labels = answers
+        .iter()
+        .map(|answer| match structured {
+            true => CommandResponse::from_answer(answer).command,
+            false => answer.clone(),
+        })
+        .collect();
+    match Select::new("Choose a command:", labels).raw_prompt() {
+        Ok(choice) => answers.swap_remove(choice.index),
+        Err(_) => {
+            println!("Error with questionnaire, try again later");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Asks every model of `list` at once, shows their answers side by side and lets the user pick one.
+async fn compare_models(
+    client: &gpt3::Gpt,
+    list: &str,
+    messages: &[Gpt3Message],
+    structured: bool,
+) -> String {
+    let clients = client.for_models(list);
+    let comparisons = compare::ask_all(&clients, messages).await;
+    for line in compare::table(&comparisons, structured, &usage::Pricing::from_env()) {
+        eprintln!("{line}");
+    }
+    if comparisons
+        .iter()
+        .all(|comparison| comparison.result.is_err())
+    {
+        let error = comparisons
+            .into_iter()
+            .find_map(|comparison| comparison.result.err());
+        exit_with_error(error.unwrap_or(GptError::Config(String::from(
+            "Error: --compare expects a comma separated list of models.",
+        ))));
+    }
+    compare::choose(&comparisons, structured).unwrap_or_else(|| {
+        println!("Error with questionnaire, try again later");
+        std::process::exit(1);
+    })
+}
+
+/// Tokens and estimated cost of a single answer, for `--usage`.
+fn print_usage(response: &gpt3::Gpt3Response) {
+    let usage = &response.usage;
+    if response.cached {
+        eprintln!("Answered from cache, no tokens used.");
+        return;
+    }
+    let cost = usage::Pricing::from_env()
+        .cost(
+            &response.model,
+            usage.prompt_tokens.max(0) as u64,
+            usage.completion_tokens.max(0) as u64,
+        )
+        .map_or_else(|| String::from("unknown"), |cost| format!("${cost:.6}"));
+    eprintln!(
+        "{}",
+        format!(
+            "Tokens: {} prompt + {} completion = {} total, estimated cost: {cost}",
+            usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+        )
+        .dimmed()
+    );
+}
+
 async fn async_main() {
-    let args: Vec<String> = env::args().skip(1).collect();
+    let (options, args) = match parse_options(&env::args().skip(1).collect::<Vec<String>>()) {
+        Ok(parsed) => parsed,
+        Err(error) => {
+            eprintln!("{}", error.red());
+            std::process::exit(1);
+        }
+    };
     exit_with_messages_if_required(should_exit(&args));
+    if let Some(command_result) = commands::run(&args) {
+        exit_with_messages_if_required(command_result);
+    }
 
-    let content = args.join(" ");
+    let content = if args == ["fix"] {
+        match fix::prepare() {
+            Ok(content) => content,
+            Err(error) => {
+                eprintln!("{}", error.red());
+                std::process::exit(1);
+            }
+        }
+    } else {
+        args.join(" ")
+    };
     let rt = Runtime::new().unwrap();
 
     rt.block_on(async {
-        let client = gpt3::Gpt::new(Some(false), None);
-        let response = client
-            .ask(vec![
-                Gpt3Message {
-                    content: gpt3::Gpt::get_system_prompt(),
-                    role: String::from("system"),
-                },
-                Gpt3Message {
-                    role: String::from("user"),
-                    content,
-                },
-            ])
-            .await;
-
-        match response {
-            Err(error) => {
-                let mut messages = vec![error.red()];
-                if error == *"Error: OPENAI_API_KEY environment variable is not defined." {
-                    messages.push(
-                        "Please set the OPENAI_API_KEY environment variable to your OpenAI API key."
-                            .normal(),
-                    );
+        let candidates = options.candidates.unwrap_or(1);
+        let client = gpt3::Gpt::new(Some(false), None)
+            .with_offline(options.offline)
+            .with_ledger(usage::Ledger::default_path())
+            .with_force_budget(options.force_budget)
+            .with_candidates(candidates)
+            .with_sampling(&options.sampling)
+            .with_structured(options.structured)
+            .with_tools(options.tools)
+            .with_verbosity(options.verbosity);
+        let structured = client.is_structured();
+        if options.agent {
+            run_agent(&client, &content).await;
+        }
+
+        let mut messages = vec![
+            Gpt3Message {
+                content: gpt3::Gpt::get_system_prompt(),
+                role: String::from("system"),
+            },
+            Gpt3Message {
+                role: String::from("user"),
+                content,
+            },
+        ];
+
+        // Models are compared for the first answer, fixes are asked from the configured one.
+        let mut compare = options.compare.as_deref();
+        loop {
+            let answer_text = match compare.take() {
+                Some(list) => compare_models(&client, list, &messages, structured).await,
+                None => {
+                    let data = match client.ask(messages.clone()).await {
+                        Err(error @ GptError::ModelNotFound(_)) => {
+                            let model = client.similar_model().await;
+                            exit_with_suggestion(error, model)
+                        }
+                        Err(error) => exit_with_error(error),
+                        Ok(data) => data,
+                    };
+                    if options.usage {
+                        print_usage(&data);
+                    }
+                    if candidates > 1 && !data.cached {
+                        let answer_text = choose_candidate(data.distinct_answers(), structured);
+                        if let Err(error) = client.prefer(&messages, &data, &answer_text) {
+                            eprintln!("{}", error.to_string().red());
+                        }
+                        answer_text
+                    } else {
+                        let choice = data.choices.first().expect("No choice in response");
+                        choice.message.content.clone()
+                    }
                 }
-                exit_with_messages_if_required(ShouldExit {
-                    is_error: true,
-                    exit: true,
-                    messages,
-                });
-            }
-            Ok(data) => {
-                let choice = data.choices.first().expect("No choice in response");
-                let answer_text = &choice.message.content;
+            };
 
-                postprocess(answer_text);
+            let command = structured.then(|| CommandResponse::from_answer(&answer_text));
+            match postprocess(&answer_text, command.as_ref()) {
+                Some(execution) if execution.failed() && ask_for_fix() => {
+                    let execution = capture_stderr(execution, command.as_ref());
+                    messages.push(Gpt3Message {
+                        role: String::from("assistant"),
+                        content: answer_text,
+                    });
+                    messages.push(Gpt3Message {
+                        role: String::from("user"),
+                        content: execution.fix_request(),
+                    });
+                }
+                _ => break,
             }
         }
     });
@@ -0,0 +1,411 @@
+use crate::error::GptError;
+use crate::provider::Provider;
+use crate::usage::Pricing;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, write};
+
+/// Seconds a listing of models is reused before it's fetched again.
+pub const MAX_AGE: u64 = 24 * 60 * 60;
+
+/// Model offered by the provider.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub id: String,
+    /// Tokens of prompt and answer together, when the provider tells.
+    pub context_length: Option<u64>,
+    /// USD per million prompt and completion tokens, from the provider or [`Pricing`].
+    pub pricing: Option<(f64, f64)>,
+}
+
+/// `/v1/models` of OpenAI, OpenRouter, vLLM and other compatible servers.
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ListedModel>,
+}
+
+#[derive(Deserialize)]
+struct ListedModel {
+    id: String,
+    /// OpenRouter.
+    context_length: Option<u64>,
+    /// vLLM.
+    max_model_len: Option<u64>,
+    /// OpenRouter, USD per token as strings, negative when it varies.
+    pricing: Option<ListedPricing>,
+}
+
+#[derive(Deserialize)]
+struct ListedPricing {
+    prompt: Option<String>,
+    completion: Option<String>,
+}
+
+impl ListedPricing {
+    fn per_million(&self) -> Option<(f64, f64)> {
+        let rate = |price: &Option<String>| {
+            price
+                .as_deref()
+                .and_then(|price| price.parse::<f64>().ok())
+                .filter(|price| *price >= 0.0)
+                .map(|price| price * 1_000_000.0)
+        };
+        Some((rate(&self.prompt)?, rate(&self.completion)?))
+    }
+}
+
+/// `models.list` of Gemini.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiModelList {
+    #[serde(default)]
+    models: Vec<GeminiModel>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiModel {
+    name: String,
+    input_token_limit: Option<u64>,
+    #[serde(default)]
+    supported_generation_methods: Vec<String>,
+}
+
+/// Models of a listing returned by `provider`, sorted by id, priced by `pricing` when the
+/// listing has no prices.
+pub fn parse(
+    provider: &Provider,
+    body: &str,
+    pricing: &Pricing,
+) -> Result<Vec<ModelInfo>, GptError> {
+    let malformed = |e: serde_json::Error| GptError::MalformedResponse(e.to_string());
+    let mut models: Vec<ModelInfo> = match provider {
+        Provider::Gemini { .. } => serde_json::from_str::<GeminiModelList>(body)
+            .map_err(malformed)?
+            .models
+            .into_iter()
+            .filter(|model| {
+                model
+                    .supported_generation_methods
+                    .iter()
+                    .any(|method| method == "generateContent")
+            })
+            .map(|model| ModelInfo {
+                id: model
+                    .name
+                    .strip_prefix("models/")
+                    .unwrap_or(&model.name)
+                    .to_string(),
+                context_length: model.input_token_limit,
+                pricing: None,
+            })
+            .collect(),
+        _ => serde_json::from_str::<ModelList>(body)
+            .map_err(malformed)?
+            .data
+            .into_iter()
+            .map(|model| ModelInfo {
+                context_length: model.context_length.or(model.max_model_len),
+                pricing: model.pricing.as_ref().and_then(ListedPricing::per_million),
+                id: model.id,
+            })
+            .collect(),
+    };
+    for model in &mut models {
+        model.pricing = model.pricing.or_else(|| pricing.rates(&model.id));
+    }
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(models)
+}
+
+/// Models whose id contains `filter`, ignoring case.
+pub fn filter<'a>(models: &'a [ModelInfo], filter: &str) -> Vec<&'a ModelInfo> {
+    let filter = filter.to_lowercase();
+    models
+        .iter()
+        .filter(|model| model.id.to_lowercase().contains(&filter))
+        .collect()
+}
+
+/// Aligned columns of ids, context lengths and prices.
+pub fn table(models: &[&ModelInfo]) -> Vec<String> {
+    let price = |price: f64| format!("${price:.2}");
+    let rows: Vec<[String; 4]> = models
+        .iter()
+        .map(|model| {
+            [
+                model.id.clone(),
+                model
+                    .context_length
+                    .map_or_else(|| String::from("-"), |length| length.to_string()),
+                model
+                    .pricing
+                    .map_or_else(|| String::from("-"), |(prompt, _)| price(prompt)),
+                model
+                    .pricing
+                    .map_or_else(|| String::from("-"), |(_, completion)| price(completion)),
+            ]
+        })
+        .collect();
+    let header = [
+        String::from("Model"),
+        String::from("Context"),
+        String::from("Prompt/1M"),
+        String::from("Completion/1M"),
+    ];
+
+    let mut widths = header.clone().map(|cell| cell.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    std::iter::once(&header)
+        .chain(&rows)
+        .map(|row| {
+            row.iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect()
+}
+
+/// Edits turning `a` into `b`.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Listed model closest to `model`, also comparing the part after the vendor prefix of ids
+/// like `openai/gpt-4o`. `None` when nothing is close enough to be a typo.
+pub fn similar<'a>(model: &str, models: &'a [ModelInfo]) -> Option<&'a str> {
+    let model = model.to_lowercase();
+    let limit = (model.chars().count() / 3).max(2);
+    models
+        .iter()
+        .map(|listed| {
+            let id = listed.id.to_lowercase();
+            let name = id.rsplit('/').next().unwrap_or(&id);
+            (
+                levenshtein(&model, &id).min(levenshtein(&model, name)),
+                &listed.id,
+            )
+        })
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, id)| id.as_str())
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Listing {
+    /// Unix time of the request.
+    fetched: u64,
+    models: Vec<ModelInfo>,
+}
+
+/// Listings of every endpoint asked, saved in `GPT_MODELS_PATH` or `~/.gpt-models.json`.
+#[derive(Default, Serialize, Deserialize)]
+pub struct ModelsCache {
+    listings: BTreeMap<String, Listing>,
+}
+
+impl ModelsCache {
+    pub fn default_path() -> String {
+        std::env::var("GPT_MODELS_PATH")
+            .unwrap_or_else(|_| format!("{}/.gpt-models.json", dirs::home_dir().unwrap().display()))
+    }
+
+    pub fn load(path: &str) -> Self {
+        read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        write(path, serde_json::to_string(self).unwrap())
+    }
+
+    /// Models listed by `url` at most `max_age` seconds before `now`.
+    pub fn get(&self, url: &str, now: u64, max_age: u64) -> Option<Vec<ModelInfo>> {
+        self.listings
+            .get(url)
+            .filter(|listing| now.saturating_sub(listing.fetched) <= max_age)
+            .map(|listing| listing.models.clone())
+    }
+
+    pub fn set(&mut self, url: &str, now: u64, models: &[ModelInfo]) {
+        self.listings.insert(
+            url.to_string(),
+            Listing {
+                fetched: now,
+                models: models.to_vec(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(id: &str) -> ModelInfo {
+        ModelInfo {
+            id: id.to_string(),
+            context_length: None,
+            pricing: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_openrouter() {
+        let provider = Provider::OpenAi {
+            host: String::from("https://openrouter.ai/api"),
+        };
+        let body = r#"{"data": [
+            { "id": "openai/gpt-4o", "context_length": 128000, "pricing": { "prompt": "0.0000025", "completion": "0.00001" } },
+            { "id": "openrouter/auto", "context_length": 2000000, "pricing": { "prompt": "-1", "completion": "-1" } },
+            { "id": "meta-llama/llama-3.1-8b-instruct", "max_model_len": 131072 }
+        ]}"#;
+        let models = parse(&provider, body, &Pricing::default()).unwrap();
+        assert_eq!(
+            models,
+            vec![
+                ModelInfo {
+                    id: String::from("meta-llama/llama-3.1-8b-instruct"),
+                    context_length: Some(131072),
+                    pricing: None,
+                },
+                ModelInfo {
+                    id: String::from("openai/gpt-4o"),
+                    context_length: Some(128000),
+                    pricing: Some((2.5, 10.0)),
+                },
+                ModelInfo {
+                    id: String::from("openrouter/auto"),
+                    context_length: Some(2000000),
+                    pricing: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_openai_uses_known_prices() {
+        let provider = Provider::OpenAi {
+            host: String::from(Provider::OPEN_AI_HOST),
+        };
+        let body = r#"{"object": "list", "data": [
+            { "id": "gpt-4o-mini", "object": "model", "created": 1721172741, "owned_by": "system" },
+            { "id": "whisper-1", "object": "model", "created": 1677532384, "owned_by": "openai-internal" }
+        ]}"#;
+        let models = parse(&provider, body, &Pricing::default()).unwrap();
+        assert_eq!(models[0].pricing, Some((0.15, 0.60)));
+        assert_eq!(models[1].pricing, None);
+    }
+
+    #[test]
+    fn test_parse_gemini() {
+        let provider = Provider::Gemini {
+            host: String::from(Provider::GEMINI_HOST),
+            model: String::from("gemini-2.0-flash"),
+        };
+        let body = r#"{"models": [
+            { "name": "models/gemini-2.0-flash", "inputTokenLimit": 1048576, "supportedGenerationMethods": ["generateContent", "countTokens"] },
+            { "name": "models/text-embedding-004", "inputTokenLimit": 2048, "supportedGenerationMethods": ["embedContent"] }
+        ]}"#;
+        let models = parse(&provider, body, &Pricing::default()).unwrap();
+        assert_eq!(
+            models,
+            vec![ModelInfo {
+                id: String::from("gemini-2.0-flash"),
+                context_length: Some(1048576),
+                pricing: Some((0.10, 0.40)),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_filter_and_table() {
+        let models = [
+            ModelInfo {
+                id: String::from("openai/gpt-4o"),
+                context_length: Some(128000),
+                pricing: Some((2.5, 10.0)),
+            },
+            model("meta-llama/llama-3.1-8b-instruct"),
+        ];
+        let found = filter(&models, "GPT");
+        assert_eq!(
+            table(&found),
+            vec![
+                "Model          Context  Prompt/1M  Completion/1M",
+                "openai/gpt-4o  128000   $2.50      $10.00",
+            ]
+        );
+        assert_eq!(
+            table(&filter(&models, "llama"))[1],
+            "meta-llama/llama-3.1-8b-instruct  -        -          -"
+        );
+    }
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("gpt-4o", "gpt-4o"), 0);
+        assert_eq!(levenshtein("gtp-4o", "gpt-4o"), 2);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_similar() {
+        let models = [
+            model("gpt-4o"),
+            model("gpt-4o-mini"),
+            model("openai/o3-mini"),
+            model("whisper-1"),
+        ];
+        assert_eq!(similar("gpt4o", &models), Some("gpt-4o"));
+        assert_eq!(similar("gpt-4o-mnii", &models), Some("gpt-4o-mini"));
+        assert_eq!(similar("o3-mini", &models), Some("openai/o3-mini"));
+        assert_eq!(similar("llama3", &models), None);
+    }
+
+    #[test]
+    fn test_cache() {
+        let path = std::env::temp_dir()
+            .join(format!("gpt-models-{}.json", rand::random::<u64>()))
+            .display()
+            .to_string();
+        let url = "https://api.openai.com/v1/models";
+        let mut cache = ModelsCache::load(&path);
+        assert_eq!(cache.get(url, 1000, MAX_AGE), None);
+
+        cache.set(url, 1000, &[model("gpt-4o")]);
+        cache.save(&path).unwrap();
+        let cache = ModelsCache::load(&path);
+        assert_eq!(
+            cache.get(url, 1000 + MAX_AGE, MAX_AGE),
+            Some(vec![model("gpt-4o")])
+        );
+        assert_eq!(cache.get(url, 1001 + MAX_AGE, MAX_AGE), None);
+        assert_eq!(
+            cache.get("http://localhost:8080/v1/models", 1000, MAX_AGE),
+            None
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+use crate::sampling::{Sampling, parse_stop};
+use std::str::FromStr;
+
+/// Flags given before the task description, eg.: `p --offline show calendar`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    pub offline: bool,
+    /// Print tokens and estimated cost of the answer on stderr.
+    pub usage: bool,
+    /// Send the request even when a budget is exceeded.
+    pub force_budget: bool,
+    /// Number of answers to choose from, eg.: `--candidates 3`.
+    pub candidates: Option<u32>,
+    /// Overrides of `GPT_TEMPERATURE` and other sampling variables.
+    pub sampling: Sampling,
+    /// Ask for a command with its explanation and risks instead of free text.
+    pub structured: bool,
+    /// Let the model inspect the system with read-only tools.
+    pub tools: bool,
+    /// Reach the goal with several commands, each confirmed before it runs.
+    pub agent: bool,
+    /// Models asked at once to choose from, eg.: `--compare gpt-4o,gemini:gemini-2.0-flash`.
+    pub compare: Option<String>,
+    /// `-v` traces HTTP requests on stderr, `-vv` with their bodies.
+    pub verbosity: u8,
+}
+
+const MAX_CANDIDATES: u32 = 10;
+
+/// Flags followed by a value, as `--flag value` or `--flag=value`.
+const VALUE_FLAGS: [&str; 9] = [
+    "--candidates",
+    "--compare",
+    "--temperature",
+    "--top-p",
+    "--max-tokens",
+    "--seed",
+    "--stop",
+    "--presence-penalty",
+    "--frequency-penalty",
+];
+
+/// Splits leading flags from the words of the task description.
+pub fn parse_options(args: &[String]) -> Result<(Options, Vec<String>), String> {
+    let mut options = Options::default();
+    let mut rest = args.iter().peekable();
+
+    while let Some(arg) = rest.peek().copied() {
+        match arg.as_str() {
+            "--offline" => options.offline = true,
+            "--usage" => options.usage = true,
+            "--force-budget" => options.force_budget = true,
+            "--structured" => options.structured = true,
+            "--tools" => options.tools = true,
+            "--agent" => options.agent = true,
+            "-v" | "--verbose" => options.verbosity += 1,
+            "-vv" => options.verbosity += 2,
+            _ => {
+                let (flag, inline_value) = match arg.split_once('=') {
+                    Some((flag, value)) => (flag, Some(value)),
+                    None => (arg.as_str(), None),
+                };
+                if !VALUE_FLAGS.contains(&flag) {
+                    break;
+                }
+                let value = match inline_value {
+                    Some(value) => value,
+                    None => {
+                        rest.next();
+                        rest.peek()
+                            .ok_or_else(|| format!("{flag} expects a value."))?
+                    }
+                };
+                set_value(&mut options, flag, value)?;
+            }
+        }
+        rest.next();
+    }
+
+    Ok((options, rest.cloned().collect()))
+}
+
+fn set_value(options: &mut Options, flag: &str, value: &str) -> Result<(), String> {
+    let sampling = &mut options.sampling;
+    match flag {
+        "--candidates" => options.candidates = Some(parse_candidates(value)?),
+        "--compare" => options.compare = Some(value.to_string()),
+        "--temperature" => sampling.temperature = Some(parse(flag, value)?),
+        "--top-p" => sampling.top_p = Some(parse(flag, value)?),
+        "--max-tokens" => sampling.max_tokens = Some(parse(flag, value)?),
+        "--seed" => sampling.seed = Some(parse(flag, value)?),
+        "--stop" => sampling.stop.extend(parse_stop(value)),
+        "--presence-penalty" => sampling.presence_penalty = Some(parse(flag, value)?),
+        "--frequency-penalty" => sampling.frequency_penalty = Some(parse(flag, value)?),
+        _ => unreachable!("{flag} is not a value flag"),
+    }
+    Ok(())
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{flag} expects a number, got {value}."))
+}
+
+fn parse_candidates(value: &str) -> Result<u32, String> {
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|count| (1..=MAX_CANDIDATES).contains(count))
+        .ok_or_else(|| format!("--candidates expects a number from 1 to {MAX_CANDIDATES}."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options_without_flags() {
+        let (options, rest) = parse_options(&args(&["show", "calendar"])).unwrap();
+        assert_eq!(options, Options::default());
+        assert_eq!(rest, args(&["show", "calendar"]));
+    }
+
+    #[test]
+    fn test_parse_options_offline() {
+        let (options, rest) = parse_options(&args(&["--offline", "show", "calendar"])).unwrap();
+        assert!(options.offline);
+        assert_eq!(rest, args(&["show", "calendar"]));
+    }
+
+    #[test]
+    fn test_parse_options_usage() {
+        let (options, rest) = parse_options(&args(&[
+            "--usage",
+            "--force-budget",
+            "--structured",
+            "--tools",
+            "--agent",
+            "--offline",
+            "show",
+            "calendar",
+        ]))
+        .unwrap();
+        assert!(
+            options.usage
+                && options.force_budget
+                && options.structured
+                && options.tools
+                && options.agent
+                && options.offline
+        );
+        assert_eq!(rest, args(&["show", "calendar"]));
+    }
+
+    #[test]
+    fn test_parse_options_verbosity() {
+        let (options, rest) = parse_options(&args(&["-v", "show", "calendar"])).unwrap();
+        assert_eq!(options.verbosity, 1);
+        assert_eq!(rest, args(&["show", "calendar"]));
+
+        let (options, _) = parse_options(&args(&["-vv", "show", "calendar"])).unwrap();
+        assert_eq!(options.verbosity, 2);
+    }
+
+    #[test]
+    fn test_parse_options_candidates() {
+        let (options, rest) =
+            parse_options(&args(&["--candidates", "3", "show", "calendar"])).unwrap();
+        assert_eq!(options.candidates, Some(3));
+        assert_eq!(rest, args(&["show", "calendar"]));
+
+        let (options, _) = parse_options(&args(&["--candidates=2", "show", "calendar"])).unwrap();
+        assert_eq!(options.candidates, Some(2));
+    }
+
+    #[test]
+    fn test_parse_options_compare() {
+        let (options, rest) = parse_options(&args(&[
+            "--compare",
+            "gpt-4o,gemini:gemini-2.0-flash",
+            "show",
+            "calendar",
+        ]))
+        .unwrap();
+        assert_eq!(
+            options.compare.as_deref(),
+            Some("gpt-4o,gemini:gemini-2.0-flash")
+        );
+        assert_eq!(rest, args(&["show", "calendar"]));
+    }
+
+    #[test]
+    fn test_parse_options_invalid_candidates() {
+        for invalid in [&["--candidates", "0"][..], &["--candidates=many"]] {
+            assert_eq!(
+                parse_options(&args(invalid)),
+                Err(String::from("--candidates expects a number from 1 to 10."))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_options_missing_value() {
+        assert_eq!(
+            parse_options(&args(&["--candidates"])),
+            Err(String::from("--candidates expects a value."))
+        );
+    }
+
+    #[test]
+    fn test_parse_options_sampling() {
+        let (options, rest) = parse_options(&args(&[
+            "--temperature",
+            "0.9",
+            "--max-tokens=200",
+            "--seed",
+            "42",
+            "--stop",
+            "END",
+            "--stop=;",
+            "write",
+            "a",
+            "haiku",
+        ]))
+        .unwrap();
+        assert_eq!(
+            options.sampling,
+            Sampling {
+                temperature: Some(0.9),
+                max_tokens: Some(200),
+                seed: Some(42),
+                stop: vec![String::from("END"), String::from(";")],
+                ..Sampling::default()
+            }
+        );
+        assert_eq!(rest, args(&["write", "a", "haiku"]));
+    }
+
+    #[test]
+    fn test_parse_options_invalid_number() {
+        assert_eq!(
+            parse_options(&args(&["--temperature", "hot", "show", "calendar"])),
+            Err(String::from("--temperature expects a number, got hot."))
+        );
+    }
+
+    #[test]
+    fn test_parse_options_only_leading_flags() {
+        let (options, rest) = parse_options(&args(&["grep", "--offline", "flag"])).unwrap();
+        assert!(!options.offline);
+        assert_eq!(rest, args(&["grep", "--offline", "flag"]));
+    }
+}
@@ -0,0 +1,50 @@
+/// Name of the current setup, `GPT_PROFILE` or `default`.
+pub fn name() -> String {
+    std::env::var("GPT_PROFILE").unwrap_or_else(|_| String::from("default"))
+}
+
+/// `{name}_{PROFILE}` when it's set for the current profile, `name` otherwise.
+pub fn var(name: &str) -> Result<String, std::env::VarError> {
+    names(name)
+        .iter()
+        .map(std::env::var)
+        .find(Result::is_ok)
+        .unwrap_or(Err(std::env::VarError::NotPresent))
+}
+
+/// Variables looked up for `name`, the one of the current profile first.
+pub fn names(name: &str) -> Vec<String> {
+    match std::env::var("GPT_PROFILE") {
+        Ok(profile) => vec![scoped(name, &profile), name.to_string()],
+        Err(_) => vec![name.to_string()],
+    }
+}
+
+/// Variable `name` of `profile`, uppercased with anything but letters and digits replaced by `_`.
+fn scoped(name: &str, profile: &str) -> String {
+    let suffix: String = profile
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{name}_{suffix}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scoped() {
+        assert_eq!(scoped("GPT_TEMPERATURE", "work"), "GPT_TEMPERATURE_WORK");
+        assert_eq!(
+            scoped("GPT_SEED", "side-project.2"),
+            "GPT_SEED_SIDE_PROJECT_2"
+        );
+    }
+}
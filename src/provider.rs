@@ -0,0 +1,397 @@
+use crate::api_key::ApiKey;
+use crate::error::GptError;
+use crate::gemini;
+use crate::gpt3::Gpt3Response;
+use serde_json::Value;
+
+/// Azure OpenAI API version used when `AZURE_OPENAI_API_VERSION` is not set.
+pub const AZURE_API_VERSION: &str = "2024-10-21";
+
+/// Service answering chat completions, with its own URLs and authentication.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Provider {
+    /// OpenAI or any server with an OpenAI compatible `/v1/chat/completions`.
+    OpenAi { host: String },
+    /// Azure OpenAI, where the model is chosen by the deployment in the URL.
+    Azure {
+        endpoint: String,
+        deployment: String,
+        api_version: String,
+    },
+    /// Google Gemini `generateContent`, with requests and responses translated.
+    Gemini { host: String, model: String },
+}
+
+impl Provider {
+    pub const OPEN_AI_HOST: &'static str = "https://api.openai.com";
+    pub const GEMINI_HOST: &'static str = "https://generativelanguage.googleapis.com";
+    const GEMINI_MODEL: &'static str = "gemini-2.0-flash";
+
+    /// `GPT_PROVIDER=azure`, or `AZURE_OPENAI_ENDPOINT` alone, selects Azure configured by
+    /// `AZURE_OPENAI_ENDPOINT`, `AZURE_OPENAI_DEPLOYMENT` and `AZURE_OPENAI_API_VERSION`.
+    /// `GPT_PROVIDER=gemini` selects Gemini at `GEMINI_BASE_URL` or Google's API.
+    /// Otherwise it's OpenAI at `OPENAI_BASE_URL`, `host` or api.openai.com.
+    pub fn from_env(host: Option<&str>) -> Self {
+        let name = std::env::var("GPT_PROVIDER")
+            .map(|provider| provider.to_lowercase())
+            .unwrap_or_else(|_| {
+                String::from(match std::env::var("AZURE_OPENAI_ENDPOINT") {
+                    Ok(_) => "azure",
+                    Err(_) => "openai",
+                })
+            });
+        let model = |default: &str| std::env::var("GPT_MODEL").unwrap_or(default.to_string());
+
+        match name.as_str() {
+            "gemini" => Provider::named("gemini", &model(Provider::GEMINI_MODEL)),
+            "azure" => Provider::named(
+                "azure",
+                &std::env::var("AZURE_OPENAI_DEPLOYMENT").unwrap_or_else(|_| model("gpt-4o")),
+            ),
+            _ => None,
+        }
+        .unwrap_or_else(|| Provider::OpenAi {
+            host: std::env::var("OPENAI_BASE_URL")
+                .unwrap_or_else(|_| String::from(host.unwrap_or(Provider::OPEN_AI_HOST))),
+        })
+    }
+
+    /// Provider called `name` serving `model`, configured by the environment.
+    pub fn named(name: &str, model: &str) -> Option<Self> {
+        match name {
+            "openai" => Some(Provider::OpenAi {
+                host: std::env::var("OPENAI_BASE_URL")
+                    .unwrap_or_else(|_| String::from(Provider::OPEN_AI_HOST)),
+            }),
+            "azure" => Some(Provider::Azure {
+                endpoint: std::env::var("AZURE_OPENAI_ENDPOINT").unwrap_or_default(),
+                deployment: model.to_string(),
+                api_version: std::env::var("AZURE_OPENAI_API_VERSION")
+                    .unwrap_or_else(|_| String::from(AZURE_API_VERSION)),
+            }),
+            "gemini" => Some(Provider::Gemini {
+                host: std::env::var("GEMINI_BASE_URL")
+                    .unwrap_or_else(|_| String::from(Provider::GEMINI_HOST)),
+                model: model.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::OpenAi { .. } => "openai",
+            Provider::Azure { .. } => "azure",
+            Provider::Gemini { .. } => "gemini",
+        }
+    }
+
+    /// The same provider serving `model`, which is part of the URL on Azure and Gemini.
+    pub fn with_model(&self, model: &str) -> Self {
+        match self {
+            Provider::OpenAi { .. } => self.clone(),
+            Provider::Azure {
+                endpoint,
+                api_version,
+                ..
+            } => Provider::Azure {
+                endpoint: endpoint.clone(),
+                deployment: model.to_string(),
+                api_version: api_version.clone(),
+            },
+            Provider::Gemini { host, .. } => Provider::Gemini {
+                host: host.clone(),
+                model: model.to_string(),
+            },
+        }
+    }
+
+    /// `OPENAI_API_KEY`, or `AZURE_OPENAI_API_KEY` falling back to it for Azure, or
+    /// `GEMINI_API_KEY` or `GOOGLE_API_KEY` for Gemini, each also read from `_FILE` or `_COMMAND`.
+    pub fn api_key(&self) -> ApiKey {
+        match self {
+            Provider::OpenAi { .. } => ApiKey::from_env(&["OPENAI_API_KEY"]),
+            Provider::Azure { .. } => ApiKey::from_env(&["AZURE_OPENAI_API_KEY", "OPENAI_API_KEY"]),
+            Provider::Gemini { .. } => ApiKey::from_env(&["GEMINI_API_KEY", "GOOGLE_API_KEY"]),
+        }
+    }
+
+    /// Model used when `GPT_MODEL` is not set.
+    pub fn default_model(&self) -> &'static str {
+        match self {
+            Provider::OpenAi { .. } | Provider::Azure { .. } => "gpt-4o",
+            Provider::Gemini { .. } => Provider::GEMINI_MODEL,
+        }
+    }
+
+    /// OpenAI models with the full chat completions API, so `n`, JSON schemas and caching are used.
+    pub fn is_open_ai(&self) -> bool {
+        match self {
+            Provider::OpenAi { host } => host == Provider::OPEN_AI_HOST,
+            Provider::Azure { .. } => true,
+            Provider::Gemini { .. } => false,
+        }
+    }
+
+    /// Well known hosted service, so its answers are cached. A custom `OPENAI_BASE_URL` may
+    /// serve a different model under the same name at any time.
+    pub fn is_hosted(&self) -> bool {
+        match self {
+            Provider::OpenAi { .. } => self.is_open_ai(),
+            Provider::Azure { .. } | Provider::Gemini { .. } => true,
+        }
+    }
+
+    /// Takes the `tools` of chat completions. Gemini declares functions in its own format.
+    pub fn supports_tools(&self) -> bool {
+        !matches!(self, Provider::Gemini { .. })
+    }
+
+    pub fn chat_url(&self) -> String {
+        match self {
+            Provider::OpenAi { host } => format!("{host}/v1/chat/completions"),
+            Provider::Azure {
+                endpoint,
+                deployment,
+                api_version,
+            } => format!(
+                "{}/openai/deployments/{deployment}/chat/completions?api-version={api_version}",
+                endpoint.trim_end_matches('/')
+            ),
+            Provider::Gemini { host, model } => {
+                format!("{host}/v1beta/models/{model}:generateContent")
+            }
+        }
+    }
+
+    /// Endpoint listing the models the key can use.
+    pub fn models_url(&self) -> String {
+        match self {
+            Provider::OpenAi { host } => format!("{host}/v1/models"),
+            Provider::Azure {
+                endpoint,
+                api_version,
+                ..
+            } => format!(
+                "{}/openai/models?api-version={api_version}",
+                endpoint.trim_end_matches('/')
+            ),
+            Provider::Gemini { host, .. } => format!("{host}/v1beta/models?pageSize=1000"),
+        }
+    }
+
+    /// Name and value of the header authenticating with `api_key`.
+    pub fn auth_header(&self, api_key: &str) -> (&'static str, String) {
+        match self {
+            Provider::OpenAi { .. } => ("Authorization", format!("Bearer {api_key}")),
+            Provider::Azure { .. } => ("api-key", api_key.to_string()),
+            Provider::Gemini { .. } => ("x-goog-api-key", api_key.to_string()),
+        }
+    }
+
+    /// Body sent for the chat completion request `data`.
+    pub fn request_body(&self, data: &Value) -> Value {
+        match self {
+            Provider::Gemini { .. } => gemini::request(data),
+            _ => data.clone(),
+        }
+    }
+
+    /// Chat completion read from the body of a successful response.
+    pub fn parse_response(&self, body: &str) -> Result<Gpt3Response, GptError> {
+        match self {
+            Provider::Gemini { .. } => gemini::response(body),
+            _ => serde_json::from_str(body).map_err(|e| GptError::MalformedResponse(e.to_string())),
+        }
+    }
+
+    /// Fails on settings the provider can't work without.
+    pub fn validate(&self) -> Result<(), GptError> {
+        match self {
+            Provider::Azure { endpoint, .. } if endpoint.is_empty() => {
+                Err(GptError::Config(String::from(
+                    "Error: AZURE_OPENAI_ENDPOINT is not set, eg.: https://my-resource.openai.azure.com",
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Models tried in order when the configured one fails, from `GPT_FALLBACK`.
+pub fn fallbacks_from_env(primary: &Provider) -> Vec<(Provider, String)> {
+    std::env::var("GPT_FALLBACK")
+        .map(|value| parse_models(&value, primary))
+        .unwrap_or_default()
+}
+
+/// Comma separated `provider:model` pairs, a bare model is served by the `primary` provider.
+pub fn parse_models(value: &str, primary: &Provider) -> Vec<(Provider, String)> {
+    entries(value)
+        .map(|entry| parse_model(entry, primary))
+        .collect()
+}
+
+fn entries(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+/// Only a known provider is split off, so ids like `llama3:8b` of Ollama stay whole models of the
+/// `primary` provider.
+fn parse_model(entry: &str, primary: &Provider) -> (Provider, String) {
+    entry
+        .split_once(':')
+        .and_then(|(name, model)| {
+            Provider::named(&name.to_lowercase(), model)
+                .map(|provider| (provider, model.to_string()))
+        })
+        .unwrap_or_else(|| (primary.with_model(entry), entry.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn azure() -> Provider {
+        Provider::Azure {
+            endpoint: String::from("https://my-resource.openai.azure.com/"),
+            deployment: String::from("gpt-4o-prod"),
+            api_version: String::from(AZURE_API_VERSION),
+        }
+    }
+
+    fn gemini() -> Provider {
+        Provider::Gemini {
+            host: String::from(Provider::GEMINI_HOST),
+            model: String::from(Provider::GEMINI_MODEL),
+        }
+    }
+
+    #[test]
+    fn test_chat_url() {
+        let openai = Provider::OpenAi {
+            host: String::from(Provider::OPEN_AI_HOST),
+        };
+        assert_eq!(
+            openai.chat_url(),
+            "https://api.openai.com/v1/chat/completions"
+        );
+        assert_eq!(
+            azure().chat_url(),
+            "https://my-resource.openai.azure.com/openai/deployments/gpt-4o-prod/chat/completions?api-version=2024-10-21"
+        );
+        assert_eq!(
+            gemini().chat_url(),
+            "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.0-flash:generateContent"
+        );
+    }
+
+    #[test]
+    fn test_models_url() {
+        let openai = Provider::OpenAi {
+            host: String::from("http://localhost:11434"),
+        };
+        assert_eq!(openai.models_url(), "http://localhost:11434/v1/models");
+        assert_eq!(
+            azure().models_url(),
+            "https://my-resource.openai.azure.com/openai/models?api-version=2024-10-21"
+        );
+        assert_eq!(
+            gemini().models_url(),
+            "https://generativelanguage.googleapis.com/v1beta/models?pageSize=1000"
+        );
+    }
+
+    #[test]
+    fn test_auth_header() {
+        let openai = Provider::OpenAi {
+            host: String::from("http://localhost:11434"),
+        };
+        assert_eq!(
+            openai.auth_header("sk-1"),
+            ("Authorization", String::from("Bearer sk-1"))
+        );
+        assert_eq!(azure().auth_header("abc"), ("api-key", String::from("abc")));
+        assert_eq!(
+            gemini().auth_header("AIza"),
+            ("x-goog-api-key", String::from("AIza"))
+        );
+        assert!(!openai.is_open_ai() && !openai.is_hosted());
+        assert!(azure().is_open_ai());
+        assert!(!gemini().is_open_ai() && gemini().is_hosted());
+    }
+
+    #[test]
+    fn test_parse_fallbacks() {
+        let primary = Provider::OpenAi {
+            host: String::from("http://localhost:11434"),
+        };
+        let fallbacks = parse_models(
+            "gpt-4o-mini, gemini:gemini-2.0-flash,azure:gpt-4o-prod,bedrock:claude,",
+            &primary,
+        );
+        let labels: Vec<String> = fallbacks
+            .iter()
+            .map(|(provider, model)| format!("{}:{model}", provider.name()))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                "openai:gpt-4o-mini",
+                "gemini:gemini-2.0-flash",
+                "azure:gpt-4o-prod",
+                "openai:bedrock:claude"
+            ]
+        );
+        assert_eq!(fallbacks[0].0, primary);
+        assert!(
+            fallbacks[2]
+                .0
+                .chat_url()
+                .contains("/deployments/gpt-4o-prod/")
+        );
+    }
+
+    #[test]
+    fn test_parse_models() {
+        let primary = Provider::named("openai", "gpt-4o").unwrap();
+        assert_eq!(
+            parse_models("gpt-4o, gemini:gemini-2.0-flash", &primary).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_parse_models_keeps_tagged_ids() {
+        let primary = Provider::OpenAi {
+            host: String::from("http://localhost:11434"),
+        };
+        assert_eq!(
+            parse_models("llama3:8b,OpenAI:gpt-4o", &primary),
+            vec![
+                (primary.clone(), String::from("llama3:8b")),
+                (
+                    Provider::named("openai", "gpt-4o").unwrap(),
+                    String::from("gpt-4o")
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(azure().validate().is_ok());
+        let missing_endpoint = Provider::Azure {
+            endpoint: String::new(),
+            deployment: String::from("gpt-4o"),
+            api_version: String::from(AZURE_API_VERSION),
+        };
+        assert!(matches!(
+            missing_endpoint.validate(),
+            Err(GptError::Config(_))
+        ));
+    }
+}
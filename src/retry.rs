@@ -0,0 +1,202 @@
+use crate::profile;
+use reqwest::StatusCode;
+use reqwest::header::HeaderMap;
+use std::time::{Duration, SystemTime};
+
+/// When and how long to wait before sending a failed request again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of requests, including the first one.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Default policy with the attempt count taken from `GPT_MAX_ATTEMPTS`, or its
+    /// `{NAME}_{PROFILE}` variant for the current `GPT_PROFILE`.
+    pub fn from_env() -> Self {
+        let default = RetryPolicy::default();
+        let max_attempts = profile::var("GPT_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|value| value.parse::<u32>().ok())
+            .map_or(default.max_attempts, |attempts| attempts.max(1));
+
+        RetryPolicy {
+            max_attempts,
+            ..default
+        }
+    }
+
+    pub fn is_retriable(status: StatusCode) -> bool {
+        matches!(status.as_u16(), 429 | 500 | 502 | 503 | 504)
+    }
+
+    /// Exponential backoff with equal jitter: half of the doubled delay is fixed, the other half random.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = exponential / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+
+    /// Delay requested by the server, or the backoff for this attempt when there is none.
+    pub fn delay(&self, attempt: u32, headers: &HeaderMap) -> Duration {
+        server_delay(headers)
+            .map(|delay| delay.min(self.max_delay))
+            .unwrap_or_else(|| self.backoff(attempt))
+    }
+}
+
+/// `Retry-After` (seconds or HTTP date), then OpenAI `x-ratelimit-reset-*` headers of exhausted limits.
+fn server_delay(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(retry_after) = header("retry-after") {
+        if let Ok(seconds) = retry_after.trim().parse::<f64>() {
+            return Duration::try_from_secs_f64(seconds).ok();
+        }
+        if let Ok(date) = httpdate::parse_http_date(retry_after) {
+            return Some(
+                date.duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO),
+            );
+        }
+    }
+
+    ["requests", "tokens"]
+        .iter()
+        .filter(|kind| header(&format!("x-ratelimit-remaining-{kind}")).is_none_or(|r| r == "0"))
+        .filter_map(|kind| header(&format!("x-ratelimit-reset-{kind}")).and_then(parse_reset))
+        .max()
+}
+
+/// Parses Go-style durations used by OpenAI, eg.: `20ms`, `1s`, `6m0s`, `1h2m3.5s`.
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let number: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit_len] {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return None,
+        };
+        total += number * seconds;
+        rest = &rest[unit_len..];
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_is_retriable() {
+        for status in [429, 500, 502, 503, 504] {
+            assert!(RetryPolicy::is_retriable(
+                StatusCode::from_u16(status).unwrap()
+            ));
+        }
+        for status in [400, 401, 404, 501] {
+            assert!(!RetryPolicy::is_retriable(
+                StatusCode::from_u16(status).unwrap()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_reset() {
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_reset("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_reset("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(
+            parse_reset("1h2m3.5s"),
+            Some(Duration::from_millis(3_723_500))
+        );
+        assert_eq!(parse_reset(""), None);
+        assert_eq!(parse_reset("soon"), None);
+    }
+
+    #[test]
+    fn test_delay_from_retry_after_seconds() {
+        let policy = RetryPolicy::default();
+        let delay = policy.delay(1, &headers(&[("retry-after", "7")]));
+        assert_eq!(delay, Duration::from_secs(7));
+    }
+
+    #[test]
+    fn test_delay_from_retry_after_date() {
+        let policy = RetryPolicy::default();
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(30));
+        let delay = policy.delay(1, &headers(&[("retry-after", &date)]));
+        assert!(delay > Duration::from_secs(25) && delay <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_delay_from_exhausted_rate_limit() {
+        let policy = RetryPolicy::default();
+        let delay = policy.delay(
+            1,
+            &headers(&[
+                ("x-ratelimit-remaining-requests", "12"),
+                ("x-ratelimit-reset-requests", "30s"),
+                ("x-ratelimit-remaining-tokens", "0"),
+                ("x-ratelimit-reset-tokens", "1.5s"),
+            ]),
+        );
+        assert_eq!(delay, Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_delay_is_capped() {
+        let policy = RetryPolicy::default();
+        let delay = policy.delay(1, &headers(&[("retry-after", "3600")]));
+        assert_eq!(delay, policy.max_delay);
+    }
+
+    #[test]
+    fn test_backoff_grows_with_jitter() {
+        let policy = RetryPolicy::default();
+        for attempt in 1..=4 {
+            let ceiling = policy.base_delay * 2u32.pow(attempt - 1);
+            let delay = policy.backoff(attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling);
+        }
+        assert!(policy.backoff(30) <= policy.max_delay);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::profile;
+use serde::Serialize;
+
+/// Temperature of free text answers and of candidates, which would all be the same at 0. A single
+/// generated command uses 0 to be deterministic.
+const CREATIVE_TEMPERATURE: f64 = 0.7;
+
+/// Parameters controlling how the model samples its answer, sent only when set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Sampling {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// `max_tokens` of reasoning models, which reject the former, see [`Sampling::for_model`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+}
+
+impl Sampling {
+    /// Deterministic for a single answer to the default command generation prompt, more creative
+    /// for custom prompts and for `candidates` answers to choose from.
+    pub fn defaults(command_generation: bool, candidates: u32) -> Self {
+        Sampling {
+            temperature: Some(if command_generation && candidates <= 1 {
+                0.0
+            } else {
+                CREATIVE_TEMPERATURE
+            }),
+            ..Sampling::default()
+        }
+    }
+
+    /// Values from `GPT_TEMPERATURE`, `GPT_TOP_P`, `GPT_MAX_TOKENS`, `GPT_SEED`, `GPT_STOP`,
+    /// `GPT_PRESENCE_PENALTY` and `GPT_FREQUENCY_PENALTY`, or their `{NAME}_{PROFILE}` variants for
+    /// the current `GPT_PROFILE`. Invalid values are ignored.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            profile::var(name)
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+        }
+
+        Sampling {
+            temperature: var("GPT_TEMPERATURE"),
+            top_p: var("GPT_TOP_P"),
+            max_tokens: var("GPT_MAX_TOKENS"),
+            max_completion_tokens: None,
+            seed: var("GPT_SEED"),
+            stop: profile::var("GPT_STOP")
+                .map(|value| parse_stop(&value))
+                .unwrap_or_default(),
+            presence_penalty: var("GPT_PRESENCE_PENALTY"),
+            frequency_penalty: var("GPT_FREQUENCY_PENALTY"),
+        }
+    }
+
+    /// `self` with the values set in `overrides` replaced.
+    pub fn merge(self, overrides: &Sampling) -> Self {
+        Sampling {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            max_tokens: overrides.max_tokens.or(self.max_tokens),
+            max_completion_tokens: overrides
+                .max_completion_tokens
+                .or(self.max_completion_tokens),
+            seed: overrides.seed.or(self.seed),
+            stop: if overrides.stop.is_empty() {
+                self.stop
+            } else {
+                overrides.stop.clone()
+            },
+            presence_penalty: overrides.presence_penalty.or(self.presence_penalty),
+            frequency_penalty: overrides.frequency_penalty.or(self.frequency_penalty),
+        }
+    }
+
+    /// Parameters sent to `model`. Reasoning models reject `temperature`, so the default one is
+    /// left out for them, and `max_tokens`, which they take as `max_completion_tokens`.
+    pub fn for_model(&self, model: &str, defaults: &Sampling) -> Self {
+        let reasoning =
+            model.starts_with('o') && model[1..].starts_with(|c: char| c.is_ascii_digit());
+        if !reasoning {
+            return self.clone();
+        }
+        Sampling {
+            temperature: if self.temperature == defaults.temperature {
+                None
+            } else {
+                self.temperature
+            },
+            max_tokens: None,
+            max_completion_tokens: self.max_completion_tokens.or(self.max_tokens),
+            ..self.clone()
+        }
+    }
+}
+
+/// A JSON array of sequences, or a single sequence.
+pub fn parse_stop(value: &str) -> Vec<String> {
+    if value.trim_start().starts_with('[') {
+        serde_json::from_str(value).unwrap_or_default()
+    } else {
+        vec![value.to_string()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(Sampling::defaults(true, 1).temperature, Some(0.0));
+        assert_eq!(
+            Sampling::defaults(false, 1).temperature,
+            Some(CREATIVE_TEMPERATURE)
+        );
+        assert_eq!(
+            serde_json::to_value(Sampling::defaults(true, 1)).unwrap(),
+            serde_json::json!({"temperature": 0.0})
+        );
+    }
+
+    #[test]
+    fn test_defaults_for_candidates() {
+        assert_eq!(
+            Sampling::defaults(true, 3).temperature,
+            Some(CREATIVE_TEMPERATURE)
+        );
+        assert_eq!(
+            Sampling::defaults(false, 3).temperature,
+            Some(CREATIVE_TEMPERATURE)
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let env = Sampling {
+            max_tokens: Some(100),
+            stop: vec![String::from("\n")],
+            ..Sampling::defaults(true, 1)
+        };
+        let cli = Sampling {
+            temperature: Some(1.0),
+            seed: Some(42),
+            ..Sampling::default()
+        };
+
+        assert_eq!(
+            env.merge(&cli),
+            Sampling {
+                temperature: Some(1.0),
+                max_tokens: Some(100),
+                seed: Some(42),
+                stop: vec![String::from("\n")],
+                ..Sampling::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_stop() {
+        assert_eq!(parse_stop("END"), vec!["END"]);
+        assert_eq!(parse_stop(r#"["\n", "END"]"#), vec!["\n", "END"]);
+    }
+
+    #[test]
+    fn test_for_reasoning_model() {
+        let defaults = Sampling::defaults(true, 1);
+        let sampling = Sampling {
+            seed: Some(1),
+            ..defaults.clone()
+        };
+        assert_eq!(sampling.for_model("o3-mini", &defaults).temperature, None);
+        assert_eq!(sampling.for_model("o3-mini", &defaults).seed, Some(1));
+        assert_eq!(sampling.for_model("gpt-4o", &defaults), sampling);
+
+        let explicit = Sampling {
+            temperature: Some(1.0),
+            ..defaults.clone()
+        };
+        assert_eq!(
+            explicit.for_model("o3-mini", &defaults).temperature,
+            Some(1.0)
+        );
+    }
+
+    #[test]
+    fn test_max_tokens_for_reasoning_model() {
+        let defaults = Sampling::defaults(true, 1);
+        let sampling = Sampling {
+            max_tokens: Some(200),
+            ..defaults.clone()
+        };
+        assert_eq!(
+            serde_json::to_value(sampling.for_model("o1", &defaults)).unwrap(),
+            serde_json::json!({"max_completion_tokens": 200})
+        );
+        assert_eq!(
+            serde_json::to_value(sampling.for_model("gpt-4o", &defaults)).unwrap(),
+            serde_json::json!({"temperature": 0.0, "max_tokens": 200})
+        );
+    }
+}
@@ -8,7 +8,7 @@ pub struct ShouldExit {
     pub messages: Vec<ColoredString>,
 }
 
-pub fn should_exit(args: &Vec<String>) -> ShouldExit {
+pub fn should_exit(args: &[String]) -> ShouldExit {
     if args.len().eq(&0) {
         return ShouldExit {
             exit: true,
@@ -42,8 +42,8 @@ mod tests {
         let args: Vec<String> = vec![];
         let result = should_exit(&args);
 
-        assert_eq!(result.exit, true);
-        assert_eq!(result.is_error, true);
+        assert!(result.exit);
+        assert!(result.is_error);
         assert_eq!(result.messages.len(), 2);
         assert_eq!(
             result.messages[0].clone().clear().to_string().as_str(),
@@ -62,8 +62,8 @@ mod tests {
         let args: Vec<String> = vec![String::from("--version")];
         let result = should_exit(&args);
 
-        assert_eq!(result.exit, true);
-        assert_eq!(result.is_error, false);
+        assert!(result.exit);
+        assert!(!result.is_error);
         assert_eq!(result.messages.len(), 1);
         assert_eq!(result.messages[0].to_string().as_str(), VERSION);
     }
@@ -73,8 +73,8 @@ mod tests {
         let args: Vec<String> = vec![String::from("show"), String::from("calendar")];
         let result = should_exit(&args);
 
-        assert_eq!(result.exit, false);
-        assert_eq!(result.is_error, false);
+        assert!(!result.exit);
+        assert!(!result.is_error);
         assert_eq!(result.messages.len(), 0);
     }
 }
@@ -0,0 +1,48 @@
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+const FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// Animated `label` with elapsed time on stderr, removed when dropped. Does nothing when
+/// stderr is not a terminal, so redirected output stays clean.
+pub struct Spinner {
+    task: Option<JoinHandle<()>>,
+}
+
+impl Spinner {
+    pub fn start(label: &str) -> Self {
+        if !std::io::stderr().is_terminal() {
+            return Spinner { task: None };
+        }
+        let label = label.to_string();
+        let started = Instant::now();
+        let task = tokio::spawn(async move {
+            for frame in FRAMES.iter().cycle() {
+                eprint!(
+                    "\r\x1b[2K{frame} {label} {:.1}s",
+                    started.elapsed().as_secs_f64()
+                );
+                let _ = std::io::stderr().flush();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+        Spinner { task: Some(task) }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+            clear_line();
+        }
+    }
+}
+
+/// Removes the spinner before other messages are printed on stderr.
+pub fn clear_line() {
+    if std::io::stderr().is_terminal() {
+        eprint!("\r\x1b[2K");
+    }
+}
@@ -0,0 +1,462 @@
+use crate::api_key;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::fs::{File, read_dir, read_to_string};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const MAX_ENTRIES: usize = 200;
+const DEFAULT_LINES: usize = 20;
+const MAX_LINES: usize = 200;
+/// Most bytes of a file read by `read_file`, however long its lines are.
+const MAX_READ: u64 = 64 * 1024;
+/// Longest tool result sent back to the model, in bytes.
+const MAX_OUTPUT: usize = 8 * 1024;
+const VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Programs `version` may run, known to only print their version for `--version`.
+const VERSION_PROGRAMS: &[&str] = &[
+    "bash",
+    "bun",
+    "cargo",
+    "clang",
+    "cmake",
+    "curl",
+    "deno",
+    "docker",
+    "ffmpeg",
+    "gcc",
+    "git",
+    "go",
+    "helm",
+    "java",
+    "kubectl",
+    "make",
+    "node",
+    "npm",
+    "perl",
+    "php",
+    "pip",
+    "pip3",
+    "pnpm",
+    "podman",
+    "python",
+    "python3",
+    "ruby",
+    "rustc",
+    "terraform",
+    "yarn",
+    "zsh",
+];
+/// Directories holding keys and credentials, never read by `read_file`.
+const SECRET_DIRS: &[&str] = &[
+    ".ssh", ".gnupg", ".aws", ".azure", "gcloud", ".kube", ".docker",
+];
+/// Files holding secrets, never read by `read_file`, along with `.env.*` files.
+const SECRET_FILES: &[&str] = &[".env", ".netrc", ".git-credentials", ".pgpass"];
+
+/// Call of a function requested by the model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_kind")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON object encoded as a string.
+    pub arguments: String,
+}
+
+fn function_kind() -> String {
+    String::from("function")
+}
+
+fn function(name: &str, description: &str, properties: Value, required: &[&str]) -> Value {
+    json!({
+        "type": "function",
+        "function": {
+            "name": name,
+            "description": description,
+            "parameters": {
+                "type": "object",
+                "properties": properties,
+                "required": required
+            }
+        }
+    })
+}
+
+/// Read-only tools the model may call, in the `tools` format of chat completions.
+pub fn definitions() -> Value {
+    json!([
+        function(
+            "list_directory",
+            "List entries of a directory in the working directory, directories end with a slash.",
+            json!({ "path": { "type": "string", "description": "Directory, the current one by default." } }),
+            &[],
+        ),
+        function(
+            "read_file",
+            "Read the first lines of a text file in the working directory.",
+            json!({
+                "path": { "type": "string" },
+                "lines": { "type": "integer", "description": "Number of lines, 20 by default, at most 200." }
+            }),
+            &["path"],
+        ),
+        function(
+            "which",
+            "Find the path of a program in PATH.",
+            json!({ "program": { "type": "string" } }),
+            &["program"],
+        ),
+        function(
+            "version",
+            &format!(
+                "Print the output of `program --version` for one of: {}.",
+                VERSION_PROGRAMS.join(", ")
+            ),
+            json!({ "program": { "type": "string" } }),
+            &["program"],
+        ),
+        function(
+            "os_release",
+            "Read /etc/os-release describing the Linux distribution.",
+            json!({}),
+            &[],
+        ),
+    ])
+}
+
+/// Runs the tool, returning its result or an error message for the model.
+pub fn call(call: &FunctionCall) -> String {
+    let arguments: Value = serde_json::from_str(&call.arguments).unwrap_or(json!({}));
+    let string = |name: &str| arguments[name].as_str().map(str::to_string);
+
+    let result = match call.name.as_str() {
+        "list_directory" => list_directory(&string("path").unwrap_or_else(|| String::from("."))),
+        "read_file" => match string("path") {
+            Some(path) => read_file(
+                &path,
+                arguments["lines"]
+                    .as_u64()
+                    .map_or(DEFAULT_LINES, |lines| lines as usize),
+            ),
+            None => Err(String::from("missing path")),
+        },
+        "which" => string("program")
+            .ok_or_else(|| String::from("missing program"))
+            .and_then(|program| which(&program))
+            .map(|path| path.display().to_string()),
+        "version" => string("program")
+            .ok_or_else(|| String::from("missing program"))
+            .and_then(|program| version(&program)),
+        "os_release" => os_release(),
+        name => Err(format!("unknown tool {name}")),
+    };
+
+    truncate(result.unwrap_or_else(|error| format!("Error: {error}")))
+}
+
+fn truncate(mut output: String) -> String {
+    if output.len() > MAX_OUTPUT {
+        let mut end = MAX_OUTPUT;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+        output.push_str("\n[truncated]");
+    }
+    output
+}
+
+fn list_directory(path: &str) -> Result<String, String> {
+    let root = std::env::current_dir().map_err(|e| format!("can't find working directory: {e}"))?;
+    list_directory_within(&root, path)
+}
+
+/// Lists a directory under `root`, see [`within`].
+fn list_directory_within(root: &Path, path: &str) -> Result<String, String> {
+    let mut entries: Vec<String> = read_dir(within(root, path, "list")?)
+        .map_err(|e| format!("can't list {path}: {e}"))?
+        .filter_map(Result::ok)
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => format!("{name}/"),
+                _ => name,
+            }
+        })
+        .collect();
+    entries.sort();
+    let total = entries.len();
+    entries.truncate(MAX_ENTRIES);
+    if total > MAX_ENTRIES {
+        entries.push(format!("[{} more entries]", total - MAX_ENTRIES));
+    }
+    Ok(entries.join("\n"))
+}
+
+fn read_file(path: &str, lines: usize) -> Result<String, String> {
+    let root = std::env::current_dir().map_err(|e| format!("can't find working directory: {e}"))?;
+    read_file_within(&root, path, lines)
+}
+
+/// `path` resolved against `root`, when it's under it. Links are resolved first, so they can't
+/// lead outside of it. `action` names what failed when `path` doesn't exist.
+fn within(root: &Path, path: &str, action: &str) -> Result<PathBuf, String> {
+    let root = root
+        .canonicalize()
+        .map_err(|e| format!("can't find working directory: {e}"))?;
+    let resolved = root
+        .join(path)
+        .canonicalize()
+        .map_err(|e| format!("can't {action} {path}: {e}"))?;
+    if !resolved.starts_with(&root) {
+        return Err(format!("{path} is outside the working directory"));
+    }
+    Ok(resolved)
+}
+
+/// Reads at most [`MAX_READ`] bytes of a file under `root` that doesn't hold secrets, see
+/// [`within`].
+fn read_file_within(root: &Path, path: &str, lines: usize) -> Result<String, String> {
+    let resolved = within(root, path, "read")?;
+    if is_secret(&resolved) {
+        return Err(format!("{path} may hold secrets"));
+    }
+
+    let file = File::open(&resolved).map_err(|e| format!("can't read {path}: {e}"))?;
+    let mut reader = BufReader::new(file.take(MAX_READ));
+    let mut text = Vec::new();
+    for _ in 0..lines.clamp(1, MAX_LINES) {
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => text.push(
+                String::from_utf8_lossy(&line)
+                    .trim_end_matches(['\n', '\r'])
+                    .to_string(),
+            ),
+            Err(e) => return Err(format!("can't read {path}: {e}")),
+        }
+    }
+    Ok(text.join("\n"))
+}
+
+/// Credentials, keys and the cache key file.
+fn is_secret(path: &Path) -> bool {
+    let in_secret_dir = path.components().any(|component| {
+        matches!(component, Component::Normal(name) if SECRET_DIRS.iter().any(|dir| name == *dir))
+    });
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let cache_keys = ["GPT_CACHE_KEY_FILE", "GPT_CACHE_NEW_KEY_FILE"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .filter_map(|key_file| Path::new(&key_file).canonicalize().ok());
+
+    in_secret_dir
+        || SECRET_FILES.contains(&name.as_str())
+        || name.starts_with(".env.")
+        || cache_keys.into_iter().any(|key_file| key_file == path)
+}
+
+/// Only bare program names are looked up, so no arbitrary path is ever executed.
+fn which(program: &str) -> Result<PathBuf, String> {
+    if program.is_empty() || program.contains('/') {
+        return Err(format!("{program} is not a program name"));
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+        .ok_or_else(|| format!("{program} not found in PATH"))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+fn version(program: &str) -> Result<String, String> {
+    if !VERSION_PROGRAMS.contains(&program) {
+        return Err(format!(
+            "{program} is not one of the programs whose version can be checked"
+        ));
+    }
+    let path = which(program)?;
+    let mut child = api_key::scrub(Command::new(&path).arg("--version"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("can't run {program}: {e}"))?;
+
+    let started = Instant::now();
+    while child.try_wait().map_err(|e| e.to_string())?.is_none() {
+        if started.elapsed() > VERSION_TIMEOUT {
+            let _ = child.kill();
+            return Err(format!("{program} --version did not finish in time"));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    let text = if output.stdout.is_empty() {
+        output.stderr
+    } else {
+        output.stdout
+    };
+    Ok(String::from_utf8_lossy(&text)
+        .lines()
+        .take(5)
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+fn os_release() -> Result<String, String> {
+    read_to_string("/etc/os-release")
+        .or_else(|_| read_to_string("/usr/lib/os-release"))
+        .map_err(|e| format!("can't read os-release: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(name: &str, arguments: Value) -> String {
+        call(&FunctionCall {
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+        })
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gpt-tools-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("notes.txt"), "one\ntwo\nthree\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_list_directory() {
+        let dir = temp_dir();
+        assert_eq!(list_directory_within(&dir, ".").unwrap(), "notes.txt\nsrc/");
+        assert_eq!(
+            list_directory_within(&dir.join("src"), "..").unwrap_err(),
+            ".. is outside the working directory"
+        );
+        assert_eq!(
+            list_directory_within(&dir, "/etc").unwrap_err(),
+            "/etc is outside the working directory"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_file() {
+        let dir = temp_dir();
+        assert_eq!(read_file_within(&dir, "notes.txt", 2).unwrap(), "one\ntwo");
+        assert_eq!(
+            read_file_within(&dir.join("src"), "../notes.txt", 2).unwrap_err(),
+            "../notes.txt is outside the working directory"
+        );
+        assert!(run("read_file", json!({})).starts_with("Error: missing path"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_file_refuses_secrets() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(dir.join(".ssh")).unwrap();
+        std::fs::write(dir.join(".ssh/id_ed25519"), "key").unwrap();
+        std::fs::write(dir.join(".env.local"), "TOKEN=1").unwrap();
+
+        assert_eq!(
+            read_file_within(&dir, ".ssh/id_ed25519", 1).unwrap_err(),
+            ".ssh/id_ed25519 may hold secrets"
+        );
+        assert_eq!(
+            read_file_within(&dir, ".env.local", 1).unwrap_err(),
+            ".env.local may hold secrets"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_file_stops_at_byte_limit() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("long.txt"), "x".repeat(2 * MAX_READ as usize)).unwrap();
+
+        let text = read_file_within(&dir, "long.txt", 1).unwrap();
+
+        assert_eq!(text.len(), MAX_READ as usize);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_which() {
+        assert!(run("which", json!({ "program": "sh" })).ends_with("/sh"));
+        assert_eq!(
+            run("which", json!({ "program": "/bin/sh" })),
+            "Error: /bin/sh is not a program name"
+        );
+    }
+
+    #[test]
+    fn test_version() {
+        assert!(run("version", json!({ "program": "rustc" })).starts_with("rustc "));
+        assert!(run("version", json!({ "program": "no-such-program-gpt" })).starts_with("Error: "));
+        assert_eq!(
+            run("version", json!({ "program": "sh" })),
+            "Error: sh is not one of the programs whose version can be checked"
+        );
+    }
+
+    #[test]
+    fn test_unknown_tool() {
+        assert_eq!(run("rm", json!({})), "Error: unknown tool rm");
+    }
+
+    #[test]
+    fn test_truncate() {
+        let output = truncate("ą".repeat(MAX_OUTPUT));
+        assert!(output.len() <= MAX_OUTPUT + "\n[truncated]".len());
+        assert!(output.ends_with("[truncated]"));
+    }
+
+    #[test]
+    fn test_definitions() {
+        let definitions = definitions();
+        let names: Vec<&str> = definitions
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tool| tool["function"]["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "list_directory",
+                "read_file",
+                "which",
+                "version",
+                "os_release"
+            ]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use crate::api_key::{REDACTED, redact};
+use crate::spinner::clear_line;
+use reqwest::header::HeaderMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::Duration;
+
+/// Request headers carrying the API key of one of the providers.
+const AUTH_HEADERS: [&str; 3] = ["authorization", "api-key", "x-goog-api-key"];
+
+/// Response headers shown with `-v`, every header is shown with `-vv`.
+fn is_traced(name: &str) -> bool {
+    name == "x-request-id" || name == "retry-after" || name.starts_with("x-ratelimit-")
+}
+
+/// Logs HTTP exchanges on stderr or in `GPT_LOG_FILE`, never on stdout. Level 1 shows the
+/// endpoint, headers, status and latency, level 2 adds request and response bodies.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    pub level: u8,
+    pub file: Option<String>,
+}
+
+impl Trace {
+    pub fn from_env(level: u8) -> Self {
+        Trace {
+            level,
+            file: std::env::var("GPT_LOG_FILE").ok(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.level > 0
+    }
+
+    fn write(&self, lines: &[String]) {
+        let text: String = lines.iter().map(|line| format!("{line}\n")).collect();
+        match &self.file {
+            Some(path) => {
+                let written = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(text.as_bytes()));
+                if let Err(e) = written {
+                    eprintln!("Can't write GPT_LOG_FILE {path}: {e}");
+                }
+            }
+            None => {
+                clear_line();
+                eprint!("{text}");
+            }
+        }
+    }
+
+    /// Logs the request about to be sent, with `secrets` and authentication headers redacted.
+    pub fn request(&self, request: &reqwest::Request, secrets: &[&str]) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut lines = vec![format!(
+            "> {} {}",
+            request.method(),
+            redact(request.url().as_str(), secrets)
+        )];
+        for (name, value) in request.headers() {
+            let value = match AUTH_HEADERS.contains(&name.as_str()) {
+                true => String::from(REDACTED),
+                false => redact(&String::from_utf8_lossy(value.as_bytes()), secrets),
+            };
+            lines.push(format!("> {name}: {value}"));
+        }
+        if self.level > 1
+            && let Some(body) = request.body().and_then(|body| body.as_bytes())
+        {
+            lines.push(format!(
+                "> {}",
+                redact(&String::from_utf8_lossy(body), secrets)
+            ));
+        }
+        self.write(&lines);
+    }
+
+    /// Logs the response, reading its body for level 2 and returning an equal response.
+    pub async fn response(
+        &self,
+        response: reqwest::Response,
+        latency: Duration,
+        secrets: &[&str],
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        if !self.is_enabled() {
+            return Ok(response);
+        }
+        let status = response.status();
+        let headers = response.headers().clone();
+        let mut lines = vec![format!("< {status} in {:.3}s", latency.as_secs_f64())];
+        lines.extend(self.response_headers(&headers, secrets));
+        if self.level < 2 {
+            self.write(&lines);
+            return Ok(response);
+        }
+
+        let body = response.text().await?;
+        lines.push(format!("< {}", redact(&body, secrets)));
+        self.write(&lines);
+        let mut response = http::Response::new(body);
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        Ok(reqwest::Response::from(response))
+    }
+
+    fn response_headers(&self, headers: &HeaderMap, secrets: &[&str]) -> Vec<String> {
+        headers
+            .iter()
+            .filter(|(name, _)| self.level > 1 || is_traced(name.as_str()))
+            .map(|(name, value)| {
+                format!(
+                    "< {name}: {}",
+                    redact(&String::from_utf8_lossy(value.as_bytes()), secrets)
+                )
+            })
+            .collect()
+    }
+
+    /// Logs a request that got no response.
+    pub fn failure(&self, error: &reqwest::Error, latency: Duration) {
+        if self.is_enabled() {
+            self.write(&[format!(
+                "< failed in {:.3}s: {error}",
+                latency.as_secs_f64()
+            )]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn log_file() -> String {
+        std::env::temp_dir()
+            .join(format!("gpt-trace-{}.log", rand::random::<u64>()))
+            .display()
+            .to_string()
+    }
+
+    fn request() -> reqwest::Request {
+        reqwest::Client::new()
+            .post("https://api.openai.com/v1/chat/completions")
+            .header("Authorization", "Bearer sk-secret")
+            .json(&json!({ "model": "gpt-4o" }))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_request() {
+        let path = log_file();
+        let trace = Trace {
+            level: 2,
+            file: Some(path.clone()),
+        };
+        trace.request(&request(), &["sk-secret"]);
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            log,
+            "> POST https://api.openai.com/v1/chat/completions\n> authorization: <redacted>\n> content-type: application/json\n> {\"model\":\"gpt-4o\"}\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_response() {
+        let mut response = http::Response::new(String::from(r#"{"key":"sk-secret"}"#));
+        for (name, value) in [
+            ("x-request-id", "req_123"),
+            ("x-ratelimit-remaining-requests", "499"),
+            ("server", "cloudflare"),
+        ] {
+            response.headers_mut().insert(name, value.parse().unwrap());
+        }
+
+        let path = log_file();
+        let trace = Trace {
+            level: 1,
+            file: Some(path.clone()),
+        };
+        let response = trace
+            .response(
+                reqwest::Response::from(response),
+                Duration::from_millis(1234),
+                &["sk-secret"],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "< 200 OK in 1.234s\n< x-request-id: req_123\n< x-ratelimit-remaining-requests: 499\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let trace = Trace {
+            level: 2,
+            file: Some(path.clone()),
+        };
+        let response = trace
+            .response(response, Duration::from_millis(5), &["sk-secret"])
+            .await
+            .unwrap();
+        // The body is still there for the caller.
+        assert_eq!(response.text().await.unwrap(), r#"{"key":"sk-secret"}"#);
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(log.contains("< server: cloudflare\n"));
+        assert!(log.ends_with("< {\"key\":\"<redacted>\"}\n"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_disabled() {
+        let path = log_file();
+        Trace {
+            level: 0,
+            file: Some(path.clone()),
+        }
+        .request(&request(), &[]);
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
@@ -0,0 +1,471 @@
+use crate::gpt3::Usage;
+use crate::profile;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{OpenOptions, read_to_string, rename, write};
+use std::io::ErrorKind;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// USD per million prompt and completion tokens.
+const DEFAULT_PRICES: [(&str, f64, f64); 15] = [
+    ("gpt-4o-mini", 0.15, 0.60),
+    ("gpt-4o", 2.50, 10.00),
+    ("gpt-4.1-nano", 0.10, 0.40),
+    ("gpt-4.1-mini", 0.40, 1.60),
+    ("gpt-4.1", 2.00, 8.00),
+    ("gpt-4-turbo", 10.00, 30.00),
+    ("gpt-4", 30.00, 60.00),
+    ("gpt-3.5-turbo", 0.50, 1.50),
+    ("o1", 15.00, 60.00),
+    ("o3-mini", 1.10, 4.40),
+    ("o3", 2.00, 8.00),
+    ("o4-mini", 1.10, 4.40),
+    ("gemini-2.0-flash", 0.10, 0.40),
+    ("gemini-2.5-flash", 0.30, 2.50),
+    ("gemini-2.5-pro", 1.25, 10.00),
+];
+
+/// Prices of models, matched by name or by name and a snapshot suffix, so `gpt-4o-2024-08-06`
+/// uses the price of `gpt-4o` while `gpt-4o-mini` or `gpt-4.5` don't.
+pub struct Pricing {
+    prices: HashMap<String, (f64, f64)>,
+}
+
+impl Pricing {
+    /// Built-in prices extended or overridden by `GPT_PRICING`, a JSON object like
+    /// `{"gpt-4o": [2.5, 10]}` or a path to a file containing it.
+    pub fn from_env() -> Self {
+        let mut pricing = Pricing::default();
+        if let Ok(value) = std::env::var("GPT_PRICING") {
+            match Pricing::parse_overrides(&value) {
+                Ok(overrides) => pricing.prices.extend(overrides),
+                Err(error) => eprintln!("Ignoring GPT_PRICING: {error}"),
+            }
+        }
+        pricing
+    }
+
+    fn parse_overrides(value: &str) -> Result<HashMap<String, (f64, f64)>, String> {
+        let json = if value.trim_start().starts_with('{') {
+            value.to_string()
+        } else {
+            read_to_string(value).map_err(|e| format!("can't read {value}: {e}"))?
+        };
+        serde_json::from_str(&json).map_err(|e| e.to_string())
+    }
+
+    /// USD per million prompt and completion tokens of `model`.
+    pub fn rates(&self, model: &str) -> Option<(f64, f64)> {
+        self.prices
+            .iter()
+            .filter(|(name, _)| {
+                model
+                    .strip_prefix(name.as_str())
+                    .is_some_and(is_snapshot_suffix)
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, rates)| *rates)
+    }
+
+    pub fn cost(&self, model: &str, prompt_tokens: u64, completion_tokens: u64) -> Option<f64> {
+        let (prompt, completion) = self.rates(model)?;
+        Some((prompt_tokens as f64 * prompt + completion_tokens as f64 * completion) / 1_000_000.0)
+    }
+}
+
+/// Nothing, or a date or version like `-2024-08-06`, `-0613` or `-001`.
+fn is_snapshot_suffix(suffix: &str) -> bool {
+    suffix.is_empty()
+        || suffix.strip_prefix('-').is_some_and(|rest| {
+            !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit() || c == '-')
+        })
+}
+
+impl Default for Pricing {
+    fn default() -> Self {
+        Pricing {
+            prices: DEFAULT_PRICES
+                .iter()
+                .map(|(model, prompt, completion)| (model.to_string(), (*prompt, *completion)))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageEntry {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Estimated in USD, zero for models without a known price.
+    pub cost: f64,
+}
+
+impl UsageEntry {
+    fn add(&mut self, other: &UsageEntry) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost += other.cost;
+    }
+}
+
+/// Tokens used by requests that reached the API, by day, model and profile.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    days: BTreeMap<String, BTreeMap<String, BTreeMap<String, UsageEntry>>>,
+    /// Budget warnings already shown, so each is shown once per period.
+    #[serde(default)]
+    pub(crate) warned: BTreeSet<String>,
+}
+
+impl Ledger {
+    /// `GPT_USAGE_PATH` or `~/.gpt-usage.json`.
+    pub fn default_path() -> String {
+        std::env::var("GPT_USAGE_PATH")
+            .unwrap_or_else(|_| format!("{}/.gpt-usage.json", dirs::home_dir().unwrap().display()))
+    }
+
+    /// Label separating usage of different setups, `GPT_PROFILE` or `default`.
+    pub fn profile() -> String {
+        profile::name()
+    }
+
+    /// Empty when there is no ledger yet. One that can't be read is an error, so the history it
+    /// holds is never replaced with an empty one.
+    pub fn load(path: &str) -> Result<Self, String> {
+        match read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Error: usage ledger {path} is corrupted: {e}")),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Ledger::default()),
+            Err(e) => Err(format!("Error: can't read usage ledger {path}: {e}")),
+        }
+    }
+
+    /// Writes a temporary file renamed over `path`, so the ledger is never left half written.
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let temporary = format!("{path}.{}.tmp", std::process::id());
+        write(&temporary, serde_json::to_string_pretty(self).unwrap())?;
+        rename(&temporary, path)
+    }
+
+    /// Loads the ledger at `path`, applies `change` and saves it, holding a lock on `{path}.lock`
+    /// meanwhile so that concurrent processes don't lose each other's records.
+    pub fn update<T>(path: &str, change: impl FnOnce(&mut Ledger) -> T) -> Result<T, String> {
+        let lock_path = format!("{path}.lock");
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .and_then(|lock| lock.lock().map(|_| lock))
+            .map_err(|e| format!("Error: can't lock usage ledger {lock_path}: {e}"))?;
+
+        let mut ledger = Ledger::load(path)?;
+        let result = change(&mut ledger);
+        ledger
+            .save(path)
+            .map_err(|e| format!("Error: can't save usage to {path}: {e}"))?;
+        drop(lock);
+        Ok(result)
+    }
+
+    pub fn record(&mut self, day: &str, model: &str, profile: &str, entry: &UsageEntry) {
+        self.days
+            .entry(day.to_string())
+            .or_default()
+            .entry(model.to_string())
+            .or_default()
+            .entry(profile.to_string())
+            .or_default()
+            .add(entry);
+    }
+
+    /// Totals by `(profile, model)` of days starting with `prefix`, eg.: `2024-05` for a month.
+    pub fn summary(&self, prefix: &str) -> BTreeMap<(String, String), UsageEntry> {
+        let mut summary: BTreeMap<(String, String), UsageEntry> = BTreeMap::new();
+        for (_, models) in self.days.iter().filter(|(day, _)| day.starts_with(prefix)) {
+            for (model, profiles) in models {
+                for (profile, entry) in profiles {
+                    summary
+                        .entry((profile.clone(), model.clone()))
+                        .or_default()
+                        .add(entry);
+                }
+            }
+        }
+        summary
+    }
+
+    pub fn total(&self, prefix: &str) -> UsageEntry {
+        let mut total = UsageEntry::default();
+        for entry in self.summary(prefix).values() {
+            total.add(entry);
+        }
+        total
+    }
+}
+
+/// Ledger entry of a single request.
+pub fn entry(pricing: &Pricing, model: &str, usage: &Usage) -> UsageEntry {
+    let prompt_tokens = usage.prompt_tokens.max(0) as u64;
+    let completion_tokens = usage.completion_tokens.max(0) as u64;
+    UsageEntry {
+        requests: 1,
+        prompt_tokens,
+        completion_tokens,
+        cost: pricing
+            .cost(model, prompt_tokens, completion_tokens)
+            .unwrap_or(0.0),
+    }
+}
+
+/// Adds a request to the ledger at `path`.
+pub fn record(path: &str, model: &str, usage: &Usage) {
+    let entry = entry(&Pricing::from_env(), model, usage);
+    let recorded = Ledger::update(path, |ledger| {
+        ledger.record(&today(), model, &Ledger::profile(), &entry)
+    });
+    if let Err(error) = recorded {
+        eprintln!("{error}");
+    }
+}
+
+/// Current UTC date as `YYYY-MM-DD`.
+pub fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Gregorian date of a day counted from 1970-01-01, after Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Table of the month containing `day` for `p usage`.
+pub fn month_report(ledger: &Ledger, day: &str) -> Vec<String> {
+    let month = &day[..7];
+    let summary = ledger.summary(month);
+    if summary.is_empty() {
+        return vec![format!("No usage recorded in {month}.")];
+    }
+
+    let mut lines = vec![
+        format!("Usage in {month}"),
+        format!(
+            "{:<12} {:<28} {:>8} {:>12} {:>12} {:>10}",
+            "profile", "model", "requests", "prompt", "completion", "cost"
+        ),
+    ];
+    for ((profile, model), entry) in summary.iter() {
+        lines.push(format!(
+            "{:<12} {:<28} {:>8} {:>12} {:>12} {:>10}",
+            profile,
+            model,
+            entry.requests,
+            entry.prompt_tokens,
+            entry.completion_tokens,
+            format!("${:.4}", entry.cost)
+        ));
+    }
+    let total = ledger.total(month);
+    lines.push(format!(
+        "{:<12} {:<28} {:>8} {:>12} {:>12} {:>10}",
+        "total",
+        "",
+        total.requests,
+        total.prompt_tokens,
+        total.completion_tokens,
+        format!("${:.4}", total.cost)
+    ));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: i32, completion_tokens: i32) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    #[test]
+    fn test_cost_of_snapshots() {
+        let pricing = Pricing::default();
+        assert_eq!(
+            pricing.cost("gpt-4o-2024-08-06", 1_000_000, 1_000_000),
+            Some(12.5)
+        );
+        assert_eq!(pricing.cost("gpt-4-0613", 1_000_000, 0), Some(30.0));
+        assert_eq!(pricing.cost("gpt-4o-mini", 1_000_000, 0), Some(0.15));
+        assert_eq!(pricing.cost("llama3", 1_000, 1_000), None);
+    }
+
+    #[test]
+    fn test_cost_of_other_models_sharing_a_prefix() {
+        let pricing = Pricing::default();
+        assert_eq!(pricing.cost("o1-mini", 1_000, 1_000), None);
+        assert_eq!(pricing.cost("gpt-4.5-preview", 1_000, 1_000), None);
+        assert_eq!(pricing.cost("o3-pro", 1_000, 1_000), None);
+    }
+
+    #[test]
+    fn test_pricing_overrides() {
+        let overrides =
+            Pricing::parse_overrides(r#"{"llama3": [0, 0], "gpt-4o": [1, 2]}"#).unwrap();
+        let mut pricing = Pricing::default();
+        pricing.prices.extend(overrides);
+        assert_eq!(pricing.cost("llama3", 1_000, 1_000), Some(0.0));
+        assert_eq!(pricing.cost("gpt-4o", 1_000_000, 1_000_000), Some(3.0));
+        assert!(Pricing::parse_overrides("/nonexistent/pricing.json").is_err());
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_723), (2024, 1, 1));
+        assert_eq!(today().len(), 10);
+    }
+
+    #[test]
+    fn test_ledger_summary() {
+        let pricing = Pricing::default();
+        let mut ledger = Ledger::default();
+        ledger.record(
+            "2024-05-01",
+            "gpt-4o",
+            "default",
+            &entry(&pricing, "gpt-4o", &usage(100, 10)),
+        );
+        ledger.record(
+            "2024-05-02",
+            "gpt-4o",
+            "default",
+            &entry(&pricing, "gpt-4o", &usage(50, 5)),
+        );
+        ledger.record(
+            "2024-05-02",
+            "llama3",
+            "work",
+            &entry(&pricing, "llama3", &usage(10, 1)),
+        );
+        ledger.record(
+            "2024-04-30",
+            "gpt-4o",
+            "default",
+            &entry(&pricing, "gpt-4o", &usage(1000, 100)),
+        );
+
+        let summary = ledger.summary("2024-05");
+        let gpt = &summary[&("default".to_string(), "gpt-4o".to_string())];
+        assert_eq!(gpt.requests, 2);
+        assert_eq!(gpt.prompt_tokens, 150);
+        assert_eq!(gpt.completion_tokens, 15);
+        assert!((gpt.cost - 0.000525).abs() < 1e-12);
+        assert_eq!(
+            summary[&("work".to_string(), "llama3".to_string())].cost,
+            0.0
+        );
+        assert_eq!(ledger.total("2024-05").requests, 3);
+    }
+
+    #[test]
+    fn test_ledger_save_and_load() {
+        let path = &format!("/tmp/.gpt-usage-{}.json", rand::random::<u64>());
+        let mut ledger = Ledger::load(path).unwrap();
+        ledger.record(
+            "2024-05-01",
+            "gpt-4o",
+            "default",
+            &entry(&Pricing::default(), "gpt-4o", &usage(100, 10)),
+        );
+        ledger.save(path).unwrap();
+
+        assert_eq!(
+            Ledger::load(path).unwrap().total("2024-05").prompt_tokens,
+            100
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_ledger_is_kept() {
+        let path = &format!("/tmp/.gpt-usage-{}.json", rand::random::<u64>());
+        std::fs::write(path, "{\"days\": {").unwrap();
+
+        assert!(Ledger::load(path).unwrap_err().contains("is corrupted"));
+        record(path, "gpt-4o", &usage(100, 10));
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "{\"days\": {");
+        std::fs::remove_file(path).unwrap();
+        let _ = std::fs::remove_file(format!("{path}.lock"));
+    }
+
+    #[test]
+    fn test_concurrent_updates_are_kept() {
+        let path = format!("/tmp/.gpt-usage-{}.json", rand::random::<u64>());
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        Ledger::update(&path, |ledger| {
+                            ledger.record(
+                                "2024-05-01",
+                                "gpt-4o",
+                                "default",
+                                &entry(&Pricing::default(), "gpt-4o", &usage(1, 1)),
+                            )
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(Ledger::load(&path).unwrap().total("2024-05").requests, 40);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(format!("{path}.lock")).unwrap();
+    }
+
+    #[test]
+    fn test_month_report() {
+        let mut ledger = Ledger::default();
+        assert_eq!(
+            month_report(&ledger, "2024-05-10"),
+            vec!["No usage recorded in 2024-05."]
+        );
+
+        ledger.record(
+            "2024-05-01",
+            "gpt-4o",
+            "default",
+            &entry(&Pricing::default(), "gpt-4o", &usage(100, 10)),
+        );
+        let report = month_report(&ledger, "2024-05-10");
+        assert_eq!(report.len(), 4);
+        assert!(report[2].starts_with("default      gpt-4o"));
+        assert!(report[3].starts_with("total"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Runs the binary against recorded cassettes, without touching the network.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn cassette(name: &str) -> String {
+    format!("{}/tests/cassettes/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Empty directory used as `HOME`, so no cache, ledger or key of the user is seen.
+fn home() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("gpt-cli-test-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+fn gpt(home: &Path, args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_gpt-cli"))
+        .args(args)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("HOME", home)
+        .env("NO_COLOR", "1")
+        .env("GPT_POST", "out")
+        .env("GPT_CACHE_PATH", home.join("cache.json"))
+        .env("GPT_USAGE_PATH", home.join("usage.json"))
+        .envs(env.iter().copied())
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn test_replays_answer() {
+    let output = gpt(
+        &home(),
+        &["show", "calendar"],
+        &[("GPT_CASSETTE", &cassette("calendar.json"))],
+    );
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "cal\n");
+}
+
+#[test]
+fn test_replays_retries() {
+    let output = gpt(
+        &home(),
+        &["show", "disk", "usage"],
+        &[("GPT_CASSETTE", &cassette("rate_limited.json"))],
+    );
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "df -h\n");
+    assert!(stderr(&output).contains("Request failed (429 Too Many Requests), retrying"));
+}
+
+#[test]
+fn test_replays_error_status() {
+    let output = gpt(
+        &home(),
+        &["show", "calendar"],
+        &[("GPT_CASSETTE", &cassette("invalid_key.json"))],
+    );
+    assert_eq!(output.status.code(), Some(11));
+    assert!(stderr(&output).contains("Error: authentication rejected: Incorrect API key"));
+}
+
+#[test]
+fn test_traces_without_touching_stdout() {
+    let output = gpt(
+        &home(),
+        &["-vv", "show", "calendar"],
+        &[
+            ("GPT_CASSETTE", &cassette("calendar.json")),
+            ("OPENAI_API_KEY", "sk-traced-secret"),
+        ],
+    );
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "cal\n");
+    let trace = stderr(&output);
+    assert!(trace.contains("> POST https://api.openai.com/v1/chat/completions\n"));
+    assert!(trace.contains("> authorization: <redacted>\n"));
+    assert!(trace.contains("< 200 OK in "));
+    assert!(trace.contains(r#""content": "cal""#));
+    assert!(!trace.contains("sk-traced-secret"));
+}
+
+#[test]
+fn test_lists_models() {
+    let output = gpt(
+        &home(),
+        &["models", "4O"],
+        &[("GPT_CASSETTE", &cassette("models.json"))],
+    );
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "Model        Context  Prompt/1M  Completion/1M\n\
+         gpt-4o       -        $2.50      $10.00\n\
+         gpt-4o-mini  -        $0.15      $0.60\n"
+    );
+}
+
+#[test]
+fn test_suggests_similar_model() {
+    let output = gpt(
+        &home(),
+        &["show", "calendar"],
+        &[
+            ("GPT_CASSETTE", &cassette("models.json")),
+            ("GPT_MODEL", "gpt4o"),
+        ],
+    );
+    assert_eq!(output.status.code(), Some(14));
+    assert!(stderr(&output).contains("Did you mean gpt-4o? Set GPT_MODEL=gpt-4o."));
+}
+
+#[test]
+fn test_unrecorded_request() {
+    let output = gpt(
+        &home(),
+        &["show", "time"],
+        &[("GPT_CASSETTE", &cassette("calendar.json"))],
+    );
+    assert_eq!(output.status.code(), Some(19));
+    assert!(stderr(&output).contains("recorded in"));
+}
+
+#[test]
+fn test_missing_cassette() {
+    let home = home();
+    let missing = home.join("missing.json");
+    let output = gpt(
+        &home,
+        &["show", "calendar"],
+        &[("GPT_CASSETTE", missing.to_str().unwrap())],
+    );
+    assert_eq!(output.status.code(), Some(22));
+    assert!(stderr(&output).contains("can't read GPT_CASSETTE"));
+}
+
+#[test]
+fn test_records_then_replays() {
+    let server = httpmock::MockServer::start();
+    server.mock(|when, then| {
+        when.method(httpmock::Method::POST)
+            .path("/v1/chat/completions")
+            .header("Authorization", "Bearer sk-recorded-secret");
+        then.status(200)
+            .header("content-type", "application/json")
+            .body(
+                r#"{"id":"chatcmpl-3","object":"chat.completion","created":1700000000,"model":"gpt-4o","usage":{"prompt_tokens":52,"completion_tokens":2,"total_tokens":54},"choices":[{"index":0,"message":{"role":"assistant","content":"uptime"},"finish_reason":"stop"}]}"#,
+            );
+    });
+    let recorded = home().join("uptime.json");
+    let path = recorded.to_str().unwrap();
+
+    let output = gpt(
+        &home(),
+        &["show", "uptime"],
+        &[
+            ("GPT_CASSETTE", path),
+            ("GPT_CASSETTE_MODE", "record"),
+            ("OPENAI_BASE_URL", &server.base_url()),
+            ("OPENAI_API_KEY", "sk-recorded-secret"),
+        ],
+    );
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "uptime\n");
+    assert!(
+        !std::fs::read_to_string(&recorded)
+            .unwrap()
+            .contains("sk-recorded-secret")
+    );
+
+    // Replayed without a key, and with the server gone.
+    drop(server);
+    let output = gpt(&home(), &["show", "uptime"], &[("GPT_CASSETTE", path)]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "uptime\n");
+}
@@ -0,0 +1,46 @@
+//! Interrupts the binary with Ctrl-C while it waits for a slow server.
+
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_ctrl_c_cancels_slow_request() {
+    let server = httpmock::MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(httpmock::Method::POST)
+            .path("/v1/chat/completions");
+        then.status(200).delay(Duration::from_secs(30));
+    });
+    let home = std::env::temp_dir().join(format!("gpt-cli-test-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&home).unwrap();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_gpt-cli"))
+        .args(["show", "calendar"])
+        .env_clear()
+        .env("HOME", &home)
+        .env("NO_COLOR", "1")
+        .env("OPENAI_API_KEY", "sk-test")
+        .env("OPENAI_BASE_URL", server.base_url())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let started = Instant::now();
+    while mock.hits() == 0 {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "no request sent"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    // SAFETY: sends a signal to the child spawned above.
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+    }
+    let output = child.wait_with_output().unwrap();
+
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(output.status.code(), Some(130));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Request cancelled."));
+}
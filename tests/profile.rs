@@ -0,0 +1,54 @@
+//! Runs the binary with a profile overriding the key and sampling of the environment.
+
+use std::process::Command;
+
+#[test]
+fn test_profile_overrides_key_and_sampling() {
+    let server = httpmock::MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(httpmock::Method::POST)
+            .path("/v1/chat/completions")
+            .header("Authorization", "Bearer sk-work")
+            .json_body_partial(r#"{"temperature": 0.3}"#);
+        then.status(200).json_body(serde_json::json!({
+            "id": "testid",
+            "object": "chat.completion",
+            "created": 1678705627,
+            "model": "gpt-4o",
+            "usage": { "prompt_tokens": 45, "completion_tokens": 1, "total_tokens": 46 },
+            "choices": [
+                {
+                    "message": { "role": "assistant", "content": "cal" },
+                    "finish_reason": "stop",
+                    "index": 0
+                }
+            ]
+        }));
+    });
+    let home = std::env::temp_dir().join(format!("gpt-cli-test-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&home).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_gpt-cli"))
+        .args(["show", "calendar"])
+        .env_clear()
+        .env("HOME", &home)
+        .env("NO_COLOR", "1")
+        .env("GPT_POST", "out")
+        .env("GPT_PROFILE", "work")
+        .env("OPENAI_API_KEY", "sk-default")
+        .env("OPENAI_API_KEY_WORK", "sk-work")
+        .env("GPT_TEMPERATURE", "0.9")
+        .env("GPT_TEMPERATURE_WORK", "0.3")
+        .env("OPENAI_BASE_URL", server.base_url())
+        .output()
+        .unwrap();
+
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "cal\n");
+    mock.assert();
+}